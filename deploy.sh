@@ -13,6 +13,7 @@ scp  ./Cargo.toml pi@192.168.0.100:/home/pi/jane/sage_controller/
 
 ssh pi@192.168.0.100 <<'ENDSSH'
   cd /home/pi/jane/sage_controller
+  rustup update stable
   cargo build --release
   sudo systemctl stop home_controller
   rm /home/pi/jane/home_controller
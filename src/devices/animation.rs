@@ -0,0 +1,443 @@
+use crate::devices::web::Color;
+use crate::devices::{Capability, Control, DeviceType};
+use crate::io::{transient, IO};
+use crate::log_error;
+use crate::runtime::Background;
+use anyhow::{Error, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+/// Web beams get a frame at most this often, every send is an http request.
+const BEAM_FRAME_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_FRAME_MS: u64 = 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    #[default]
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+    /// Jumps to the frame at the end of the transition.
+    Step,
+}
+
+impl Easing {
+    fn apply(&self, progress: f64) -> f64 {
+        let p = progress.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => p,
+            Easing::EaseIn => p * p,
+            Easing::EaseOut => 1.0 - (1.0 - p) * (1.0 - p),
+            Easing::EaseInOut => {
+                if p < 0.5 {
+                    2.0 * p * p
+                } else {
+                    1.0 - (-2.0 * p + 2.0).powi(2) / 2.0
+                }
+            }
+            Easing::Step => {
+                if p < 1.0 {
+                    0.0
+                } else {
+                    1.0
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Keyframe {
+    /// Transition time from the previous frame. The first frame uses it only to loop back from the last one.
+    pub duration_ms: u64,
+    pub color: Color,
+    /// 0-100.
+    pub brightness: u8,
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+///
+/// Keyframe sequence played on several devices in sync.
+/// {"devices": ["lounge_beam", "kitchen_lamp"], "looped": true,
+///  "keyframes": [{"duration_ms": 2000, "color": [255, 0, 0], "brightness": 100, "easing": "ease_in_out"}, ..]}
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Animation {
+    /// Ids of web beams, color lights and dimmers.
+    pub devices: Vec<String>,
+    pub keyframes: Vec<Keyframe>,
+    #[serde(default)]
+    pub looped: bool,
+}
+
+impl Animation {
+    fn validate(&self) -> Result<()> {
+        if self.keyframes.is_empty() {
+            return Err(Error::msg("Animation has no keyframes"));
+        }
+        if let Some(frame) = self.keyframes.iter().find(|frame| frame.brightness > 100) {
+            return Err(Error::msg(format!(
+                "Invalid brightness: {}",
+                frame.brightness
+            )));
+        }
+        if let Some(frame) = self
+            .keyframes
+            .iter()
+            .find(|frame| frame.duration_ms > MAX_FRAME_MS)
+        {
+            return Err(Error::msg(format!(
+                "Invalid duration: {} ms, max {} ms",
+                frame.duration_ms, MAX_FRAME_MS
+            )));
+        }
+        if self.looped && self.cycle_ms() == 0 {
+            return Err(Error::msg("Looped animation must take some time"));
+        }
+        Ok(())
+    }
+
+    fn cycle_ms(&self) -> u64 {
+        let frames = self.keyframes.iter().skip(1).map(|frame| frame.duration_ms);
+        let back = if self.looped {
+            self.keyframes[0].duration_ms
+        } else {
+            0
+        };
+        frames.sum::<u64>() + back
+    }
+
+    /// Color and brightness at the time, None after the end of the animation.
+    fn frame_at(&self, elapsed_ms: u64) -> Option<(Color, f64)> {
+        let cycle = self.cycle_ms();
+        let mut time = if self.looped {
+            elapsed_ms % cycle
+        } else if elapsed_ms > cycle {
+            return None;
+        } else {
+            elapsed_ms
+        };
+
+        let count = self.keyframes.len();
+        let mut from = &self.keyframes[0];
+        let steps = if self.looped { count } else { count - 1 };
+        for i in 1..=steps {
+            let to = &self.keyframes[i % count];
+            if time < to.duration_ms {
+                let progress = to.easing.apply(time as f64 / to.duration_ms as f64);
+                return Some(blend(from, to, progress));
+            }
+            time -= to.duration_ms;
+            from = to;
+        }
+        Some((from.color, from.brightness as f64))
+    }
+}
+
+fn blend(from: &Keyframe, to: &Keyframe, progress: f64) -> (Color, f64) {
+    let lerp = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * progress).round() as u8;
+    let (r1, g1, b1) = from.color;
+    let (r2, g2, b2) = to.color;
+    let brightness =
+        from.brightness as f64 + (to.brightness as f64 - from.brightness as f64) * progress;
+    ((lerp(r1, r2), lerp(g1, g2), lerp(b1, b2)), brightness)
+}
+
+/// Update of the device showing the frame, None if the device can't be animated.
+fn device_state(device: &dyn Control, color: Color, brightness: f64) -> Option<Value> {
+    let level = brightness.round() as u8;
+    match device.dev_type() {
+        DeviceType::WebBeam => {
+            let scale = |c: u8| (c as f64 * brightness / 100.0).round() as u8;
+            let channel = json!({
+                "is_on": level > 0,
+                "is_spot_on": false,
+                "led_state": {
+                    "is_on": true,
+                    "mode": {"Color": [scale(color.0), scale(color.1), scale(color.2)]},
+                },
+            });
+            Some(json!({"channel_1": channel, "channel_2": channel}))
+        }
+        DeviceType::ColorLight => {
+            let mut state = json!({"is_on": level > 0, "brightness": level});
+            if device.capabilities().contains(&Capability::Color) {
+                state["color"] = json!({"rgb": [color.0, color.1, color.2]});
+            }
+            Some(state)
+        }
+        DeviceType::SerialDimmer => Some(json!({"is_on": level > 0, "brightness": level})),
+        _ => None,
+    }
+}
+
+/// Last frame sent to a device.
+struct Sent {
+    state: Value,
+    at: Instant,
+}
+
+#[derive(Debug)]
+struct Playing {
+    generation: u64,
+    devices: Vec<String>,
+    bg: Background,
+}
+
+#[derive(Debug, Default)]
+struct Animations {
+    generation: u64,
+    playing: HashMap<String, Playing>,
+}
+
+///
+/// Plays animations on the runtime. A device is driven by one animation at a time.
+///
+#[derive(Debug, Clone)]
+pub struct Animator {
+    io: IO,
+    animations: Arc<Mutex<Animations>>,
+}
+
+impl Animator {
+    pub fn new(io: &IO) -> Animator {
+        Animator {
+            io: io.clone(),
+            animations: Default::default(),
+        }
+    }
+
+    pub fn start(&self, name: &str, animation: Animation) -> Result<()> {
+        animation.validate()?;
+        for id in &animation.devices {
+            let device = self
+                .io
+                .device_holder()
+                .devices()
+                .get(id)
+                .ok_or_else(|| Error::msg(format!("device {} not found", id)))?;
+            if device_state(device.as_ref(), (0, 0, 0), 0.0).is_none() {
+                return Err(Error::msg(format!("Device {} can't be animated", id)));
+            }
+        }
+
+        let mut animations = self.animations.lock().unwrap();
+        let busy = animations
+            .playing
+            .iter()
+            .filter(|(other, playing)| {
+                *other == name
+                    || playing
+                        .devices
+                        .iter()
+                        .any(|id| animation.devices.contains(id))
+            })
+            .map(|(other, _)| other.to_owned())
+            .collect::<Vec<_>>();
+        for other in busy {
+            if let Some(playing) = animations.playing.remove(&other) {
+                playing.bg.stop();
+            }
+        }
+
+        animations.generation += 1;
+        let generation = animations.generation;
+        let started = Instant::now();
+        let last_sent = Mutex::new(HashMap::new());
+        let animator = self.clone();
+        let key = name.to_owned();
+        let devices = animation.devices.clone();
+        let bg = Background::every(self.io.runtime(), FRAME_INTERVAL, true, move || {
+            // Slow devices take longer than a frame, the next frame catches up.
+            let mut last_sent = match last_sent.try_lock() {
+                Ok(last_sent) => last_sent,
+                Err(_) => return,
+            };
+            let elapsed = started.elapsed().as_millis() as u64;
+            let frame = animation.frame_at(elapsed);
+            animator.show(&animation, frame, &mut last_sent);
+            if frame.is_none() {
+                animator.finish(&key, generation);
+            }
+        });
+        info!("Start animation {} on {:?}", name, devices);
+        animations.playing.insert(
+            name.to_owned(),
+            Playing {
+                generation,
+                devices,
+                bg,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn stop(&self, name: &str) -> Result<()> {
+        let playing = self
+            .animations
+            .lock()
+            .unwrap()
+            .playing
+            .remove(name)
+            .ok_or_else(|| Error::msg(format!("Animation {} is not playing", name)))?;
+        playing.bg.stop();
+        Ok(())
+    }
+
+    /// {name: [device ids]}
+    pub fn playing(&self) -> HashMap<String, Vec<String>> {
+        self.animations
+            .lock()
+            .unwrap()
+            .playing
+            .iter()
+            .map(|(name, playing)| (name.to_owned(), playing.devices.clone()))
+            .collect()
+    }
+
+    /// Sends the frame, the last one after the end, to the devices whose state changed.
+    /// Frames are never buffered for offline devices, only the last one is.
+    fn show(
+        &self,
+        animation: &Animation,
+        frame: Option<(Color, f64)>,
+        last_sent: &mut HashMap<String, Sent>,
+    ) {
+        let (color, brightness) = frame.unwrap_or_else(|| {
+            let last = animation.keyframes[animation.keyframes.len() - 1];
+            (last.color, last.brightness as f64)
+        });
+        let devices = self.io.device_holder().devices();
+        for id in &animation.devices {
+            if let Some(device) = devices.get(id) {
+                if let Some(state) = device_state(device.as_ref(), color, brightness) {
+                    let skip = last_sent.get(id).is_some_and(|sent| {
+                        sent.state == state
+                            || (frame.is_some()
+                                && device.dev_type() == DeviceType::WebBeam
+                                && sent.at.elapsed() < BEAM_FRAME_INTERVAL)
+                    });
+                    if skip {
+                        continue;
+                    }
+                    if frame.is_some() {
+                        log_error!(transient(|| device.update(state.clone())));
+                    } else {
+                        log_error!(device.update(state.clone()));
+                    }
+                    last_sent.insert(
+                        id.to_owned(),
+                        Sent {
+                            state,
+                            at: Instant::now(),
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    fn finish(&self, name: &str, generation: u64) {
+        let mut animations = self.animations.lock().unwrap();
+        if animations
+            .playing
+            .get(name)
+            .map(|playing| playing.generation)
+            == Some(generation)
+        {
+            if let Some(playing) = animations.playing.remove(name) {
+                info!("Animation {} finished", name);
+                playing.bg.stop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::animation::{Animation, Animator, Easing, Keyframe};
+    use crate::devices::{Control, SerialDimmer, SerialSwitch, WebBeam};
+    use crate::io::{Recorder, IO};
+    use crate::runtime::Runtime;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn frame(duration_ms: u64, color: (u8, u8, u8), brightness: u8) -> Keyframe {
+        Keyframe {
+            duration_ms,
+            color,
+            brightness,
+            easing: Easing::Linear,
+        }
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut animation = Animation {
+            devices: vec![],
+            keyframes: vec![
+                frame(1000, (0, 0, 0), 0),
+                frame(1000, (200, 100, 0), 100),
+                frame(2000, (0, 100, 200), 50),
+            ],
+            looped: false,
+        };
+        assert_eq!(animation.frame_at(0), Some(((0, 0, 0), 0.0)));
+        assert_eq!(animation.frame_at(500), Some(((100, 50, 0), 50.0)));
+        assert_eq!(animation.frame_at(2000), Some(((100, 100, 100), 75.0)));
+        assert_eq!(animation.frame_at(3000), Some(((0, 100, 200), 50.0)));
+        assert_eq!(animation.frame_at(3001), None);
+
+        animation.looped = true;
+        assert_eq!(animation.frame_at(3500), Some(((0, 50, 100), 25.0)));
+        assert_eq!(animation.frame_at(4500), Some(((100, 50, 0), 50.0)));
+
+        animation.keyframes[1].easing = Easing::Step;
+        assert_eq!(animation.frame_at(4500), Some(((0, 0, 0), 0.0)));
+        assert_eq!(Easing::EaseInOut.apply(0.25), 0.125);
+
+        assert!(animation.validate().is_ok());
+        animation.keyframes[0].duration_ms = u64::MAX;
+        assert!(animation.validate().is_err());
+    }
+
+    #[test]
+    fn test_animator() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(2), recorder.clone());
+        WebBeam::new(&mut io, "beam");
+        let lamp = SerialDimmer::new(&mut io, "lamp", 0x01, 0, 100);
+        SerialSwitch::new(&mut io, "fun", 0x02);
+        let io = io.freeze();
+        let animator = Animator::new(&io);
+
+        let animation = Animation {
+            devices: vec!["beam".to_owned(), "lamp".to_owned()],
+            keyframes: vec![frame(0, (0, 0, 255), 20), frame(300, (255, 0, 0), 100)],
+            looped: false,
+        };
+        let mut with_switch = animation.clone();
+        with_switch.devices.push("fun".to_owned());
+        assert!(animator.start("cycle", with_switch).is_err());
+
+        animator.start("cycle", animation).unwrap();
+        assert!(animator.playing().contains_key("cycle"));
+        let start = Instant::now();
+        while !animator.playing().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(animator.playing().is_empty());
+        assert_eq!(
+            recorder.last_sent("beam").unwrap().args,
+            vec!["OFF:ON:color:255:0:0".to_owned(); 2]
+        );
+        assert_eq!(lamp.load(), json!({"is_on": true, "brightness": 100}));
+        assert!(animator.stop("cycle").is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use crate::devices::{
+    Calibration, ColorLight, Cover, CoverTransport, DeviceMeta, LightKind, LightTransport,
+    SerialSwitch, Thermostat, ThermostatSettings, Valve, ValveSettings, WebSwitch,
+};
+use crate::io::IOMut;
+use crate::log_error;
+use crate::sensors::AnalogSensor;
+use crate::storage::Storage;
+
+const DEVICES: &str = "devices";
+
+///
+/// Device declared in `devices.json` in addition to the devices of the rooms.
+/// [{"type": "ColorLight", "id": "desk_light", "kind": "Rgbw", "transport": {"Serial": {"p_id": 8}}},
+///  {"type": "Cover", "id": "blind", "transport": {"Serial": {"up": 16, "down": 17, "reversal_ms": 500}}},
+///  {"type": "Thermostat", "id": "heating", "sensor": "temperature",
+///   "actuator": {"WebSwitch": {"id": "heating_valve"}}, "settings": {"mode": "manual", "setpoint": 21.0}},
+///  {"type": "Valve", "id": "garden_water", "leak_sensor": "garden_leak", "settings": {"max_open_secs": 900}}]
+/// Every device may have the optional "room" and "name".
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DeviceConfig {
+    ColorLight {
+        id: String,
+        kind: LightKind,
+        transport: LightTransport,
+    },
+    Cover {
+        id: String,
+        transport: CoverTransport,
+        #[serde(default)]
+        calibration: Calibration,
+    },
+    Thermostat {
+        id: String,
+        /// Id of the analog temperature sensor.
+        sensor: String,
+        actuator: ActuatorConfig,
+        #[serde(default)]
+        settings: ThermostatSettings,
+    },
+    Valve {
+        id: String,
+        /// Id of the analog leak sensor.
+        #[serde(default)]
+        leak_sensor: Option<String>,
+        #[serde(default)]
+        settings: ValveSettings,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DeclaredDevice {
+    #[serde(flatten)]
+    device: DeviceConfig,
+    #[serde(flatten)]
+    meta: DeviceMeta,
+}
+
+/// On/off actuator, registered as a device of its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ActuatorConfig {
+    SerialSwitch { id: String, p_id: u8 },
+    WebSwitch { id: String },
+}
+
+impl DeviceConfig {
+    fn id(&self) -> &str {
+        match self {
+            DeviceConfig::ColorLight { id, .. }
+            | DeviceConfig::Cover { id, .. }
+            | DeviceConfig::Thermostat { id, .. }
+            | DeviceConfig::Valve { id, .. } => id,
+        }
+    }
+
+    fn build(&self, io: &mut IOMut) {
+        match self {
+            DeviceConfig::ColorLight {
+                id,
+                kind,
+                transport,
+            } => {
+                if let Err(err) = transport.validate(*kind) {
+                    error!("Invalid transport of light {}: {}", id, err);
+                    return;
+                }
+                ColorLight::new(io, id, *kind, *transport);
+            }
+            DeviceConfig::Cover {
+                id,
+                transport,
+                calibration,
+            } => {
+                Cover::new(io, id, *transport, *calibration);
+            }
+            DeviceConfig::Thermostat {
+                id,
+                sensor,
+                actuator,
+                settings,
+            } => {
+                if let Err(err) = settings.validate() {
+                    error!("Invalid settings of thermostat {}: {}", id, err);
+                    return;
+                }
+                let sensor = AnalogSensor::new(io, sensor);
+                let settings = settings.clone();
+                match actuator {
+                    ActuatorConfig::SerialSwitch { id: switch, p_id } => {
+                        let switch = SerialSwitch::new(io, switch, *p_id);
+                        Thermostat::new(io, id, sensor, switch, settings);
+                    }
+                    ActuatorConfig::WebSwitch { id: switch } => {
+                        let switch = WebSwitch::new(io, switch);
+                        Thermostat::new(io, id, sensor, switch, settings);
+                    }
+                }
+            }
+            DeviceConfig::Valve {
+                id,
+                leak_sensor,
+                settings,
+            } => {
+                let leak_sensor = leak_sensor
+                    .as_ref()
+                    .map(|sensor| AnalogSensor::new(io, sensor));
+                Valve::new(io, id, leak_sensor, settings.clone());
+            }
+        }
+    }
+}
+
+/// Registers the declared devices.
+pub fn load_devices(io: &mut IOMut, storage: &Storage) {
+    match storage.load::<Vec<DeclaredDevice>>(DEVICES) {
+        Ok(Some(devices)) => {
+            info!("Loaded {} declared devices", devices.len());
+            for DeclaredDevice { device, meta } in devices {
+                device.build(io);
+                log_error!(io.describe(device.id(), meta));
+            }
+        }
+        Ok(None) => {}
+        Err(err) => error!("Failed to load declared devices: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::load_devices;
+    use crate::devices::{Capability, DeviceFilter, DeviceMeta, DeviceType};
+    use crate::io::{Input, Recorder, IO};
+    use crate::runtime::Runtime;
+    use crate::storage::Storage;
+
+    #[test]
+    fn test_declared_meta() {
+        let storage = Storage::temp();
+        storage
+            .save(
+                "devices",
+                &json!([
+                    {"type": "ColorLight", "id": "desk_light", "kind": "TunableWhite",
+                     "transport": "Web", "room": "office", "name": "Desk light"},
+                    {"type": "Cover", "id": "blind", "transport": "Web"},
+                    {"type": "ColorLight", "id": "strip", "kind": "Rgbw",
+                     "transport": {"Serial": {"p_id": 253}}},
+                ]),
+            )
+            .unwrap();
+        let mut io = IO::with_output(&Runtime::new(1), Recorder::new());
+        load_devices(&mut io, &storage);
+        assert!(io
+            .describe("sofa", DeviceMeta::new(Some("office"), "Sofa"))
+            .is_err());
+        let io = io.freeze();
+
+        let all = io.devices_list(&DeviceFilter::default());
+        assert_eq!(
+            all.iter().map(|info| info.id.as_str()).collect::<Vec<_>>(),
+            vec!["blind", "desk_light"]
+        );
+        assert_eq!(all[0].name, "blind");
+        assert_eq!(all[0].room, None);
+
+        let office = io.devices_list(&DeviceFilter {
+            room: Some("office".to_owned()),
+            dev_type: Some(DeviceType::ColorLight),
+        });
+        assert_eq!(office.len(), 1);
+        assert_eq!(office[0].name, "Desk light");
+        assert_eq!(
+            office[0].capabilities,
+            vec![
+                Capability::OnOff,
+                Capability::Brightness,
+                Capability::ColorTemperature
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(&office[0]).unwrap()["type"],
+            json!("ColorLight")
+        );
+    }
+}
@@ -0,0 +1,590 @@
+use crate::devices::web::Reconciler;
+use crate::devices::{Control, DeviceType, Flush, Schema};
+use crate::io::{Cmd, IOMut, Output, WebCmd, IO};
+use crate::log_error;
+use crate::runtime::RtTimer;
+use anyhow::{Error, Result};
+use serde_json::Value;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Serial relay command: args 0x01 - on, 0x02 - off.
+const SWITCH: u8 = 0x02;
+const MAX_TRAVEL_TIME_MS: i64 = 10 * 60 * 1000;
+const REVERSAL_MS: u64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CoverTransport {
+    /// Motor driven by two interlocked relays.
+    /// The motor stands still for `reversal_ms` before it runs the other way.
+    Serial {
+        up: u8,
+        down: u8,
+        #[serde(default = "CoverTransport::default_reversal_ms")]
+        reversal_ms: u64,
+    },
+    Web,
+}
+
+impl CoverTransport {
+    fn default_reversal_ms() -> u64 {
+        REVERSAL_MS
+    }
+}
+
+///
+/// Travel times of the motor. The motor gives no feedback, so the position is estimated by them.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Calibration {
+    /// Full travel time from closed to open.
+    pub open_time_ms: u64,
+    /// Full travel time from open to closed.
+    pub close_time_ms: u64,
+    /// Extra run time when moving to an end position to resync the estimation.
+    pub overrun_ms: u64,
+}
+
+impl Calibration {
+    fn validate(&self) -> Result<()> {
+        if self.open_time_ms == 0 || self.close_time_ms == 0 {
+            Err(Error::msg(format!("Invalid calibration: {:?}", self)))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            open_time_ms: 30_000,
+            close_time_ms: 30_000,
+            overrun_ms: 2_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Motion {
+    Stopped,
+    Opening,
+    Closing,
+}
+
+#[derive(Debug)]
+struct CoverState {
+    /// Position at the start of the motion, 0 - closed, 100 - open.
+    position: f64,
+    motion: Motion,
+    started: Instant,
+    target: u8,
+    calibration: Calibration,
+    /// Last direction the motor ran and when it stopped.
+    direction: Motion,
+    stopped: Instant,
+}
+
+impl CoverState {
+    fn set_motion(&mut self, motion: Motion) {
+        if motion != Motion::Stopped {
+            self.direction = motion;
+        } else if self.motion != Motion::Stopped {
+            self.stopped = Instant::now();
+        }
+        self.motion = motion;
+    }
+
+    fn position(&self) -> f64 {
+        estimate(
+            self.position,
+            self.motion,
+            self.started.elapsed(),
+            &self.calibration,
+        )
+    }
+}
+
+///
+/// Curtain or blind motor with position control.
+///
+#[derive(Debug, Clone)]
+pub struct Cover {
+    id: Arc<String>,
+    io: IO,
+    transport: CoverTransport,
+    state: Arc<RwLock<CoverState>>,
+    timer: Arc<Mutex<RtTimer>>,
+    reconciler: Reconciler,
+}
+
+impl Cover {
+    pub fn new(
+        io: &mut IOMut,
+        id: &str,
+        transport: CoverTransport,
+        calibration: Calibration,
+    ) -> Cover {
+        let dev = Cover {
+            id: Arc::new(id.to_owned()),
+            io: io.shared(),
+            transport,
+            state: Arc::new(RwLock::new(CoverState {
+                position: 0.0,
+                motion: Motion::Stopped,
+                started: Instant::now(),
+                target: 0,
+                calibration,
+                direction: Motion::Stopped,
+                stopped: Instant::now(),
+            })),
+            timer: Arc::new(Mutex::new(RtTimer::new(io.rt(), true))),
+            reconciler: Default::default(),
+        };
+        io.reg_device(Box::new(dev.clone()));
+        dev
+    }
+
+    pub(super) fn schema() -> Schema {
+        Schema::object(vec![
+            ("action", Schema::Enum(vec!["open", "close", "stop"])),
+            ("target", Schema::percent()),
+            ("position", Schema::percent()),
+            (
+                "calibration",
+                Schema::record(vec![
+                    ("open_time_ms", Schema::integer(1, MAX_TRAVEL_TIME_MS)),
+                    ("close_time_ms", Schema::integer(1, MAX_TRAVEL_TIME_MS)),
+                    ("overrun_ms", Schema::integer(0, MAX_TRAVEL_TIME_MS)),
+                ]),
+            ),
+        ])
+    }
+
+    pub fn open(&self) -> Result<()> {
+        self.move_to(100)
+    }
+
+    pub fn close(&self) -> Result<()> {
+        self.move_to(0)
+    }
+
+    pub fn move_to(&self, target: u8) -> Result<()> {
+        self.io.locks().check(&self.id)?;
+        if target > 100 {
+            return Err(Error::msg(format!("Invalid cover position: {}", target)));
+        }
+        self.run_to(target)
+    }
+
+    /// Runs the motor to the target. A reversal first stops the motor for the dead time.
+    fn run_to(&self, target: u8) -> Result<()> {
+        let mut timer = self.timer.lock().unwrap();
+        timer.stop();
+
+        let (travel, dead_time) = {
+            let mut state = self.state.write().unwrap();
+            let position = state.position();
+            let motion = if target as f64 > position {
+                Motion::Opening
+            } else if (target as f64) < position {
+                Motion::Closing
+            } else {
+                Motion::Stopped
+            };
+            let dead_time = self.dead_time(&state, motion);
+            state.position = position;
+            state.started = Instant::now();
+            state.set_motion(if dead_time.is_some() {
+                Motion::Stopped
+            } else {
+                motion
+            });
+            state.target = target;
+            (travel_time(position, target, &state.calibration), dead_time)
+        };
+
+        if let Err(err) = self.flush() {
+            // The motor may have started anyway, stop it and the position estimation.
+            log_error!(&self.halt());
+            return Err(err);
+        }
+        let cover = self.clone();
+        match (dead_time, travel) {
+            (Some(dead_time), _) => {
+                timer.after(dead_time, move || log_error!(&cover.run_to(target)));
+            }
+            (None, Some(travel)) => timer.after(travel, move || log_error!(&cover.halt())),
+            (None, None) => {}
+        }
+        Ok(())
+    }
+
+    /// Time the motor still has to stand still before it runs in the given direction.
+    fn dead_time(&self, state: &CoverState, motion: Motion) -> Option<Duration> {
+        let reversal = match self.transport {
+            CoverTransport::Serial { reversal_ms, .. } => Duration::from_millis(reversal_ms),
+            CoverTransport::Web => return None,
+        };
+        let reverses = matches!(
+            (state.direction, motion),
+            (Motion::Opening, Motion::Closing) | (Motion::Closing, Motion::Opening)
+        );
+        if !reverses {
+            return None;
+        }
+        let still = if state.motion == Motion::Stopped {
+            state.stopped.elapsed()
+        } else {
+            Duration::from_millis(0)
+        };
+        reversal
+            .checked_sub(still)
+            .filter(|dead_time| *dead_time > Duration::from_millis(0))
+    }
+
+    pub fn stop(&self) -> Result<()> {
+        self.io.locks().check(&self.id)?;
+        self.timer.lock().unwrap().stop();
+        self.halt()
+    }
+
+    fn halt(&self) -> Result<()> {
+        {
+            let mut state = self.state.write().unwrap();
+            let position = state.position();
+            state.position = position;
+            state.set_motion(Motion::Stopped);
+            state.target = position.round() as u8;
+        }
+        self.flush()
+    }
+
+    ///
+    /// v1 args ["OPEN"|"CLOSE"|"STOP"], v2 state {motion, target}
+    /// Motions are transient: a late start would run the motor with a wrong estimation.
+    ///
+    fn cmd(&self) -> WebCmd {
+        let state = self.state.read().unwrap();
+        let arg = match state.motion {
+            Motion::Opening => "OPEN",
+            Motion::Closing => "CLOSE",
+            Motion::Stopped => "STOP",
+        };
+        let cmd = WebCmd::new(
+            vec![arg.to_owned()],
+            json!({
+                "motion": state.motion,
+                "target": state.target,
+            }),
+        );
+        if state.motion == Motion::Stopped {
+            cmd
+        } else {
+            cmd.transient()
+        }
+    }
+}
+
+///
+/// State {position, target, motion, calibration: {open_time_ms, close_time_ms, overrun_ms}}
+/// Update {action: "open"|"close"|"stop"} | {target} | {position} | {calibration}
+///
+impl Control for Cover {
+    fn id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    fn dev_type(&self) -> DeviceType {
+        DeviceType::Cover
+    }
+
+    fn load(&self) -> Value {
+        let state = self.state.read().unwrap();
+        let mut value = json!({
+            "position": state.position().round() as u8,
+            "target": state.target,
+            "motion": state.motion,
+            "calibration": state.calibration,
+        });
+        if self.transport == CoverTransport::Web {
+            value["reported"] = self.reconciler.info();
+        }
+        self.io.locks().describe(&self.id, &mut value);
+        value
+    }
+
+    /// The motor draws power only while moving.
+    fn power_level(&self) -> Option<f64> {
+        let moving = self.state.read().unwrap().motion != Motion::Stopped;
+        Some(if moving { 1.0 } else { 0.0 })
+    }
+
+    fn update(&self, value: Value) -> Result<()> {
+        self.io.locks().check(&self.id)?;
+        if let Some(calibration) = value.get("calibration") {
+            let calibration: Calibration = serde_json::from_value(calibration.clone())?;
+            calibration.validate()?;
+            let mut state = self.state.write().unwrap();
+            let position = state.position();
+            state.position = position;
+            state.started = Instant::now();
+            state.calibration = calibration;
+        }
+
+        // Known position of the stopped cover, e.g. after a manual check.
+        if let Some(position) = value["position"].as_u64() {
+            let mut state = self.state.write().unwrap();
+            if state.motion != Motion::Stopped {
+                return Err(Error::msg("Cover position can be set only when stopped"));
+            }
+            if position > 100 {
+                return Err(Error::msg(format!("Invalid cover position: {}", position)));
+            }
+            state.position = position as f64;
+            state.target = position as u8;
+        }
+
+        if let Some(action) = value["action"].as_str() {
+            return match action {
+                "open" => self.open(),
+                "close" => self.close(),
+                "stop" => self.stop(),
+                _ => Err(Error::msg(format!("Unknown cover action: {}", action))),
+            };
+        }
+
+        if let Some(target) = value["target"].as_u64() {
+            if target > 100 {
+                return Err(Error::msg(format!("Invalid cover position: {}", target)));
+            }
+            return self.move_to(target as u8);
+        }
+        Ok(())
+    }
+
+    fn reconcile(&self) -> Result<()> {
+        match self.transport {
+            CoverTransport::Web => self.reconciler.reconcile(&self.io, &self.id, self.cmd()),
+            CoverTransport::Serial { .. } => self.flush(),
+        }
+    }
+}
+
+impl Flush for Cover {
+    fn flush(&self) -> Result<()> {
+        match self.transport {
+            CoverTransport::Serial { up, down, .. } => {
+                let motion = self.state.read().unwrap().motion;
+                // The relay of the opposite direction is always released first.
+                let (off, on) = match motion {
+                    Motion::Opening => (vec![down], Some(up)),
+                    Motion::Closing => (vec![up], Some(down)),
+                    Motion::Stopped => (vec![up, down], None),
+                };
+                for p_id in off {
+                    self.io.serial_write(Cmd::new(SWITCH, p_id, 0x02))?;
+                }
+                if let Some(p_id) = on {
+                    self.io.serial_write(Cmd::new(SWITCH, p_id, 0x01))?;
+                }
+                Ok(())
+            }
+            CoverTransport::Web => self.io.send(&self.id, self.cmd()),
+        }
+    }
+}
+
+/// Position after the motor ran for `elapsed` from `position`.
+fn estimate(position: f64, motion: Motion, elapsed: Duration, calibration: &Calibration) -> f64 {
+    let elapsed = elapsed.as_millis() as f64;
+    match motion {
+        Motion::Stopped => position,
+        Motion::Opening => {
+            (position + elapsed * 100.0 / calibration.open_time_ms as f64).min(100.0)
+        }
+        Motion::Closing => (position - elapsed * 100.0 / calibration.close_time_ms as f64).max(0.0),
+    }
+}
+
+/// Run time of the motor from `position` to `target`, None if it is already there.
+fn travel_time(position: f64, target: u8, calibration: &Calibration) -> Option<Duration> {
+    let distance = target as f64 - position;
+    let full_time = if distance > 0.0 {
+        calibration.open_time_ms
+    } else if distance < 0.0 {
+        calibration.close_time_ms
+    } else {
+        return None;
+    };
+    let mut time = (distance.abs() * full_time as f64 / 100.0).round() as u64;
+    if target == 0 || target == 100 {
+        time += calibration.overrun_ms;
+    }
+    Some(Duration::from_millis(time))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::cover::{estimate, travel_time, Calibration, Motion};
+    use crate::devices::{Control, Cover, CoverTransport};
+    use crate::io::{Cmd, Recorder, IO};
+    use crate::runtime::Runtime;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_estimation() {
+        let calibration = Calibration {
+            open_time_ms: 20_000,
+            close_time_ms: 10_000,
+            overrun_ms: 1_000,
+        };
+        let elapsed = Duration::from_secs(5);
+        assert_eq!(estimate(10.0, Motion::Opening, elapsed, &calibration), 35.0);
+        assert_eq!(estimate(10.0, Motion::Closing, elapsed, &calibration), 0.0);
+        assert_eq!(
+            estimate(90.0, Motion::Opening, elapsed, &calibration),
+            100.0
+        );
+        assert_eq!(estimate(40.0, Motion::Stopped, elapsed, &calibration), 40.0);
+
+        assert_eq!(
+            travel_time(0.0, 50, &calibration),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            travel_time(50.0, 0, &calibration),
+            Some(Duration::from_secs(6))
+        );
+        assert_eq!(travel_time(50.0, 50, &calibration), None);
+    }
+
+    #[test]
+    fn test_serial_cover() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        let calibration = Calibration {
+            open_time_ms: 500,
+            close_time_ms: 500,
+            overrun_ms: 0,
+        };
+        let cover = Cover::new(
+            &mut io,
+            "living_room_blind",
+            CoverTransport::Serial {
+                up: 0x10,
+                down: 0x11,
+                reversal_ms: 0,
+            },
+            calibration,
+        );
+
+        cover.update(json!({"action": "open"})).unwrap();
+        assert_eq!(cover.load()["motion"], json!("opening"));
+        assert_eq!(
+            recorder.serial(),
+            vec![Cmd::new(0x02, 0x11, 0x02), Cmd::new(0x02, 0x10, 0x01)]
+        );
+
+        let start = Instant::now();
+        while cover.load()["motion"] != json!("stopped") && start.elapsed() < Duration::from_secs(5)
+        {
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(cover.load()["position"], json!(100));
+        assert_eq!(
+            recorder.serial()[2..],
+            [Cmd::new(0x02, 0x10, 0x02), Cmd::new(0x02, 0x11, 0x02)]
+        );
+
+        recorder.clear();
+        cover.update(json!({"target": 40})).unwrap();
+        assert_eq!(cover.load()["motion"], json!("closing"));
+        cover.update(json!({"action": "stop"})).unwrap();
+        assert_eq!(cover.load()["motion"], json!("stopped"));
+        assert!(cover.load()["position"].as_u64().unwrap() > 40);
+        assert!(cover.update(json!({"target": 101})).is_err());
+        assert!(cover
+            .update(
+                json!({"calibration": {"open_time_ms": 0, "close_time_ms": 1, "overrun_ms": 0}})
+            )
+            .is_err());
+
+        cover.update(json!({"position": 0})).unwrap();
+        assert_eq!(cover.load()["position"], json!(0));
+    }
+
+    #[test]
+    fn test_reversal() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        let cover = Cover::new(
+            &mut io,
+            "blind",
+            CoverTransport::Serial {
+                up: 0x10,
+                down: 0x11,
+                reversal_ms: 500,
+            },
+            Calibration::default(),
+        );
+        cover.update(json!({"position": 50})).unwrap();
+
+        cover.open().unwrap();
+        recorder.clear();
+        let start = Instant::now();
+        cover.close().unwrap();
+        // Both relays are released, the opposite one is engaged after the dead time.
+        assert_eq!(
+            recorder.serial(),
+            vec![Cmd::new(0x02, 0x10, 0x02), Cmd::new(0x02, 0x11, 0x02)]
+        );
+        assert_eq!(cover.load()["motion"], json!("stopped"));
+        assert_eq!(cover.load()["target"], json!(0));
+
+        while recorder.serial().len() < 4 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert_eq!(
+            recorder.serial()[2..],
+            [Cmd::new(0x02, 0x10, 0x02), Cmd::new(0x02, 0x11, 0x01)]
+        );
+        assert_eq!(cover.load()["motion"], json!("closing"));
+
+        // The same direction starts at once.
+        cover.stop().unwrap();
+        recorder.clear();
+        cover.close().unwrap();
+        assert_eq!(
+            recorder.serial(),
+            vec![Cmd::new(0x02, 0x10, 0x02), Cmd::new(0x02, 0x11, 0x01)]
+        );
+        cover.stop().unwrap();
+    }
+
+    #[test]
+    fn test_offline_web_cover() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        let cover = Cover::new(
+            &mut io,
+            "blind",
+            CoverTransport::Web,
+            Calibration::default(),
+        );
+        let io = io.freeze();
+
+        recorder.set_offline("blind");
+        assert!(cover.update(json!({"action": "open"})).is_err());
+        assert_eq!(cover.load()["motion"], json!("stopped"));
+        assert_eq!(cover.load()["position"], json!(0));
+        // Only the stop is kept for the device, never the motion.
+        assert_eq!(
+            io.pending("blind").unwrap().cmd.args,
+            vec!["STOP".to_owned()]
+        );
+    }
+}
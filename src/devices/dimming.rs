@@ -0,0 +1,211 @@
+use crate::devices::{Control, SerialDimmer};
+use crate::home::configuration::{ConfigValue, Configuration, OnUpdate};
+use crate::storage::Storage;
+use anyhow::{Error, Result};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Config key and storage name of the dimmer calibrations: {dimmer_id: DimmerCalibration}
+pub const DIMMING: &str = "dimming";
+/// Serial args of the full and of the lowest output of the dimmer board, 255 - off.
+const BRIGHTEST_ARG: f64 = 26.0;
+const DIMMEST_ARG: f64 = 229.0;
+const OFF_ARG: u8 = 255;
+
+///
+/// Maps the brightness the user asked for to the output power.
+/// "linear" | "cie" | {"gamma": 2.2} | {"table": [0, 2, 10, 30, 100]}
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DimmingCurve {
+    Linear,
+    Gamma(f64),
+    /// CIE 1931 lightness, perceived brightness grows evenly.
+    Cie,
+    /// Output in percents at evenly spaced brightness points, linear between them.
+    Table(Vec<u8>),
+}
+
+impl DimmingCurve {
+    fn validate(&self) -> Result<()> {
+        match self {
+            DimmingCurve::Gamma(gamma) if !(0.1..=5.0).contains(gamma) => {
+                Err(Error::msg(format!("Invalid gamma: {}", gamma)))
+            }
+            DimmingCurve::Table(points)
+                if points.len() < 2 || points.iter().any(|point| *point > 100) =>
+            {
+                Err(Error::msg(format!("Invalid dimming table: {:?}", points)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Output 0..1 for the brightness 0..1.
+    fn apply(&self, brightness: f64) -> f64 {
+        let x = brightness.clamp(0.0, 1.0);
+        match self {
+            DimmingCurve::Linear => x,
+            DimmingCurve::Gamma(gamma) => x.powf(*gamma),
+            DimmingCurve::Cie => {
+                let lightness = x * 100.0;
+                if lightness <= 8.0 {
+                    lightness / 903.3
+                } else {
+                    ((lightness + 16.0) / 116.0).powi(3)
+                }
+            }
+            DimmingCurve::Table(points) => {
+                let position = x * (points.len() - 1) as f64;
+                let i = (position.floor() as usize).min(points.len() - 2);
+                let (from, to) = (points[i] as f64, points[i + 1] as f64);
+                (from + (to - from) * (position - i as f64)) / 100.0
+            }
+        }
+    }
+}
+
+///
+/// Dimming curve and the usable output range of the lamp in percents of the board output.
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DimmerCalibration {
+    pub curve: DimmingCurve,
+    /// Lowest output at which the lamp still glows.
+    pub min_value: u8,
+    pub max_value: u8,
+}
+
+impl DimmerCalibration {
+    pub fn linear(min_value: u8, max_value: u8) -> DimmerCalibration {
+        DimmerCalibration {
+            curve: DimmingCurve::Linear,
+            min_value,
+            max_value,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.min_value > self.max_value || self.max_value > 100 {
+            return Err(Error::msg(format!(
+                "Invalid dimmer range: {}..{}",
+                self.min_value, self.max_value
+            )));
+        }
+        self.curve.validate()
+    }
+
+    /// Serial arg of the brightness 0-100.
+    pub fn arg(&self, is_on: bool, brightness: f64) -> u8 {
+        let range = (self.max_value - self.min_value) as f64;
+        let output = self.min_value as f64 + self.curve.apply(brightness / 100.0) * range;
+        if !is_on || output <= 0.0 {
+            OFF_ARG
+        } else {
+            (BRIGHTEST_ARG + (100.0 - output) * (DIMMEST_ARG - BRIGHTEST_ARG) / 100.0) as u8
+        }
+    }
+}
+
+///
+/// Calibrations of the dimmers, editable through the configuration and kept on disk.
+///
+#[derive(Debug, Clone)]
+pub struct DimmerCalibrations {
+    storage: Storage,
+    dimmers: Arc<HashMap<String, SerialDimmer>>,
+}
+
+impl DimmerCalibrations {
+    /// Applies the saved calibrations to the dimmers.
+    pub fn new(storage: Storage, dimmers: Vec<SerialDimmer>) -> DimmerCalibrations {
+        let calibrations = DimmerCalibrations {
+            storage,
+            dimmers: Arc::new(
+                dimmers
+                    .into_iter()
+                    .map(|dimmer| (dimmer.id().to_owned(), dimmer))
+                    .collect(),
+            ),
+        };
+        match calibrations
+            .storage
+            .load::<BTreeMap<String, DimmerCalibration>>(DIMMING)
+        {
+            Ok(Some(saved)) => {
+                if let Err(err) = calibrations.apply(&saved) {
+                    error!("Failed to apply dimmer calibrations: {}", err);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => error!("Failed to load dimmer calibrations: {}", err),
+        }
+        calibrations
+    }
+
+    pub fn register(&self, config: &Configuration) -> Result<()> {
+        config.add(DIMMING, ConfigValue::new(self.get(), self.clone())?);
+        Ok(())
+    }
+
+    fn get(&self) -> BTreeMap<String, DimmerCalibration> {
+        self.dimmers
+            .iter()
+            .map(|(id, dimmer)| (id.to_owned(), dimmer.calibration()))
+            .collect()
+    }
+
+    fn apply(&self, calibrations: &BTreeMap<String, DimmerCalibration>) -> Result<()> {
+        for (id, calibration) in calibrations {
+            calibration
+                .validate()
+                .map_err(|err| Error::msg(format!("Dimmer {}: {}", id, err)))?;
+            if !self.dimmers.contains_key(id) {
+                return Err(Error::msg(format!("Unknown dimmer: {}", id)));
+            }
+        }
+        for (id, calibration) in calibrations {
+            self.dimmers[id].calibrate(calibration.clone())?;
+        }
+        Ok(())
+    }
+}
+
+impl OnUpdate for DimmerCalibrations {
+    fn on_update(&self, value: Value) -> Result<(), Error> {
+        let calibrations: BTreeMap<String, DimmerCalibration> = serde_json::from_value(value)?;
+        self.apply(&calibrations)?;
+        info!("Update dimmer calibrations: {:?}", calibrations.keys());
+        self.storage.save(DIMMING, &self.get())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::dimming::{DimmerCalibration, DimmingCurve};
+
+    #[test]
+    fn test_curves() {
+        let linear = DimmerCalibration::linear(0, 100);
+        assert_eq!(linear.arg(true, 100.0), 26);
+        assert_eq!(linear.arg(true, 40.0), 147);
+        assert_eq!(linear.arg(true, 0.0), 255);
+        assert_eq!(linear.arg(false, 100.0), 255);
+
+        let cie = DimmerCalibration {
+            curve: DimmingCurve::Cie,
+            ..linear.clone()
+        };
+        assert!(cie.arg(true, 1.0) > 227);
+        assert!(cie.arg(true, 50.0) > linear.arg(true, 50.0));
+        assert_eq!(cie.arg(true, 100.0), 26);
+
+        assert_eq!(DimmingCurve::Gamma(2.0).apply(0.5), 0.25);
+        assert_eq!(DimmingCurve::Table(vec![0, 10, 100]).apply(0.25), 0.05);
+        assert_eq!(DimmingCurve::Table(vec![0, 10, 100]).apply(1.0), 1.0);
+        assert!(DimmingCurve::Table(vec![50]).validate().is_err());
+        assert!(DimmerCalibration::linear(60, 40).validate().is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use crate::io::IO;
+use crate::log_error;
+use crate::runtime::Background;
+use crate::storage::Storage;
+use anyhow::{Error, Result};
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveDate};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const ENERGY: &str = "energy";
+/// {device_id: watts}, nominal power of the devices at full level.
+const WATTAGE: &str = "wattage";
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+/// Longer gaps between samples, e.g. after a suspend, are not accounted.
+const MAX_GAP_SECS: f64 = 60.0;
+const SAVE_INTERVAL_SECS: i64 = 60;
+/// Daily totals are kept for this number of days, monthly totals forever.
+const KEEP_DAYS: i64 = 92;
+
+///
+/// On-time weighted by the power level and the energy estimated by the nominal wattage.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub on_secs: f64,
+    pub energy_kwh: f64,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.on_secs += other.on_secs;
+        self.energy_kwh += other.energy_kwh;
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Totals {
+    /// {"2026-10-19": {device_id: usage}}
+    days: BTreeMap<String, HashMap<String, Usage>>,
+    /// {"2026-10": {device_id: usage}}
+    months: BTreeMap<String, HashMap<String, Usage>>,
+}
+
+#[derive(Debug, Default)]
+struct Meter {
+    totals: Totals,
+    last_sample: Option<DateTime<Local>>,
+    last_save: Option<DateTime<Local>>,
+}
+
+///
+/// Consumption of the period by device and by room.
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct EnergyReport {
+    pub period: String,
+    pub devices: BTreeMap<String, Usage>,
+    pub rooms: BTreeMap<String, Usage>,
+}
+
+///
+/// Accumulates the usage of every device and keeps the totals on disk.
+///
+#[derive(Debug, Clone)]
+pub struct EnergyMeter {
+    io: IO,
+    storage: Storage,
+    wattage: Arc<HashMap<String, f64>>,
+    meter: Arc<Mutex<Meter>>,
+}
+
+impl EnergyMeter {
+    pub fn new(io: &IO, storage: Storage) -> EnergyMeter {
+        let wattage = match storage.load::<HashMap<String, f64>>(WATTAGE) {
+            Ok(wattage) => wattage.unwrap_or_default(),
+            Err(err) => {
+                error!("Failed to load device wattage: {}", err);
+                HashMap::new()
+            }
+        };
+        let totals = match storage.load::<Totals>(ENERGY) {
+            Ok(totals) => totals.unwrap_or_default(),
+            Err(err) => {
+                error!("Failed to load energy totals: {}", err);
+                Totals::default()
+            }
+        };
+
+        EnergyMeter {
+            io: io.clone(),
+            storage,
+            wattage: Arc::new(wattage),
+            meter: Arc::new(Mutex::new(Meter {
+                totals,
+                ..Default::default()
+            })),
+        }
+    }
+
+    pub fn start(&self) -> Background {
+        let meter = self.clone();
+        Background::every(self.io.runtime(), SAMPLE_INTERVAL, true, move || {
+            log_error!(meter.sample(Local::now()));
+        })
+    }
+
+    /// Accounts the time since the previous sample at the current power levels.
+    fn sample(&self, now: DateTime<Local>) -> Result<()> {
+        let mut meter = self.meter.lock().unwrap();
+        let elapsed = match meter.last_sample.replace(now) {
+            Some(last) => (now - last).num_milliseconds() as f64 / 1000.0,
+            None => return Ok(()),
+        };
+        if elapsed <= 0.0 || elapsed > MAX_GAP_SECS {
+            return Ok(());
+        }
+
+        let day = now.format("%Y-%m-%d").to_string();
+        let month = now.format("%Y-%m").to_string();
+        for (id, device) in self.io.device_holder().devices() {
+            let level = match device.power_level() {
+                Some(level) if level > 0.0 => level,
+                _ => continue,
+            };
+            let watts = self.wattage.get(id).copied().unwrap_or(0.0);
+            let usage = Usage {
+                on_secs: level * elapsed,
+                energy_kwh: watts * level * elapsed / 3600.0 / 1000.0,
+            };
+            let totals = &mut meter.totals;
+            for period in [
+                totals.days.entry(day.clone()).or_default(),
+                totals.months.entry(month.clone()).or_default(),
+            ] {
+                period.entry(id.to_owned()).or_default().add(&usage);
+            }
+        }
+
+        if meter
+            .last_save
+            .is_none_or(|last| (now - last).num_seconds() >= SAVE_INTERVAL_SECS)
+        {
+            let oldest = (now - ChronoDuration::days(KEEP_DAYS))
+                .format("%Y-%m-%d")
+                .to_string();
+            meter.totals.days = meter.totals.days.split_off(&oldest);
+            self.storage.save(ENERGY, &meter.totals)?;
+            meter.last_save = Some(now);
+        }
+        Ok(())
+    }
+
+    /// Totals of the day "2026-10-19" or of the month "2026-10".
+    pub fn report(&self, period: &str) -> Result<EnergyReport> {
+        let meter = self.meter.lock().unwrap();
+        let totals = if NaiveDate::parse_from_str(period, "%Y-%m-%d").is_ok() {
+            meter.totals.days.get(period)
+        } else if NaiveDate::parse_from_str(&format!("{}-01", period), "%Y-%m-%d").is_ok() {
+            meter.totals.months.get(period)
+        } else {
+            return Err(Error::msg(format!("Invalid period: {}", period)));
+        };
+
+        let mut report = EnergyReport {
+            period: period.to_owned(),
+            devices: BTreeMap::new(),
+            rooms: BTreeMap::new(),
+        };
+        let holder = self.io.device_holder();
+        for (id, usage) in totals.into_iter().flatten() {
+            report.devices.insert(id.to_owned(), *usage);
+            if let Some(room) = holder.meta(id).and_then(|meta| meta.room.as_ref()) {
+                report.rooms.entry(room.to_owned()).or_default().add(usage);
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::energy::{EnergyMeter, Usage};
+    use crate::devices::{DeviceMeta, SerialDimmer, SerialSwitch, Switch};
+    use crate::io::{Recorder, IO};
+    use crate::runtime::Runtime;
+    use crate::storage::Storage;
+    use chrono::{Duration, Local, TimeZone};
+
+    #[test]
+    fn test_energy() {
+        let storage = Storage::temp();
+        storage
+            .save("wattage", &json!({"lamp": 60.0, "fun": 30.0}))
+            .unwrap();
+        let mut io = IO::with_output(&Runtime::new(1), Recorder::new());
+        let lamp = SerialDimmer::new(&mut io, "lamp", 0x01, 0, 100);
+        let fun = SerialSwitch::new(&mut io, "fun", 0x02);
+        io.describe("lamp", DeviceMeta::new(Some("kitchen"), "Lamp"))
+            .unwrap();
+        io.describe("fun", DeviceMeta::new(Some("kitchen"), "Fan"))
+            .unwrap();
+        let io = io.freeze();
+        let meter = EnergyMeter::new(&io, storage.clone());
+
+        // Half of the power for 2 minutes.
+        lamp.set_power(50).unwrap();
+        lamp.switch(true).unwrap();
+        fun.switch(true).unwrap();
+        let start = Local.ymd(2026, 10, 19).and_hms(12, 0, 0);
+        meter.sample(start).unwrap();
+        meter.sample(start + Duration::seconds(60)).unwrap();
+        fun.switch(false).unwrap();
+        meter.sample(start + Duration::seconds(120)).unwrap();
+        // The gap is too long, e.g. the controller was stopped.
+        meter.sample(start + Duration::seconds(600)).unwrap();
+
+        let report = meter.report("2026-10-19").unwrap();
+        assert_eq!(
+            report.devices["lamp"],
+            Usage {
+                on_secs: 60.0,
+                energy_kwh: 0.001,
+            }
+        );
+        assert_eq!(report.devices["fun"].on_secs, 60.0);
+        assert_eq!(report.rooms["kitchen"].on_secs, 120.0);
+        assert_eq!(meter.report("2026-10").unwrap().devices.len(), 2);
+        assert!(meter.report("2026-10-18").unwrap().devices.is_empty());
+        assert!(meter.report("yesterday").is_err());
+
+        let restarted = EnergyMeter::new(&io, storage);
+        assert_eq!(restarted.report("2026-10-19").unwrap().devices.len(), 2);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::devices::{Member, Source};
+use crate::home::configuration::{ConfigValue, Configuration, OnUpdate};
+use crate::log_error;
+use crate::runtime::{time_ms, Background, Runtime};
+use crate::storage::Storage;
+use anyhow::{Error, Result};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Config key and storage name of the relations: {follower_id: Follow}
+pub const FOLLOWERS: &str = "followers";
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+///
+/// The follower switches on and off with its leader, e.g. a fan with the lamp.
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Follow {
+    pub leader: String,
+    /// The follower turns on once the leader has been on this long.
+    #[serde(default)]
+    pub on_delay_secs: u64,
+    /// The follower keeps running this long after the leader turned off...
+    #[serde(default)]
+    pub after_run_secs: u64,
+    /// ...if the leader has been on at least this long, otherwise it turns off with the leader.
+    #[serde(default)]
+    pub min_on_secs: u64,
+}
+
+impl Follow {
+    pub fn new(leader: &str, on_delay_secs: u64, after_run_secs: u64, min_on_secs: u64) -> Follow {
+        Follow {
+            leader: leader.to_owned(),
+            on_delay_secs,
+            after_run_secs,
+            min_on_secs,
+        }
+    }
+}
+
+/// Last seen state of the leader and the pending switches of the follower, unix ms.
+#[derive(Debug, Default)]
+struct Tracking {
+    leader_on: bool,
+    since: u128,
+    on_at: Option<u128>,
+    off_at: Option<u128>,
+}
+
+#[derive(Debug)]
+struct Relation {
+    follow: Follow,
+    tracking: Tracking,
+}
+
+///
+/// Follower relations between switchable devices, editable through the configuration and kept on disk.
+/// The leaders are checked on every sync, so only their transitions move the followers
+/// and the followers can still be switched by hand.
+///
+#[derive(Debug, Clone)]
+pub struct Followers {
+    storage: Storage,
+    rt: Runtime,
+    devices: Arc<HashMap<String, Box<dyn Member>>>,
+    relations: Arc<Mutex<BTreeMap<String, Relation>>>,
+}
+
+impl Followers {
+    /// Relations saved on disk or the defaults.
+    pub fn new(
+        rt: &Runtime,
+        storage: Storage,
+        devices: Vec<Box<dyn Member>>,
+        defaults: BTreeMap<String, Follow>,
+    ) -> Followers {
+        let followers = Followers {
+            storage,
+            rt: rt.clone(),
+            devices: Arc::new(
+                devices
+                    .into_iter()
+                    .map(|dev| (dev.id().to_owned(), dev))
+                    .collect(),
+            ),
+            relations: Default::default(),
+        };
+        let saved = match followers
+            .storage
+            .load::<BTreeMap<String, Follow>>(FOLLOWERS)
+        {
+            Ok(saved) => saved,
+            Err(err) => {
+                error!("Failed to load followers: {}", err);
+                None
+            }
+        };
+        if let Err(err) = followers.apply(saved.unwrap_or_else(|| defaults.clone())) {
+            error!("Invalid followers: {}", err);
+            log_error!(followers.apply(defaults));
+        }
+        followers
+    }
+
+    pub fn register(&self, config: &Configuration) -> Result<()> {
+        config.add(FOLLOWERS, ConfigValue::new(self.get(), self.clone())?);
+        Ok(())
+    }
+
+    /// Checks the leaders every second.
+    pub fn start(&self) -> Background {
+        let followers = self.clone();
+        Background::every(&self.rt, SYNC_INTERVAL, true, move || followers.sync())
+    }
+
+    /// Moves the followers after the changes of their leaders.
+    /// The followers move as the automation, whoever switched the leader.
+    pub fn sync(&self) {
+        Source::Automation.run(|| self.sync_at(time_ms()));
+    }
+
+    /// The switches are collected under the lock and sent without it, a send may block.
+    fn sync_at(&self, now: u128) {
+        let switches = {
+            let mut relations = self.relations.lock().unwrap();
+            relations
+                .iter_mut()
+                .filter_map(|(id, relation)| {
+                    Self::step(self.devices[&relation.follow.leader].is_on(), relation, now)
+                        .map(|is_on| (id.to_owned(), is_on))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for (id, is_on) in switches {
+            let follower = &self.devices[&id];
+            if follower.is_on() != is_on {
+                if let Err(err) = follower.switch(is_on) {
+                    error!("Failed to switch follower {}: {}", id, err);
+                }
+            }
+        }
+    }
+
+    /// Tracks the leader, returns the state the follower must take now.
+    fn step(leader_on: bool, relation: &mut Relation, now: u128) -> Option<bool> {
+        let follow = &relation.follow;
+        let tracking = &mut relation.tracking;
+        if leader_on != tracking.leader_on {
+            tracking.leader_on = leader_on;
+            if leader_on {
+                tracking.since = now;
+                tracking.off_at = None;
+                tracking.on_at = Some(now + secs(follow.on_delay_secs));
+            } else {
+                tracking.on_at = None;
+                let after_run = if now.saturating_sub(tracking.since) >= secs(follow.min_on_secs) {
+                    secs(follow.after_run_secs)
+                } else {
+                    0
+                };
+                tracking.off_at = Some(now + after_run);
+            }
+        }
+
+        if tracking.on_at.is_some_and(|at| at <= now) {
+            tracking.on_at = None;
+            return Some(true);
+        }
+        if tracking.off_at.is_some_and(|at| at <= now) {
+            tracking.off_at = None;
+            return Some(false);
+        }
+        None
+    }
+
+    fn get(&self) -> BTreeMap<String, Follow> {
+        self.relations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, relation)| (id.to_owned(), relation.follow.clone()))
+            .collect()
+    }
+
+    /// Replaces the relations. Relations with the same leader keep their pending switches.
+    fn apply(&self, follows: BTreeMap<String, Follow>) -> Result<()> {
+        for (id, follow) in &follows {
+            for dev in &[id, &follow.leader] {
+                if !self.devices.contains_key(*dev) {
+                    return Err(Error::msg(format!("Unknown switchable device: {}", dev)));
+                }
+            }
+            // Walk up the leaders, a follower must not lead itself.
+            let mut leader = &follow.leader;
+            for _ in 0..follows.len() {
+                if leader == id {
+                    return Err(Error::msg(format!("Follower {} leads itself", id)));
+                }
+                match follows.get(leader) {
+                    Some(next) => leader = &next.leader,
+                    None => break,
+                }
+            }
+        }
+
+        let mut relations = self.relations.lock().unwrap();
+        let mut previous = std::mem::take(&mut *relations);
+        for (id, follow) in follows {
+            let tracking = match previous.remove(&id) {
+                Some(relation) if relation.follow.leader == follow.leader => relation.tracking,
+                _ => Tracking {
+                    leader_on: self.devices[&follow.leader].is_on(),
+                    since: time_ms(),
+                    ..Default::default()
+                },
+            };
+            relations.insert(id, Relation { follow, tracking });
+        }
+        Ok(())
+    }
+}
+
+fn secs(secs: u64) -> u128 {
+    secs as u128 * 1000
+}
+
+impl OnUpdate for Followers {
+    fn on_update(&self, value: Value) -> Result<(), Error> {
+        let follows: BTreeMap<String, Follow> = serde_json::from_value(value)?;
+        self.apply(follows)?;
+        info!("Update followers: {:?}", self.get());
+        self.storage.save(FOLLOWERS, &self.get())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::follower::{Follow, Followers, FOLLOWERS};
+    use crate::devices::{Member, SerialDimmer, SerialSwitch, Switch};
+    use crate::home::configuration::Configuration;
+    use crate::io::{Recorder, IO};
+    use crate::runtime::Runtime;
+    use crate::storage::Storage;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_followers() {
+        let storage = Storage::temp();
+        let mut io = IO::with_output(&Runtime::new(1), Recorder::new());
+        let lamp = SerialDimmer::new(&mut io, "lamp", 0x01, 0, 100);
+        let fan = SerialSwitch::new(&mut io, "fan", 0x02);
+        let devices: Vec<Box<dyn Member>> = vec![Box::new(lamp.clone()), Box::new(fan.clone())];
+        let mut defaults = BTreeMap::new();
+        defaults.insert("fan".to_owned(), Follow::new("lamp", 10, 180, 30));
+        let followers = Followers::new(io.rt(), storage.clone(), devices, defaults);
+
+        // Short visit, the fan never starts.
+        lamp.switch(true).unwrap();
+        followers.sync_at(1_000);
+        followers.sync_at(5_000);
+        assert!(!fan.is_on());
+        lamp.switch(false).unwrap();
+        followers.sync_at(6_000);
+        followers.sync_at(20_000);
+        assert!(!fan.is_on());
+
+        // Long visit, the fan runs 3 minutes more.
+        lamp.switch(true).unwrap();
+        followers.sync_at(100_000);
+        followers.sync_at(110_000);
+        assert!(fan.is_on());
+        lamp.switch(false).unwrap();
+        followers.sync_at(200_000);
+        followers.sync_at(379_000);
+        assert!(fan.is_on());
+        followers.sync_at(380_000);
+        assert!(!fan.is_on());
+
+        let config = Configuration::default();
+        followers.register(&config).unwrap();
+        let err = config
+            .update(FOLLOWERS, json!({"fan": {"leader": "fan"}}))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Follower fan leads itself");
+        let err = config
+            .update(
+                FOLLOWERS,
+                json!({"fan": {"leader": "lamp"}, "lamp": {"leader": "fan"}}),
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Follower fan leads itself");
+        config
+            .update(FOLLOWERS, json!({"fan": {"leader": "lamp"}}))
+            .unwrap();
+
+        // Without delays the fan switches with the lamp.
+        lamp.switch(true).unwrap();
+        followers.sync_at(400_000);
+        assert!(fan.is_on());
+        lamp.switch(false).unwrap();
+        followers.sync_at(500_000);
+        assert!(!fan.is_on());
+
+        let devices: Vec<Box<dyn Member>> = vec![Box::new(lamp), Box::new(fan)];
+        let restarted = Followers::new(io.rt(), storage, devices, BTreeMap::new());
+        assert_eq!(restarted.get()["fan"], Follow::new("lamp", 0, 0, 0));
+    }
+}
@@ -0,0 +1,209 @@
+use crate::devices::{Control, DeviceType, Flush, Locks, Schema, Switch};
+use crate::io::IOMut;
+use anyhow::{Error, Result};
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+
+/// Device which can be a member of a group.
+pub trait Member: Control + Switch {}
+
+impl<T: Control + Switch> Member for T {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregate {
+    /// The group is on if any member is on.
+    AnyOn,
+    /// The group is on only if every member is on.
+    AllOn,
+}
+
+///
+/// Set of devices of any type addressed by one id. Members are called in order.
+///
+#[derive(Debug, Clone)]
+pub struct DeviceGroup {
+    id: Arc<String>,
+    members: Arc<Vec<Box<dyn Member>>>,
+    aggregate: Arc<RwLock<Aggregate>>,
+    locks: Locks,
+}
+
+impl DeviceGroup {
+    pub fn new(
+        io: &mut IOMut,
+        id: &str,
+        aggregate: Aggregate,
+        members: Vec<Box<dyn Member>>,
+    ) -> DeviceGroup {
+        let dev = DeviceGroup {
+            id: Arc::new(id.to_owned()),
+            members: Arc::new(members),
+            aggregate: Arc::new(RwLock::new(aggregate)),
+            locks: io.shared().locks().clone(),
+        };
+        io.reg_device(Box::new(dev.clone()));
+        dev
+    }
+
+    pub(super) fn schema() -> Schema {
+        Schema::object(vec![
+            ("is_on", Schema::Bool),
+            ("aggregate", Schema::Enum(vec!["any_on", "all_on"])),
+            // Validated against the schema of every member before the update.
+            ("state", Schema::Any),
+        ])
+    }
+
+    /// Applies the action to every member, even if some of them fail.
+    fn for_each<A>(&self, action: A) -> Result<()>
+    where
+        A: Fn(&dyn Member) -> Result<()>,
+    {
+        let errors = self
+            .members
+            .iter()
+            .filter_map(|member| {
+                action(member.as_ref())
+                    .err()
+                    .map(|err| format!("{}: {}", member.id(), err))
+            })
+            .collect::<Vec<_>>();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::msg(format!(
+                "Group {} failed: {}",
+                self.id,
+                errors.join(", ")
+            )))
+        }
+    }
+}
+
+impl Switch for DeviceGroup {
+    fn is_on(&self) -> bool {
+        match *self.aggregate.read().unwrap() {
+            Aggregate::AnyOn => self.members.iter().any(|member| member.is_on()),
+            Aggregate::AllOn => self.members.iter().all(|member| member.is_on()),
+        }
+    }
+
+    fn switch(&self, is_on: bool) -> Result<()> {
+        self.locks.check(&self.id)?;
+        self.for_each(|member| member.switch(is_on))
+    }
+}
+
+///
+/// State {is_on, aggregate, members: {id: state}}
+/// Update {aggregate} | {is_on} | {state} - the state is sent to every member.
+///
+impl Control for DeviceGroup {
+    fn id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    fn dev_type(&self) -> DeviceType {
+        DeviceType::Group
+    }
+
+    fn load(&self) -> Value {
+        let members = self
+            .members
+            .iter()
+            .map(|member| (member.id().to_owned(), member.load()))
+            .collect::<serde_json::Map<_, _>>();
+        let mut state = json!({
+            "is_on": self.is_on(),
+            "aggregate": *self.aggregate.read().unwrap(),
+            "members": members,
+        });
+        self.locks.describe(&self.id, &mut state);
+        state
+    }
+
+    fn update(&self, value: Value) -> Result<()> {
+        self.locks.check(&self.id)?;
+        if let Some(aggregate) = value.get("aggregate") {
+            *self.aggregate.write().unwrap() = serde_json::from_value(aggregate.clone())?;
+        }
+        if let Some(state) = value.get("state") {
+            // Either every member takes the state or none.
+            for member in self.members.iter() {
+                member
+                    .dev_type()
+                    .schema()
+                    .validate(state)
+                    .map_err(|err| Error::msg(format!("{}: {}", member.id(), err)))?;
+                member.guard(state)?;
+            }
+            self.for_each(|member| member.update(state.clone()))?;
+        }
+        if let Some(is_on) = value["is_on"].as_bool() {
+            self.switch(is_on)?;
+        }
+        Ok(())
+    }
+}
+
+impl Flush for DeviceGroup {
+    fn flush(&self) -> Result<()> {
+        self.for_each(|member| member.flush())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::{
+        Aggregate, Control, DeviceGroup, Member, SerialSwitch, Switch, WebSwitch,
+    };
+    use crate::io::{Call, Cmd, Recorder, IO};
+    use crate::runtime::Runtime;
+
+    #[test]
+    fn test_group() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        let lamp = SerialSwitch::new(&mut io, "lamp", 0x01);
+        let valve = WebSwitch::new(&mut io, "valve");
+        let members: Vec<Box<dyn Member>> = vec![Box::new(lamp.clone()), Box::new(valve.clone())];
+        let group = DeviceGroup::new(&mut io, "group", Aggregate::AnyOn, members);
+
+        lamp.switch(true).unwrap();
+        assert!(group.is_on());
+        group.update(json!({"aggregate": "all_on"})).unwrap();
+        assert!(!group.is_on());
+        assert_eq!(group.load()["is_on"], json!(false));
+
+        recorder.clear();
+        group.update(json!({"is_on": true})).unwrap();
+        assert!(group.is_on());
+        assert_eq!(
+            recorder.calls()[0],
+            Call::Serial(Cmd::new(0x02, 0x01, 0x01))
+        );
+        assert_eq!(
+            recorder.last_sent("valve").unwrap().args,
+            vec!["ON:100".to_owned()]
+        );
+
+        // A failing member doesn't stop the rest.
+        recorder.set_offline("valve");
+        recorder.clear();
+        let err = group.switch(false).unwrap_err();
+        assert!(err.to_string().contains("valve"));
+        assert_eq!(recorder.serial(), vec![Cmd::new(0x02, 0x01, 0x02)]);
+        assert!(!group.is_on());
+
+        // The lamp has no brightness, so neither member gets the state.
+        recorder.set_online("valve");
+        recorder.clear();
+        let err = group
+            .update(json!({"state": {"is_on": true, "brightness": 50}}))
+            .unwrap_err();
+        assert!(err.to_string().contains("lamp"));
+        assert!(recorder.calls().is_empty());
+    }
+}
@@ -0,0 +1,470 @@
+use crate::devices::web::Reconciler;
+use crate::devices::{Capability, Control, DeviceType, Flush, Schema, Switch};
+use crate::io::{Cmd, IOMut, Output, WebCmd, IO};
+use anyhow::{Error, Result};
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+
+/// Serial command: sets one pwm channel. id - port of the channel, args - level.
+const CHANNEL: u8 = 0x03;
+/// Color temperature of the warm and the cold channels of tunable white lights.
+const WARM_KELVIN: u16 = 2700;
+const COLD_KELVIN: u16 = 6500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LightKind {
+    /// Channels [r, g, b].
+    Rgb,
+    /// Channels [r, g, b, w].
+    Rgbw,
+    /// Channels [warm, cold].
+    TunableWhite,
+}
+
+impl LightKind {
+    fn channel_count(&self) -> u8 {
+        match self {
+            LightKind::Rgb => 3,
+            LightKind::Rgbw => 4,
+            LightKind::TunableWhite => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LightTransport {
+    /// Channel `i` is driven by the serial port `p_id + i`.
+    Serial {
+        p_id: u8,
+    },
+    Web,
+}
+
+impl LightTransport {
+    /// Checks that every channel of the light gets a serial port.
+    pub fn validate(&self, kind: LightKind) -> Result<()> {
+        match self {
+            LightTransport::Serial { p_id }
+                if p_id.checked_add(kind.channel_count() - 1).is_none() =>
+            {
+                Err(Error::msg(format!(
+                    "Serial ports of {:?} light from {} exceed 255",
+                    kind, p_id
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LightColor {
+    Rgb(u8, u8, u8),
+    /// Hue 0-360, saturation and value 0-100.
+    Hsv(u16, u8, u8),
+    Kelvin(u16),
+}
+
+impl LightColor {
+    fn validate(&self, kind: LightKind) -> Result<()> {
+        match self {
+            LightColor::Hsv(hue, saturation, value) => {
+                if *hue > 360 || *saturation > 100 || *value > 100 {
+                    return Err(Error::msg(format!("Invalid hsv color: {:?}", self)));
+                }
+            }
+            LightColor::Kelvin(kelvin) => {
+                if *kelvin < 1000 || *kelvin > 40000 {
+                    return Err(Error::msg(format!("Invalid color temperature: {}", kelvin)));
+                }
+            }
+            LightColor::Rgb(..) => {}
+        }
+
+        if kind == LightKind::TunableWhite {
+            if let LightColor::Kelvin(_) = self {
+            } else {
+                return Err(Error::msg("Tunable white light supports only kelvin color"));
+            }
+        }
+        Ok(())
+    }
+
+    fn rgb(&self) -> (u8, u8, u8) {
+        match *self {
+            LightColor::Rgb(r, g, b) => (r, g, b),
+            LightColor::Hsv(hue, saturation, value) => hsv_to_rgb(hue, saturation, value),
+            LightColor::Kelvin(kelvin) => kelvin_to_rgb(kelvin),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+struct LightState {
+    is_on: bool,
+    brightness: u8,
+    color: LightColor,
+}
+
+///
+/// Color or tunable white lamp.
+///
+#[derive(Debug, Clone)]
+pub struct ColorLight {
+    id: Arc<String>,
+    io: IO,
+    kind: LightKind,
+    transport: LightTransport,
+    state: Arc<RwLock<LightState>>,
+    reconciler: Reconciler,
+}
+
+impl ColorLight {
+    pub fn new(io: &mut IOMut, id: &str, kind: LightKind, transport: LightTransport) -> ColorLight {
+        let color = match kind {
+            LightKind::TunableWhite => LightColor::Kelvin(4000),
+            LightKind::Rgb | LightKind::Rgbw => LightColor::Rgb(255, 255, 255),
+        };
+        let dev = ColorLight {
+            id: Arc::new(id.to_owned()),
+            io: io.shared(),
+            kind,
+            transport,
+            state: Arc::new(RwLock::new(LightState {
+                is_on: false,
+                brightness: 100,
+                color,
+            })),
+            reconciler: Default::default(),
+        };
+        io.reg_device(Box::new(dev.clone()));
+        dev
+    }
+
+    pub(super) fn schema() -> Schema {
+        Schema::object(vec![
+            ("is_on", Schema::Bool),
+            ("brightness", Schema::percent()),
+            (
+                "color",
+                Schema::OneOf(vec![
+                    Schema::variant("rgb", Schema::Tuple(vec![Schema::byte(); 3])),
+                    Schema::variant(
+                        "hsv",
+                        Schema::Tuple(vec![
+                            Schema::integer(0, 360),
+                            Schema::percent(),
+                            Schema::percent(),
+                        ]),
+                    ),
+                    Schema::variant("kelvin", Schema::integer(1000, 40000)),
+                ]),
+            ),
+        ])
+    }
+
+    /// Channel levels 0-255 in the order of the light kind.
+    fn channels(&self, state: &LightState) -> Vec<u8> {
+        let level = if state.is_on {
+            state.brightness as u32
+        } else {
+            0
+        };
+        let scale = |val: u8| (val as u32 * level / 100) as u8;
+
+        match self.kind {
+            LightKind::Rgb => {
+                let (r, g, b) = state.color.rgb();
+                vec![scale(r), scale(g), scale(b)]
+            }
+            LightKind::Rgbw => {
+                let (r, g, b) = state.color.rgb();
+                let w = r.min(g).min(b);
+                vec![scale(r - w), scale(g - w), scale(b - w), scale(w)]
+            }
+            LightKind::TunableWhite => {
+                let kelvin = match state.color {
+                    LightColor::Kelvin(kelvin) => kelvin,
+                    _ => WARM_KELVIN,
+                };
+                let (warm, cold) = white_mix(kelvin);
+                vec![scale(warm), scale(cold)]
+            }
+        }
+    }
+
+    ///
+    /// v1 args ["ON|OFF:ch_1:ch_2:.."], v2 state {is_on, brightness, color, channels}
+    ///
+    fn cmd(&self) -> WebCmd {
+        let state = *self.state.read().unwrap();
+        let channels = self.channels(&state);
+        let mut arg = if state.is_on { "ON" } else { "OFF" }.to_owned();
+        for level in &channels {
+            arg.push_str(&format!(":{}", level));
+        }
+
+        WebCmd::new(
+            vec![arg],
+            json!({
+                "is_on": state.is_on,
+                "brightness": state.brightness,
+                "color": state.color,
+                "channels": channels,
+            }),
+        )
+    }
+}
+
+impl Switch for ColorLight {
+    fn is_on(&self) -> bool {
+        self.state.read().unwrap().is_on
+    }
+
+    fn switch(&self, is_on: bool) -> Result<()> {
+        self.io.locks().check(&self.id)?;
+        self.state.write().unwrap().is_on = is_on;
+        self.flush()
+    }
+}
+
+///
+/// State {is_on, brightness, color: {rgb: [r, g, b]} | {hsv: [h, s, v]} | {kelvin: k}, kind, channels}
+///
+impl Control for ColorLight {
+    fn id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    fn dev_type(&self) -> DeviceType {
+        DeviceType::ColorLight
+    }
+
+    fn load(&self) -> Value {
+        let state = *self.state.read().unwrap();
+        let mut value = json!({
+            "is_on": state.is_on,
+            "brightness": state.brightness,
+            "color": state.color,
+            "kind": self.kind,
+            "channels": self.channels(&state),
+        });
+        if self.transport == LightTransport::Web {
+            value["reported"] = self.reconciler.info();
+        }
+        self.io.locks().describe(&self.id, &mut value);
+        value
+    }
+
+    fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = vec![Capability::OnOff, Capability::Brightness];
+        match self.kind {
+            LightKind::Rgb => capabilities.push(Capability::Color),
+            LightKind::Rgbw => {
+                capabilities.extend(&[Capability::Color, Capability::ColorTemperature])
+            }
+            LightKind::TunableWhite => capabilities.push(Capability::ColorTemperature),
+        }
+        capabilities
+    }
+
+    fn power_level(&self) -> Option<f64> {
+        let state = self.state.read().unwrap();
+        Some(if state.is_on {
+            state.brightness as f64 / 100.0
+        } else {
+            0.0
+        })
+    }
+
+    fn update(&self, value: Value) -> Result<()> {
+        self.io.locks().check(&self.id)?;
+        let brightness = match value["brightness"].as_u64() {
+            Some(brightness) if brightness > 100 => {
+                return Err(Error::msg(format!("Invalid brightness: {}", brightness)));
+            }
+            brightness => brightness,
+        };
+        let color = match value.get("color") {
+            Some(color) => {
+                let color: LightColor = serde_json::from_value(color.clone())?;
+                color.validate(self.kind)?;
+                Some(color)
+            }
+            None => None,
+        };
+
+        {
+            let mut state = self.state.write().unwrap();
+            if let Some(is_on) = value["is_on"].as_bool() {
+                state.is_on = is_on;
+            }
+            if let Some(brightness) = brightness {
+                state.brightness = brightness as u8;
+            }
+            if let Some(color) = color {
+                state.color = color;
+            }
+        }
+        self.flush()
+    }
+
+    fn reconcile(&self) -> Result<()> {
+        match self.transport {
+            LightTransport::Web => self.reconciler.reconcile(&self.io, &self.id, self.cmd()),
+            LightTransport::Serial { .. } => self.flush(),
+        }
+    }
+}
+
+impl Flush for ColorLight {
+    fn flush(&self) -> Result<()> {
+        match self.transport {
+            LightTransport::Serial { p_id } => {
+                let state = *self.state.read().unwrap();
+                for (i, level) in self.channels(&state).into_iter().enumerate() {
+                    let port = p_id.checked_add(i as u8).ok_or_else(|| {
+                        Error::msg(format!("No serial port for channel {} of {}", i, self.id))
+                    })?;
+                    self.io.serial_write(Cmd::new(CHANNEL, port, level))?;
+                }
+                Ok(())
+            }
+            LightTransport::Web => self.io.send(&self.id, self.cmd()),
+        }
+    }
+}
+
+fn hsv_to_rgb(hue: u16, saturation: u8, value: u8) -> (u8, u8, u8) {
+    let hue = (hue % 360) as f32 / 60.0;
+    let value = value as f32 / 100.0;
+    let chroma = value * saturation as f32 / 100.0;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    let to_u8 = |val: f32| ((val + m) * 255.0).round() as u8;
+    (to_u8(r), to_u8(g), to_u8(b))
+}
+
+/// Approximation of the black body color by Tanner Helland.
+fn kelvin_to_rgb(kelvin: u16) -> (u8, u8, u8) {
+    let temp = kelvin as f64 / 100.0;
+    let r = if temp <= 66.0 {
+        255.0
+    } else {
+        329.698_727_446 * (temp - 60.0).powf(-0.133_204_759_2)
+    };
+    let g = if temp <= 66.0 {
+        99.470_802_586_1 * temp.ln() - 161.119_568_166_1
+    } else {
+        288.122_169_528_3 * (temp - 60.0).powf(-0.075_514_849_2)
+    };
+    let b = if temp >= 66.0 {
+        255.0
+    } else if temp <= 19.0 {
+        0.0
+    } else {
+        138.517_731_223_1 * (temp - 10.0).ln() - 305.044_792_730_7
+    };
+    let to_u8 = |val: f64| val.clamp(0.0, 255.0).round() as u8;
+    (to_u8(r), to_u8(g), to_u8(b))
+}
+
+/// Levels of the warm and the cold channels.
+fn white_mix(kelvin: u16) -> (u8, u8) {
+    let kelvin = kelvin.clamp(WARM_KELVIN, COLD_KELVIN);
+    let cold = (kelvin - WARM_KELVIN) as u32 * 255 / (COLD_KELVIN - WARM_KELVIN) as u32;
+    (255 - cold as u8, cold as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::light::{hsv_to_rgb, kelvin_to_rgb};
+    use crate::devices::{ColorLight, Control, LightKind, LightTransport};
+    use crate::io::{Cmd, Recorder, IO};
+    use crate::runtime::Runtime;
+
+    #[test]
+    fn test_serial_rgbw() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        let light = ColorLight::new(
+            &mut io,
+            "desk_light",
+            LightKind::Rgbw,
+            LightTransport::Serial { p_id: 0x08 },
+        );
+
+        light
+            .update(json!({"is_on": true, "brightness": 50, "color": {"rgb": [255, 128, 64]}}))
+            .unwrap();
+        assert_eq!(
+            recorder.serial(),
+            vec![
+                Cmd::new(0x03, 0x08, 95),
+                Cmd::new(0x03, 0x09, 32),
+                Cmd::new(0x03, 0x0A, 0),
+                Cmd::new(0x03, 0x0B, 32),
+            ]
+        );
+
+        assert!(light.update(json!({"brightness": 101})).is_err());
+        assert!(light
+            .update(json!({"color": {"hsv": [400, 0, 0]}}))
+            .is_err());
+        light
+            .update(json!({"color": {"hsv": [120, 100, 100]}}))
+            .unwrap();
+        assert_eq!(light.load()["channels"], json!([0, 127, 0, 0]));
+        assert_eq!(light.load()["color"], json!({"hsv": [120, 100, 100]}));
+    }
+
+    #[test]
+    fn test_web_tunable_white() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        let light = ColorLight::new(
+            &mut io,
+            "bedroom_light",
+            LightKind::TunableWhite,
+            LightTransport::Web,
+        );
+
+        light
+            .update(json!({"is_on": true, "color": {"kelvin": 2700}}))
+            .unwrap();
+        let cmd = recorder.last_sent("bedroom_light").unwrap();
+        assert_eq!(cmd.args, vec!["ON:255:0".to_owned()]);
+        assert_eq!(cmd.state["color"], json!({"kelvin": 2700}));
+
+        light.update(json!({"color": {"kelvin": 6500}})).unwrap();
+        assert_eq!(
+            recorder.last_sent("bedroom_light").unwrap().args,
+            vec!["ON:0:255".to_owned()]
+        );
+
+        assert!(light
+            .update(json!({"color": {"rgb": [255, 0, 0]}}))
+            .is_err());
+        assert_eq!(recorder.sent("bedroom_light").len(), 2);
+    }
+
+    #[test]
+    fn test_color_conversion() {
+        assert_eq!(hsv_to_rgb(0, 100, 100), (255, 0, 0));
+        assert_eq!(hsv_to_rgb(240, 100, 100), (0, 0, 255));
+        assert_eq!(hsv_to_rgb(60, 0, 50), (128, 128, 128));
+        assert_eq!(kelvin_to_rgb(6600), (255, 255, 255));
+        let (r, _, b) = kelvin_to_rgb(2700);
+        assert_eq!(r, 255);
+        assert!(b < 200);
+    }
+}
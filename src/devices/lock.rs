@@ -0,0 +1,250 @@
+use crate::storage::Storage;
+use anyhow::{Error, Result};
+use serde_json::Value;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error as StdError;
+use std::fmt::{Display, Error as FmtError, Formatter};
+use std::sync::{Arc, RwLock};
+
+const DEVICE_LOCKS: &str = "device_locks";
+
+thread_local! {
+    static SOURCE: Cell<Source> = const { Cell::new(Source::Automation) };
+}
+
+///
+/// Origin of a device change. Entry points run their actions on behalf of the source,
+/// everything else, e.g. background tasks, acts as the automation.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// Wall switches and other sensors.
+    Switch,
+    Api,
+    Homebridge,
+    Script,
+    /// Followers, animations, thermostats, the state restore.
+    Automation,
+}
+
+impl Source {
+    pub fn current() -> Source {
+        SOURCE.with(|source| source.get())
+    }
+
+    /// Runs the action on behalf of the source.
+    pub fn run<R, A: FnOnce() -> R>(self, action: A) -> R {
+        let _restore = RestoreSource(SOURCE.with(|source| source.replace(self)));
+        action()
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        let name = match self {
+            Source::Switch => "switch",
+            Source::Api => "api",
+            Source::Homebridge => "homebridge",
+            Source::Script => "script",
+            Source::Automation => "automation",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Puts the previous source back, even if the action panics.
+struct RestoreSource(Source);
+
+impl Drop for RestoreSource {
+    fn drop(&mut self) {
+        SOURCE.with(|source| source.set(self.0));
+    }
+}
+
+///
+/// Keeps a device in its current state: {"sources": ["switch", "script"], "reason": "child lock"}
+///
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceLock {
+    /// Sources the device is locked against, every source if empty.
+    #[serde(default)]
+    pub sources: Vec<Source>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl DeviceLock {
+    fn applies(&self, source: Source) -> bool {
+        self.sources.is_empty() || self.sources.contains(&source)
+    }
+}
+
+///
+/// Change of a locked device.
+///
+#[derive(Debug)]
+pub struct LockError {
+    id: String,
+    source: Source,
+    reason: Option<String>,
+}
+
+impl Display for LockError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "Device {} is locked for {}", self.id, self.source)?;
+        if let Some(reason) = &self.reason {
+            write!(f, ": {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl StdError for LockError {}
+
+///
+/// Locks of the devices, kept on disk. Every device checks them before it changes its state.
+///
+#[derive(Debug, Clone)]
+pub struct Locks {
+    locks: Arc<RwLock<HashMap<String, DeviceLock>>>,
+    storage: Storage,
+}
+
+impl Locks {
+    pub fn load(storage: Storage) -> Locks {
+        let locks = match storage.load::<HashMap<String, DeviceLock>>(DEVICE_LOCKS) {
+            Ok(locks) => locks.unwrap_or_default(),
+            Err(err) => {
+                error!("Failed to load device locks: {}", err);
+                HashMap::new()
+            }
+        };
+        if !locks.is_empty() {
+            info!("Loaded {} device locks", locks.len());
+        }
+        Locks {
+            locks: Arc::new(RwLock::new(locks)),
+            storage,
+        }
+    }
+
+    pub fn lock(&self, id: &str, lock: DeviceLock) -> Result<()> {
+        info!("Lock device {}: {:?}", id, lock);
+        let mut locks = self.locks.write().unwrap();
+        locks.insert(id.to_owned(), lock);
+        self.save(&locks)
+    }
+
+    pub fn unlock(&self, id: &str) -> Result<()> {
+        let mut locks = self.locks.write().unwrap();
+        match locks.remove(id) {
+            Some(_) => {
+                info!("Unlock device {}", id);
+                self.save(&locks)
+            }
+            None => Err(Error::msg(format!("Device {} is not locked", id))),
+        }
+    }
+
+    fn save(&self, locks: &HashMap<String, DeviceLock>) -> Result<()> {
+        let locks = locks.iter().collect::<BTreeMap<_, _>>();
+        self.storage.save(DEVICE_LOCKS, &locks)
+    }
+
+    /// Fails with LockError if the device is locked against the current source.
+    pub fn check(&self, id: &str) -> Result<()> {
+        let source = Source::current();
+        match self.locks.read().unwrap().get(id) {
+            Some(lock) if lock.applies(source) => Err(LockError {
+                id: id.to_owned(),
+                source,
+                reason: lock.reason.clone(),
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds the lock to the state of the locked device.
+    pub fn describe(&self, id: &str, state: &mut Value) {
+        if let Some(lock) = self.locks.read().unwrap().get(id) {
+            state["lock"] = json!(lock);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::{Control, DeviceLock, LockError, Locks, SerialDimmer, Source, Switch};
+    use crate::io::{Input, Recorder, IO};
+    use crate::runtime::Runtime;
+    use crate::storage::Storage;
+
+    #[test]
+    fn test_locks() {
+        let mut io = IO::with_output(&Runtime::new(1), Recorder::new());
+        let lamp = SerialDimmer::new(&mut io, "lamp", 0x01, 0, 100);
+        let io = io.freeze();
+        lamp.switch(true).unwrap();
+
+        let child_lock = DeviceLock {
+            sources: vec![Source::Switch],
+            reason: Some("child lock".to_owned()),
+        };
+        io.lock_device("lamp", child_lock.clone()).unwrap();
+        assert!(io.lock_device("sofa", DeviceLock::default()).is_err());
+        let err = Source::Switch.run(|| lamp.switch(false)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Device lamp is locked for switch: child lock"
+        );
+        assert!(err.downcast_ref::<LockError>().is_some());
+        assert!(lamp.is_on());
+        assert_eq!(lamp.load()["lock"], json!(child_lock));
+
+        // Other sources still control the lamp.
+        Source::Api
+            .run(|| io.update_device("lamp", json!({"brightness": 40})))
+            .unwrap();
+        assert_eq!(Source::current(), Source::Automation);
+
+        io.lock_device("lamp", DeviceLock::default()).unwrap();
+        assert!(io.update_device("lamp", json!({"is_on": false})).is_err());
+        assert_eq!(
+            lamp.load(),
+            json!({"is_on": true, "brightness": 40, "lock": {"sources": [], "reason": null}})
+        );
+
+        io.unlock_device("lamp").unwrap();
+        assert!(io.unlock_device("lamp").is_err());
+        lamp.switch(false).unwrap();
+        assert!(lamp.load().get("lock").is_none());
+    }
+
+    #[test]
+    fn test_persist_locks() {
+        let storage = Storage::temp();
+        let locks = Locks::load(storage.clone());
+        let lock = DeviceLock {
+            sources: vec![Source::Homebridge],
+            reason: None,
+        };
+        locks.lock("lamp", lock.clone()).unwrap();
+        locks.lock("fun", DeviceLock::default()).unwrap();
+        locks.unlock("fun").unwrap();
+
+        // Restart.
+        let locks = Locks::load(storage);
+        assert!(Source::Homebridge
+            .run(|| locks.check("lamp"))
+            .unwrap_err()
+            .downcast_ref::<LockError>()
+            .is_some());
+        locks.check("fun").unwrap();
+        let mut state = json!({});
+        locks.describe("lamp", &mut state);
+        assert_eq!(state["lock"], json!(lock));
+    }
+}
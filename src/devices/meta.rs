@@ -0,0 +1,86 @@
+use crate::devices::{Control, DeviceType};
+
+/// What a device can do, frontends pick the controls by it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    OnOff,
+    Brightness,
+    Color,
+    ColorTemperature,
+    /// Independent light channels, e.g. the spot and the led strip of a beam.
+    Channels,
+    Position,
+    Temperature,
+    Setpoint,
+}
+
+///
+/// Placement of a device in the home.
+///
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceMeta {
+    /// Room id, e.g. "kitchen".
+    #[serde(default)]
+    pub room: Option<String>,
+    /// Human readable name, the id if missing.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl DeviceMeta {
+    pub fn new(room: Option<&str>, name: &str) -> DeviceMeta {
+        DeviceMeta {
+            room: room.map(ToOwned::to_owned),
+            name: Some(name.to_owned()),
+        }
+    }
+}
+
+///
+/// Entry of the devices list: {id, name, room, type, capabilities}
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub name: String,
+    pub room: Option<String>,
+    #[serde(rename = "type")]
+    pub dev_type: DeviceType,
+    pub capabilities: Vec<Capability>,
+}
+
+impl DeviceInfo {
+    pub fn new(device: &dyn Control, meta: Option<&DeviceMeta>) -> DeviceInfo {
+        let meta = meta.cloned().unwrap_or_default();
+        DeviceInfo {
+            id: device.id().to_owned(),
+            name: meta.name.unwrap_or_else(|| device.id().to_owned()),
+            room: meta.room,
+            dev_type: device.dev_type(),
+            capabilities: device.capabilities(),
+        }
+    }
+}
+
+/// Query of the devices list, empty fields match every device.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DeviceFilter {
+    pub room: Option<String>,
+    #[serde(rename = "type")]
+    pub dev_type: Option<DeviceType>,
+}
+
+impl DeviceFilter {
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        let room = match &self.room {
+            Some(room) => info.room.as_ref() == Some(room),
+            None => true,
+        };
+        let dev_type = match self.dev_type {
+            Some(dev_type) => info.dev_type == dev_type,
+            None => true,
+        };
+        room && dev_type
+    }
+}
@@ -1,8 +1,39 @@
+mod animation;
+mod config;
+mod cover;
+mod dimming;
+mod energy;
+mod follower;
+mod group;
+mod light;
+mod lock;
+mod meta;
+mod presets;
+mod schema;
 mod serial;
+mod store;
+mod thermostat;
+mod valve;
 mod web;
 
+pub use self::animation::{Animation, Animator};
+pub use self::config::load_devices;
+pub use self::cover::{Calibration, Cover, CoverTransport};
+pub use self::dimming::{DimmerCalibration, DimmerCalibrations};
+pub use self::energy::{EnergyMeter, EnergyReport};
+pub use self::follower::{Follow, Followers};
+pub use self::group::{Aggregate, DeviceGroup, Member};
+pub use self::light::{ColorLight, LightKind, LightTransport};
+pub use self::lock::{DeviceLock, LockError, Locks, Source};
+pub use self::meta::{Capability, DeviceFilter, DeviceInfo, DeviceMeta};
+pub use self::presets::{LedPresets, LED_PRESETS};
+pub use self::schema::{Schema, SchemaError};
 pub use self::serial::{SerialDimmer, SerialSwitch};
+pub use self::store::{PowerOnPolicy, StateStore};
+pub use self::thermostat::{Thermostat, ThermostatSettings};
+pub use self::valve::{SafetyError, Valve, ValveSettings};
 pub use self::web::{LedMode, LedState, WebBeam, WebSwitch};
+use crate::runtime::{Background, Runtime};
 use anyhow::Result;
 use serde_json::Value;
 use std::fmt::Debug;
@@ -24,25 +55,93 @@ pub trait Control: Send + Sync + Debug + Flush {
     fn dev_type(&self) -> DeviceType;
     fn load(&self) -> Value;
     fn update(&self, state: Value) -> Result<()>;
+
+    /// Checks a state received through the API before the update, e.g. the confirmation of a risky change.
+    fn guard(&self, _state: &Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Vec<Capability> {
+        self.dev_type().capabilities()
+    }
+
+    /// Share of the nominal power drawn now, 0..1. None if the device has no consumption of its own.
+    fn power_level(&self) -> Option<f64> {
+        None
+    }
+
+    /// Brings the device to the desired state. Devices without feedback just re-send it.
+    fn reconcile(&self) -> Result<()> {
+        self.flush()
+    }
+
+    /// Starts the control loop of the device, if it has one. The loop runs until the handle is dropped.
+    fn start(&self, _rt: &Runtime) -> Option<Background> {
+        None
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DeviceType {
     SerialSwitch,
     SerialDimmer,
     WebBeam,
     WebSwitch,
+    ColorLight,
+    Cover,
+    Thermostat,
+    Valve,
+    Group,
 }
 
-#[inline]
-fn map(x: u32, in_min: u32, in_max: u32, out_min: u32, out_max: u32) -> u32 {
-    (x - in_min) * (out_max - out_min) / (in_max - in_min) + out_min
-}
+impl DeviceType {
+    pub fn all() -> Vec<DeviceType> {
+        vec![
+            DeviceType::SerialSwitch,
+            DeviceType::SerialDimmer,
+            DeviceType::WebBeam,
+            DeviceType::WebSwitch,
+            DeviceType::ColorLight,
+            DeviceType::Cover,
+            DeviceType::Thermostat,
+            DeviceType::Valve,
+            DeviceType::Group,
+        ]
+    }
+
+    pub fn capabilities(&self) -> Vec<Capability> {
+        match self {
+            DeviceType::SerialSwitch
+            | DeviceType::WebSwitch
+            | DeviceType::Valve
+            | DeviceType::Group => {
+                vec![Capability::OnOff]
+            }
+            DeviceType::SerialDimmer => vec![Capability::OnOff, Capability::Brightness],
+            DeviceType::WebBeam => vec![Capability::OnOff, Capability::Channels, Capability::Color],
+            DeviceType::ColorLight => vec![
+                Capability::OnOff,
+                Capability::Brightness,
+                Capability::Color,
+                Capability::ColorTemperature,
+            ],
+            DeviceType::Cover => vec![Capability::Position],
+            DeviceType::Thermostat => vec![Capability::Temperature, Capability::Setpoint],
+        }
+    }
 
-#[inline]
-fn invert_and_map(val: u8) -> u8 {
-    if val == 0 {
-        255
-    } else {
-        map(100 - val as u32, 0, 100, 26, 229) as u8
+    /// State accepted by `Control::update` of the devices of the type.
+    pub fn schema(&self) -> Schema {
+        match self {
+            DeviceType::SerialSwitch => SerialSwitch::schema(),
+            DeviceType::SerialDimmer => SerialDimmer::schema(),
+            DeviceType::WebBeam => WebBeam::schema(),
+            DeviceType::WebSwitch => WebSwitch::schema(),
+            DeviceType::ColorLight => ColorLight::schema(),
+            DeviceType::Cover => Cover::schema(),
+            DeviceType::Thermostat => Thermostat::schema(),
+            DeviceType::Valve => Valve::schema(),
+            DeviceType::Group => DeviceGroup::schema(),
+        }
     }
 }
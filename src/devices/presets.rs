@@ -0,0 +1,114 @@
+use crate::devices::web::Noise;
+use crate::devices::LedMode;
+use crate::home::configuration::{ConfigValue, Configuration, OnUpdate};
+use crate::storage::Storage;
+use anyhow::{Error, Result};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+/// Config key and storage name of the presets: {name: LedMode}
+pub const LED_PRESETS: &str = "led_presets";
+
+///
+/// Named LED effects, editable through the configuration and kept on disk.
+///
+#[derive(Debug, Clone)]
+pub struct LedPresets {
+    storage: Storage,
+    presets: Arc<RwLock<BTreeMap<String, LedMode>>>,
+}
+
+impl LedPresets {
+    pub fn new(storage: Storage) -> LedPresets {
+        let presets = match storage.load::<BTreeMap<String, LedMode>>(LED_PRESETS) {
+            Ok(Some(presets)) => presets,
+            Ok(None) => LedPresets::builtin(),
+            Err(err) => {
+                error!("Failed to load led presets: {}", err);
+                LedPresets::builtin()
+            }
+        };
+        LedPresets {
+            storage,
+            presets: Arc::new(RwLock::new(presets)),
+        }
+    }
+
+    fn builtin() -> BTreeMap<String, LedMode> {
+        let mut presets = BTreeMap::new();
+        presets.insert("noise".to_owned(), LedMode::Noise(Noise::default()));
+        presets.insert("warm_noise".to_owned(), LedMode::Noise(Noise::warm()));
+        presets.insert(
+            "spectrum_noise".to_owned(),
+            LedMode::Noise(Noise::spectrum()),
+        );
+        presets.insert("fire".to_owned(), LedMode::Fire((120, 255)));
+        presets.insert("sunrise".to_owned(), LedMode::Sunrise(30));
+        presets
+    }
+
+    pub fn register(&self, config: &Configuration) -> Result<()> {
+        let presets = self.presets.read().unwrap().clone();
+        config.add(LED_PRESETS, ConfigValue::new(presets, self.clone())?);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<LedMode> {
+        self.presets
+            .read()
+            .unwrap()
+            .get(name)
+            .copied()
+            .ok_or_else(|| Error::msg(format!("Unknown led preset: {}", name)))
+    }
+}
+
+impl OnUpdate for LedPresets {
+    fn on_update(&self, value: Value) -> Result<(), Error> {
+        if let Some(presets) = value.as_object() {
+            for (name, mode) in presets {
+                LedMode::schema()
+                    .validate(mode)
+                    .map_err(|err| Error::msg(format!("Led preset {}: {}", name, err)))?;
+            }
+        }
+        let presets: BTreeMap<String, LedMode> = serde_json::from_value(value)?;
+        self.storage.save(LED_PRESETS, &presets)?;
+        info!("Update led presets: {:?}", presets.keys());
+        *self.presets.write().unwrap() = presets;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::{LedPresets, LED_PRESETS};
+    use crate::home::configuration::Configuration;
+    use crate::storage::Storage;
+
+    #[test]
+    fn test_presets() {
+        let storage = Storage::temp();
+        let config = Configuration::default();
+        let presets = LedPresets::new(storage.clone());
+        presets.register(&config).unwrap();
+        assert!(presets.get("warm_noise").is_ok());
+
+        let mut value = config.get_value(LED_PRESETS).unwrap();
+        value["ocean"] = json!({"Gradient": [[0, 0, 255], [0, 255, 128]]});
+        config.update(LED_PRESETS, value.clone()).unwrap();
+        assert!(presets.get("ocean").is_ok());
+
+        value["dawn"] = json!({"Sunrise": 0});
+        let err = config.update(LED_PRESETS, value).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Led preset dawn: Invalid Sunrise: expected integer 1..255, found 0"
+        );
+
+        let restarted = LedPresets::new(storage);
+        assert!(restarted.get("ocean").is_ok());
+        assert!(restarted.get("dawn").is_err());
+    }
+}
@@ -0,0 +1,322 @@
+use serde_json::{Map, Value};
+use std::error::Error as StdError;
+use std::fmt::{Display, Error as FmtError, Formatter};
+
+///
+/// Typed description of the state accepted by `Control::update`.
+/// Published as JSON Schema and checked before every update.
+///
+#[derive(Debug, Clone)]
+pub enum Schema {
+    Any,
+    Bool,
+    Integer {
+        min: i64,
+        max: i64,
+    },
+    Number {
+        min: f64,
+        max: f64,
+    },
+    String,
+    /// One of the given strings.
+    Enum(Vec<&'static str>),
+    Array(Box<Schema>),
+    /// Fixed size array.
+    Tuple(Vec<Schema>),
+    /// Object without unknown properties.
+    Object {
+        properties: Vec<(&'static str, Schema)>,
+        required: Vec<&'static str>,
+    },
+    OneOf(Vec<Schema>),
+}
+
+impl Schema {
+    pub fn integer(min: i64, max: i64) -> Schema {
+        Schema::Integer { min, max }
+    }
+
+    pub fn byte() -> Schema {
+        Schema::integer(0, 255)
+    }
+
+    pub fn percent() -> Schema {
+        Schema::integer(0, 100)
+    }
+
+    /// Object with optional properties.
+    pub fn object(properties: Vec<(&'static str, Schema)>) -> Schema {
+        Schema::Object {
+            properties,
+            required: vec![],
+        }
+    }
+
+    /// Object with required properties only.
+    pub fn record(properties: Vec<(&'static str, Schema)>) -> Schema {
+        let required = properties.iter().map(|(name, _)| *name).collect();
+        Schema::Object {
+            properties,
+            required,
+        }
+    }
+
+    /// Externally tagged enum variant: {"name": value}.
+    pub fn variant(name: &'static str, value: Schema) -> Schema {
+        Schema::record(vec![(name, value)])
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            Schema::Any => json!({}),
+            Schema::Bool => json!({"type": "boolean"}),
+            Schema::Integer { min, max } => {
+                json!({"type": "integer", "minimum": min, "maximum": max})
+            }
+            Schema::Number { min, max } => {
+                json!({"type": "number", "minimum": min, "maximum": max})
+            }
+            Schema::String => json!({"type": "string"}),
+            Schema::Enum(values) => json!({"type": "string", "enum": values}),
+            Schema::Array(items) => json!({"type": "array", "items": items.to_json()}),
+            Schema::Tuple(items) => json!({
+                "type": "array",
+                "items": items.iter().map(Schema::to_json).collect::<Vec<_>>(),
+                "minItems": items.len(),
+                "maxItems": items.len(),
+            }),
+            Schema::Object {
+                properties,
+                required,
+            } => {
+                let properties = properties
+                    .iter()
+                    .map(|(name, schema)| ((*name).to_owned(), schema.to_json()))
+                    .collect::<Map<_, _>>();
+                json!({
+                    "type": "object",
+                    "properties": properties,
+                    "required": required,
+                    "additionalProperties": false,
+                })
+            }
+            Schema::OneOf(schemas) => {
+                json!({"oneOf": schemas.iter().map(Schema::to_json).collect::<Vec<_>>()})
+            }
+        }
+    }
+
+    pub fn validate(&self, value: &Value) -> Result<(), SchemaError> {
+        self.check(value, "")
+    }
+
+    fn check(&self, value: &Value, path: &str) -> Result<(), SchemaError> {
+        let invalid = |expected: String| {
+            Err(SchemaError {
+                path: path.to_owned(),
+                reason: format!("expected {}, found {}", expected, value),
+            })
+        };
+
+        match self {
+            Schema::Any => Ok(()),
+            Schema::Bool => match value {
+                Value::Bool(_) => Ok(()),
+                _ => invalid("boolean".to_owned()),
+            },
+            Schema::Integer { min, max } => match value.as_i64() {
+                Some(val) if val >= *min && val <= *max => Ok(()),
+                _ => invalid(format!("integer {}..{}", min, max)),
+            },
+            Schema::Number { min, max } => match value.as_f64() {
+                Some(val) if val >= *min && val <= *max => Ok(()),
+                _ => invalid(format!("number {}..{}", min, max)),
+            },
+            Schema::String => match value {
+                Value::String(_) => Ok(()),
+                _ => invalid("string".to_owned()),
+            },
+            Schema::Enum(values) => match value.as_str() {
+                Some(val) if values.contains(&val) => Ok(()),
+                _ => invalid(format!("one of {:?}", values)),
+            },
+            Schema::Array(items) => match value {
+                Value::Array(values) => values
+                    .iter()
+                    .enumerate()
+                    .try_for_each(|(i, val)| items.check(val, &format!("{}[{}]", path, i))),
+                _ => invalid("array".to_owned()),
+            },
+            Schema::Tuple(items) => match value {
+                Value::Array(values) if values.len() == items.len() => values
+                    .iter()
+                    .zip(items)
+                    .enumerate()
+                    .try_for_each(|(i, (val, item))| item.check(val, &format!("{}[{}]", path, i))),
+                _ => invalid(format!("array of {} items", items.len())),
+            },
+            Schema::Object {
+                properties,
+                required,
+            } => {
+                let values = match value {
+                    Value::Object(values) => values,
+                    _ => return invalid("object".to_owned()),
+                };
+                for name in required {
+                    if !values.contains_key(*name) {
+                        return invalid(format!("object with '{}'", name));
+                    }
+                }
+                for (name, val) in values {
+                    let field = if path.is_empty() {
+                        name.to_owned()
+                    } else {
+                        format!("{}.{}", path, name)
+                    };
+                    match properties.iter().find(|(prop, _)| prop == name) {
+                        Some((_, schema)) => schema.check(val, &field)?,
+                        None => {
+                            return Err(SchemaError {
+                                path: field,
+                                reason: "unknown property".to_owned(),
+                            })
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Schema::OneOf(schemas) => {
+                // The closest error is the one found deeper in the value.
+                let mut closest: Option<SchemaError> = None;
+                for res in schemas.iter().map(|schema| schema.check(value, path)) {
+                    match res {
+                        Ok(()) => return Ok(()),
+                        Err(err) => {
+                            if closest
+                                .as_ref()
+                                .is_none_or(|closest| err.path.len() > closest.path.len())
+                            {
+                                closest = Some(err);
+                            }
+                        }
+                    }
+                }
+                match closest {
+                    Some(err) if err.path != path => Err(err),
+                    _ => invalid(format!("one of {} variants", schemas.len())),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SchemaError {
+    path: String,
+    reason: String,
+}
+
+impl Display for SchemaError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        let path = if self.path.is_empty() {
+            "state"
+        } else {
+            self.path.as_str()
+        };
+        write!(f, "Invalid {}: {}", path, self.reason)
+    }
+}
+
+impl StdError for SchemaError {}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::{DeviceType, Schema, SchemaError, SerialDimmer, WebBeam};
+    use crate::io::{Input, Recorder, IO};
+    use crate::runtime::Runtime;
+
+    #[test]
+    fn test_validate() {
+        let schema = Schema::object(vec![
+            ("is_on", Schema::Bool),
+            ("brightness", Schema::percent()),
+            (
+                "mode",
+                Schema::OneOf(vec![
+                    Schema::variant("Color", Schema::Tuple(vec![Schema::byte(); 3])),
+                    Schema::variant("Rainbow", Schema::Tuple(vec![Schema::byte(); 2])),
+                ]),
+            ),
+        ]);
+
+        assert!(schema.validate(&json!({})).is_ok());
+        assert!(schema
+            .validate(&json!({"is_on": true, "mode": {"Color": [1, 2, 3]}}))
+            .is_ok());
+        assert_eq!(
+            schema
+                .validate(&json!({"brightness": 300}))
+                .unwrap_err()
+                .to_string(),
+            "Invalid brightness: expected integer 0..100, found 300"
+        );
+        assert_eq!(
+            schema
+                .validate(&json!({"mode": {"Color": [1, 2, 256]}}))
+                .unwrap_err()
+                .to_string(),
+            "Invalid mode.Color[2]: expected integer 0..255, found 256"
+        );
+        assert_eq!(
+            schema
+                .validate(&json!({"power": 1}))
+                .unwrap_err()
+                .to_string(),
+            "Invalid power: unknown property"
+        );
+        assert!(schema.validate(&json!({"mode": "Color"})).is_err());
+        assert_eq!(
+            schema.to_json()["properties"]["brightness"],
+            json!({"type": "integer", "minimum": 0, "maximum": 100})
+        );
+    }
+
+    #[test]
+    fn test_device_update() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        SerialDimmer::new(&mut io, "lamp", 0x01, 0, 100);
+        WebBeam::new(&mut io, "beam");
+        let io = io.freeze();
+
+        let err = io
+            .update_device("lamp", json!({"brightness": 300}))
+            .unwrap_err();
+        assert!(err.downcast_ref::<SchemaError>().is_some());
+        assert!(recorder.serial().is_empty());
+        io.update_device("lamp", json!({"is_on": true, "brightness": 30}))
+            .unwrap();
+
+        let err = io
+            .update_device(
+                "beam",
+                json!({"channel_1": {"led_state": {"mode": {"Sparkle": [1, 2]}}}}),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid channel_1.led_state.mode: expected one of 8 variants, found {\"Sparkle\":[1,2]}"
+        );
+        io.update_device(
+            "beam",
+            json!({"channel_1": {"is_on": true, "led_state": {"mode": {"Rainbow": [10, 200]}}}}),
+        )
+        .unwrap();
+
+        for dev_type in DeviceType::all() {
+            assert_eq!(dev_type.schema().to_json()["type"], json!("object"));
+        }
+    }
+}
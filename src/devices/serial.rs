@@ -1,9 +1,18 @@
-use crate::devices::{invert_and_map, map, Control, DeviceType, Flush, Switch};
+use crate::devices::{Control, DeviceType, DimmerCalibration, Flush, Schema, Switch};
 use crate::io::{Cmd, IOMut, Output, IO};
-use anyhow::Result;
+use crate::log_error;
+use crate::runtime::Background;
+use anyhow::{Error, Result};
 use serde_json::Value;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Interval between the brightness steps of a fade.
+const FADE_STEP: Duration = Duration::from_millis(50);
+const MAX_TRANSITION_MS: u64 = 60 * 60 * 1000;
+/// Brightness is a percent of the calibrated range.
+const MAX_BRIGHTNESS: u8 = 100;
 
 #[derive(Debug, Clone)]
 pub struct SerialSwitch {
@@ -24,6 +33,10 @@ impl SerialSwitch {
         io.reg_device(Box::new(dev.clone()));
         dev
     }
+
+    pub(super) fn schema() -> Schema {
+        Schema::object(vec![("is_on", Schema::Bool)])
+    }
 }
 
 impl Switch for SerialSwitch {
@@ -32,6 +45,7 @@ impl Switch for SerialSwitch {
     }
 
     fn switch(&self, is_on: bool) -> Result<()> {
+        self.io.locks().check(&self.id)?;
         self.is_on.store(is_on, Ordering::SeqCst);
         self.flush()
     }
@@ -50,9 +64,15 @@ impl Control for SerialSwitch {
     }
 
     fn load(&self) -> Value {
-        json!({
+        let mut state = json!({
             "is_on": self.is_on.load(Ordering::SeqCst)
-        })
+        });
+        self.io.locks().describe(&self.id, &mut state);
+        state
+    }
+
+    fn power_level(&self) -> Option<f64> {
+        Some(if self.is_on() { 1.0 } else { 0.0 })
     }
 
     fn update(&self, state: Value) -> Result<()> {
@@ -80,9 +100,27 @@ pub struct SerialDimmer {
     id: Arc<String>,
     p_id: u8,
     io: IO,
-    min_value: u8,
-    max_value: u8,
+    calibration: Arc<RwLock<DimmerCalibration>>,
     state: Arc<RwLock<DimmerState>>,
+    fade: Arc<Mutex<Fade>>,
+}
+
+///
+/// Running fade. Every new command bumps the generation, so steps of the interrupted fade are ignored.
+///
+#[derive(Debug, Default)]
+struct Fade {
+    generation: u64,
+    bg: Option<Background>,
+}
+
+impl Fade {
+    fn cancel(&mut self) {
+        self.generation += 1;
+        if let Some(bg) = self.bg.take() {
+            bg.stop();
+        }
+    }
 }
 
 impl SerialDimmer {
@@ -91,20 +129,114 @@ impl SerialDimmer {
             id: Arc::new(id.to_owned()),
             io: io.shared(),
             p_id,
-            min_value,
-            max_value,
+            calibration: Arc::new(RwLock::new(DimmerCalibration::linear(min_value, max_value))),
             state: Arc::new(RwLock::new(DimmerState {
                 is_on: false,
                 brightness: 100,
+                level: 0.0,
             })),
+            fade: Default::default(),
         };
         io.reg_device(Box::new(dev.clone()));
 
         dev
     }
 
-    pub fn set_power(&self, power: u8) {
+    pub(super) fn schema() -> Schema {
+        Schema::object(vec![
+            ("is_on", Schema::Bool),
+            ("brightness", Schema::percent()),
+            (
+                "transition_ms",
+                Schema::integer(0, MAX_TRANSITION_MS as i64),
+            ),
+        ])
+    }
+
+    pub fn calibration(&self) -> DimmerCalibration {
+        self.calibration.read().unwrap().clone()
+    }
+
+    /// Replaces the calibration and rewrites the output.
+    pub fn calibrate(&self, calibration: DimmerCalibration) -> Result<()> {
+        calibration.validate()?;
+        *self.calibration.write().unwrap() = calibration;
+        self.flush()
+    }
+
+    pub fn set_power(&self, power: u8) -> Result<()> {
+        self.io.locks().check(&self.id)?;
+        if power > MAX_BRIGHTNESS {
+            return Err(Error::msg(format!("Invalid brightness: {}", power)));
+        }
         self.state.write().unwrap().brightness = power;
+        Ok(())
+    }
+
+    /// Ramps the light from the current level to the desired state.
+    pub fn fade(&self, transition: Duration) -> Result<()> {
+        let mut fade = self.fade.lock().unwrap();
+        fade.cancel();
+
+        let (from, to) = {
+            let state = self.state.read().unwrap();
+            (state.level, state.target_level())
+        };
+        if transition < FADE_STEP || (from - to).abs() < f64::EPSILON {
+            return self.write();
+        }
+
+        let generation = fade.generation;
+        let started = Instant::now();
+        let dimmer = self.clone();
+        fade.bg = Some(Background::every(
+            self.io.runtime(),
+            FADE_STEP,
+            true,
+            move || dimmer.fade_step(generation, from, to, started, transition),
+        ));
+        Ok(())
+    }
+
+    fn fade_step(
+        &self,
+        generation: u64,
+        from: f64,
+        to: f64,
+        started: Instant,
+        transition: Duration,
+    ) {
+        let mut fade = self.fade.lock().unwrap();
+        if fade.generation != generation {
+            return;
+        }
+
+        let progress = started.elapsed().as_secs_f64() / transition.as_secs_f64();
+        if progress >= 1.0 {
+            fade.cancel();
+            log_error!(self.write());
+        } else {
+            let level = from + (to - from) * progress;
+            self.state.write().unwrap().level = level;
+            log_error!(self.io.serial_write(Cmd::new(
+                0x01,
+                self.p_id,
+                self.calibration.read().unwrap().arg(level > 0.0, level)
+            )));
+        }
+    }
+
+    /// Writes the desired state at once.
+    fn write(&self) -> Result<()> {
+        let arg = {
+            let mut state = self.state.write().unwrap();
+            state.level = state.target_level();
+            self.calibration
+                .read()
+                .unwrap()
+                .arg(state.is_on, state.brightness as f64)
+        };
+        self.io.serial_write(Cmd::new(0x01, self.p_id, arg))
     }
 }
 
@@ -114,6 +246,7 @@ impl Switch for SerialDimmer {
     }
 
     fn switch(&self, is_on: bool) -> Result<()> {
+        self.io.locks().check(&self.id)?;
         {
             self.state.write().unwrap().is_on = is_on;
         }
@@ -124,6 +257,7 @@ impl Switch for SerialDimmer {
 
 ///
 /// State {is_on, brightness}
+/// Update {is_on, brightness, transition_ms} - with the transition the light fades to the state.
 ///
 impl Control for SerialDimmer {
     fn id(&self) -> &str {
@@ -137,22 +271,46 @@ impl Control for SerialDimmer {
     fn load(&self) -> Value {
         let state = self.state.read().unwrap();
 
-        json!({
+        let mut value = json!({
             "is_on": state.is_on,
             "brightness": state.brightness
-        })
+        });
+        self.io.locks().describe(&self.id, &mut value);
+        value
+    }
+
+    fn power_level(&self) -> Option<f64> {
+        Some(self.state.read().unwrap().level / 100.0)
     }
 
     fn update(&self, val: Value) -> Result<()> {
-        if let Some(brightness) = val["brightness"].as_u64() {
+        self.io.locks().check(&self.id)?;
+        let transition = match val["transition_ms"].as_u64() {
+            Some(ms) if ms > MAX_TRANSITION_MS => {
+                return Err(Error::msg(format!("Invalid transition: {} ms", ms)));
+            }
+            ms => ms.map(Duration::from_millis),
+        };
+        let brightness = match val["brightness"].as_u64() {
+            Some(brightness) if brightness > MAX_BRIGHTNESS as u64 => {
+                return Err(Error::msg(format!("Invalid brightness: {}", brightness)));
+            }
+            brightness => brightness.map(|brightness| brightness as u8),
+        };
+
+        {
             let mut state = self.state.write().unwrap();
-            state.brightness = brightness as u8;
+            if let Some(brightness) = brightness {
+                state.brightness = brightness;
+            }
+            if let Some(is_on) = val["is_on"].as_bool() {
+                state.is_on = is_on;
+            }
         }
 
-        if let Some(is_on) = &val["is_on"].as_bool() {
-            self.switch(is_on.to_owned())
-        } else {
-            self.flush()
+        match transition {
+            Some(transition) => self.fade(transition),
+            None => self.flush(),
         }
     }
 }
@@ -161,24 +319,68 @@ impl Control for SerialDimmer {
 struct DimmerState {
     is_on: bool,
     brightness: u8,
+    /// Current output brightness, 0 - off. Differs from the desired state during a fade.
+    level: f64,
+}
+
+impl DimmerState {
+    fn target_level(&self) -> f64 {
+        if self.is_on {
+            self.brightness as f64
+        } else {
+            0.0
+        }
+    }
 }
 
 impl Flush for SerialDimmer {
     fn flush(&self) -> Result<()> {
-        let state = self.state.read().unwrap();
+        self.fade.lock().unwrap().cancel();
+        self.write()
+    }
+}
 
-        let arg = if state.is_on {
-            invert_and_map(map(
-                state.brightness as u32,
-                0,
-                100,
-                self.min_value as u32,
-                self.max_value as u32,
-            ) as u8)
-        } else {
-            255
-        };
+#[cfg(test)]
+mod test {
+    use crate::devices::{Control, SerialDimmer, Switch};
+    use crate::io::{Cmd, Recorder, IO};
+    use crate::runtime::Runtime;
+    use std::thread;
+    use std::time::Duration;
 
-        self.io.serial_write(Cmd::new(0x01, self.p_id, arg))
+    #[test]
+    fn test_fade() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(2), recorder.clone());
+        let lamp = SerialDimmer::new(&mut io, "lamp", 0x01, 0, 100);
+
+        lamp.update(json!({"is_on": true, "brightness": 100, "transition_ms": 500}))
+            .unwrap();
+        assert!(recorder.serial().is_empty());
+        thread::sleep(Duration::from_millis(800));
+        let cmds = recorder.serial();
+        assert!(cmds.len() > 3, "{:?}", cmds);
+        let args = cmds.iter().map(|cmd| cmd.args()).collect::<Vec<_>>();
+        assert!(args.windows(2).all(|pair| pair[0] >= pair[1]), "{:?}", args);
+        assert_eq!(cmds.last(), Some(&Cmd::new(0x01, 0x01, 26)));
+
+        // A new command interrupts the fade.
+        recorder.clear();
+        lamp.update(json!({"is_on": false, "transition_ms": 10000}))
+            .unwrap();
+        thread::sleep(Duration::from_millis(200));
+        lamp.switch(true).unwrap();
+        let count = recorder.serial().len();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(recorder.serial().len(), count);
+        assert_eq!(recorder.serial().last(), Some(&Cmd::new(0x01, 0x01, 26)));
+        assert_eq!(lamp.load(), json!({"is_on": true, "brightness": 100}));
+
+        // Scripts and restore bypass the schema.
+        assert!(lamp
+            .update(json!({"is_on": false, "brightness": 300}))
+            .is_err());
+        assert!(lamp.set_power(101).is_err());
+        assert_eq!(lamp.load(), json!({"is_on": true, "brightness": 100}));
     }
 }
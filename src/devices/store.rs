@@ -0,0 +1,314 @@
+use crate::devices::DeviceType;
+use crate::home::configuration::{ConfigValue, Configuration, OnUpdate};
+use crate::io::IO;
+use crate::log_error;
+use crate::runtime::Background;
+use crate::storage::Storage;
+use anyhow::{Error, Result};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+const DEVICE_STATE: &str = "device_state";
+/// Config key and storage name of the policies: {device_id: policy}, replaces the policies of the home.
+pub const POWER_ON: &str = "power_on";
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+/// States are saved only after they didn't change for this time.
+const DEBOUNCE: Duration = Duration::from_secs(3);
+/// Runtime fields of the device state which are not restored, locks are kept by `Locks`.
+const VOLATILE: [&str; 2] = ["reported", "lock"];
+/// Restored fields of a cover: a motion saved before the restart must not start the motor.
+const COVER_RESTORED: [&str; 2] = ["position", "calibration"];
+
+///
+/// State of the device after a restart: "restore" | "off" | {"default": {..state}}.
+///
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerOnPolicy {
+    #[default]
+    Restore,
+    Off,
+    Default(Value),
+}
+
+#[derive(Debug, Default)]
+struct Snapshot {
+    saved: Map<String, Value>,
+    current: Map<String, Value>,
+    changed: Option<Instant>,
+}
+
+///
+/// Keeps the state of every device on disk and restores it at startup.
+///
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    io: IO,
+    storage: Storage,
+    policies: Arc<RwLock<HashMap<String, PowerOnPolicy>>>,
+    snapshot: Arc<Mutex<Snapshot>>,
+}
+
+impl StateStore {
+    /// Policies saved on disk or the defaults.
+    pub fn new(io: &IO, storage: Storage, defaults: HashMap<String, PowerOnPolicy>) -> StateStore {
+        let store = StateStore {
+            io: io.clone(),
+            storage,
+            policies: Default::default(),
+            snapshot: Default::default(),
+        };
+        let saved = match store
+            .storage
+            .load::<HashMap<String, PowerOnPolicy>>(POWER_ON)
+        {
+            Ok(saved) => saved,
+            Err(err) => {
+                error!("Failed to load power on policies: {}", err);
+                None
+            }
+        };
+        if let Err(err) = store.apply(saved.unwrap_or_else(|| defaults.clone())) {
+            error!("Invalid power on policies: {}", err);
+            log_error!(store.apply(defaults));
+        }
+        store
+    }
+
+    pub fn register(&self, config: &Configuration) -> Result<()> {
+        config.add(POWER_ON, ConfigValue::new(self.policies(), self.clone())?);
+        Ok(())
+    }
+
+    pub fn policies(&self) -> HashMap<String, PowerOnPolicy> {
+        self.policies.read().unwrap().clone()
+    }
+
+    fn apply(&self, policies: HashMap<String, PowerOnPolicy>) -> Result<()> {
+        let devices = self.io.device_holder().devices();
+        for id in policies.keys() {
+            match devices.get(id) {
+                Some(device) if device.dev_type() != DeviceType::Group => {}
+                _ => return Err(Error::msg(format!("Unknown device: {}", id))),
+            }
+        }
+        *self.policies.write().unwrap() = policies;
+        Ok(())
+    }
+
+    /// Applies the power on policy of every device.
+    pub fn restore(&self) {
+        let saved = match self.storage.load::<Map<String, Value>>(DEVICE_STATE) {
+            Ok(saved) => saved.unwrap_or_default(),
+            Err(err) => {
+                error!("Failed to load device state: {}", err);
+                Map::new()
+            }
+        };
+
+        for (id, device) in self.io.device_holder().devices() {
+            if let DeviceType::Group = device.dev_type() {
+                continue;
+            }
+
+            let policy = self.policies.read().unwrap().get(id).cloned();
+            let state = match policy.unwrap_or_default() {
+                PowerOnPolicy::Restore => saved
+                    .get(id)
+                    .map(|state| restorable(device.dev_type(), state)),
+                PowerOnPolicy::Off => Some(json!({"is_on": false})),
+                PowerOnPolicy::Default(state) => Some(state),
+            };
+            if let Some(state) = state {
+                debug!("Restore device {}: {}", id, state);
+                if let Err(err) = device.update(state) {
+                    warn!("Failed to restore device {}: {}", id, err);
+                }
+            }
+        }
+
+        let mut snapshot = self.snapshot.lock().unwrap();
+        snapshot.saved = saved;
+        snapshot.current = snapshot.saved.clone();
+    }
+
+    /// Saves changed states in the background.
+    pub fn start(&self) -> Background {
+        let store = self.clone();
+        Background::every(self.io.runtime(), SNAPSHOT_INTERVAL, true, move || {
+            log_error!(store.snapshot(Instant::now()));
+        })
+    }
+
+    fn snapshot(&self, now: Instant) -> Result<()> {
+        let mut states = Map::new();
+        for (id, device) in self.io.device_holder().devices() {
+            if let DeviceType::Group = device.dev_type() {
+                continue;
+            }
+            let mut state = device.load();
+            if let Some(state) = state.as_object_mut() {
+                for key in VOLATILE.iter() {
+                    state.remove(*key);
+                }
+            }
+            states.insert(id.to_owned(), state);
+        }
+
+        let mut snapshot = self.snapshot.lock().unwrap();
+        if states != snapshot.current {
+            snapshot.current = states;
+            snapshot.changed = Some(now);
+        }
+
+        match snapshot.changed {
+            Some(changed) if now.duration_since(changed) >= DEBOUNCE => {
+                self.storage.save(DEVICE_STATE, &snapshot.current)?;
+                snapshot.saved = snapshot.current.clone();
+                snapshot.changed = None;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Part of the saved state which is safe to replay at startup.
+fn restorable(dev_type: DeviceType, state: &Value) -> Value {
+    match (dev_type, state.as_object()) {
+        (DeviceType::Cover, Some(state)) => Value::Object(
+            state
+                .iter()
+                .filter(|(key, _)| COVER_RESTORED.contains(&key.as_str()))
+                .map(|(key, value)| (key.to_owned(), value.clone()))
+                .collect(),
+        ),
+        _ => state.clone(),
+    }
+}
+
+impl OnUpdate for StateStore {
+    fn on_update(&self, value: Value) -> Result<(), Error> {
+        let policies: HashMap<String, PowerOnPolicy> = serde_json::from_value(value)?;
+        self.apply(policies)?;
+        info!("Update power on policies: {:?}", self.policies());
+        self.storage.save(POWER_ON, &self.policies())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::store::{PowerOnPolicy, StateStore, DEBOUNCE, POWER_ON};
+    use crate::devices::{Calibration, Control, Cover, CoverTransport, SerialDimmer, SerialSwitch};
+    use crate::home::configuration::Configuration;
+    use crate::io::{Cmd, Recorder, IO};
+    use crate::runtime::Runtime;
+    use crate::storage::Storage;
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    fn devices(recorder: &Recorder) -> (IO, SerialDimmer, SerialSwitch) {
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        let lamp = SerialDimmer::new(&mut io, "lamp", 0x01, 0, 100);
+        let fun = SerialSwitch::new(&mut io, "fun", 0x02);
+        (io.freeze(), lamp, fun)
+    }
+
+    #[test]
+    fn test_restore() {
+        let storage = Storage::temp();
+        let recorder = Recorder::new();
+        let (io, lamp, fun) = devices(&recorder);
+        let store = StateStore::new(&io, storage.clone(), HashMap::new());
+        store.restore();
+
+        lamp.update(json!({"is_on": true, "brightness": 40}))
+            .unwrap();
+        fun.update(json!({"is_on": true})).unwrap();
+        let now = Instant::now();
+        store.snapshot(now).unwrap();
+        assert!(storage
+            .load::<serde_json::Value>("device_state")
+            .unwrap()
+            .is_none());
+        store.snapshot(now + DEBOUNCE).unwrap();
+
+        // Restart: the lamp is restored, the fun is forced off.
+        let recorder = Recorder::new();
+        let (io, lamp, fun) = devices(&recorder);
+        let mut policies = HashMap::new();
+        policies.insert("fun".to_owned(), PowerOnPolicy::Off);
+        StateStore::new(&io, storage, policies).restore();
+        assert_eq!(lamp.load(), json!({"is_on": true, "brightness": 40}));
+        assert_eq!(fun.load(), json!({"is_on": false}));
+        let mut serial = recorder.serial();
+        serial.sort_by_key(|cmd| cmd.id());
+        assert_eq!(
+            serial,
+            vec![Cmd::new(0x01, 0x01, 147), Cmd::new(0x02, 0x02, 0x02)]
+        );
+    }
+
+    #[test]
+    fn test_restore_stopped_cover() {
+        let storage = Storage::temp();
+        storage
+            .save(
+                "device_state",
+                &json!({"blind": {
+                    "position": 40,
+                    "target": 100,
+                    "motion": "opening",
+                    "calibration": {"open_time_ms": 20000, "close_time_ms": 20000, "overrun_ms": 0}
+                }}),
+            )
+            .unwrap();
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        let transport = CoverTransport::Serial {
+            up: 0x10,
+            down: 0x11,
+            reversal_ms: 0,
+        };
+        let cover = Cover::new(&mut io, "blind", transport, Calibration::default());
+        let io = io.freeze();
+
+        StateStore::new(&io, storage, HashMap::new()).restore();
+        assert_eq!(cover.load()["motion"], json!("stopped"));
+        assert_eq!(cover.load()["position"], json!(40));
+        assert_eq!(cover.load()["calibration"]["open_time_ms"], json!(20000));
+        assert!(recorder.serial().iter().all(|cmd| cmd.args() == 0x02));
+    }
+
+    #[test]
+    fn test_configure_policies() {
+        let storage = Storage::temp();
+        let recorder = Recorder::new();
+        let (io, _, _) = devices(&recorder);
+        let mut defaults = HashMap::new();
+        defaults.insert("fun".to_owned(), PowerOnPolicy::Off);
+        let store = StateStore::new(&io, storage.clone(), defaults.clone());
+        let config = Configuration::default();
+        store.register(&config).unwrap();
+        assert_eq!(config.get_value(POWER_ON), Some(json!({"fun": "off"})));
+
+        assert!(config.update(POWER_ON, json!({"unknown": "off"})).is_err());
+        config
+            .update(
+                POWER_ON,
+                json!({"lamp": {"default": {"is_on": true, "brightness": 10}}}),
+            )
+            .unwrap();
+
+        // The saved policies replace the defaults after a restart.
+        let store = StateStore::new(&io, storage, defaults);
+        let mut expected = HashMap::new();
+        expected.insert(
+            "lamp".to_owned(),
+            PowerOnPolicy::Default(json!({"is_on": true, "brightness": 10})),
+        );
+        assert_eq!(store.policies(), expected);
+    }
+}
@@ -0,0 +1,466 @@
+use crate::devices::{Control, DeviceType, Flush, Locks, Schema, Switch};
+use crate::io::IOMut;
+use crate::log_error;
+use crate::runtime::{Background, Runtime};
+use crate::sensors::AnalogSensor;
+use anyhow::{Error, Result};
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveTime, Utc};
+use derivative::Derivative;
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+const CONTROL_INTERVAL: Duration = Duration::from_secs(10);
+/// Older readings are ignored and the heating is stopped.
+const SENSOR_TIMEOUT_SECS: i64 = 10 * 60;
+const MIN_SETPOINT: f64 = 5.0;
+const MAX_SETPOINT: f64 = 30.0;
+const MAX_CYCLE_SECS: i64 = 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThermostatMode {
+    Off,
+    Manual,
+    Schedule,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Regulator {
+    /// Heats below `setpoint - band / 2` and stops above `setpoint + band / 2`.
+    Hysteresis { band: f64 },
+    /// Time proportional output: on for `duty * cycle_secs` of every cycle.
+    Pi { kp: f64, ki: f64, cycle_secs: u64 },
+}
+
+impl Default for Regulator {
+    fn default() -> Self {
+        Regulator::Hysteresis { band: 0.5 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SchedulePoint {
+    /// Time of day "HH:MM:SS".
+    pub at: NaiveTime,
+    pub setpoint: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThermostatSettings {
+    pub mode: ThermostatMode,
+    pub setpoint: f64,
+    #[serde(default)]
+    pub regulator: Regulator,
+    /// Setpoints by time of day used in the schedule mode.
+    #[serde(default)]
+    pub schedule: Vec<SchedulePoint>,
+}
+
+impl Default for ThermostatSettings {
+    fn default() -> Self {
+        ThermostatSettings {
+            mode: ThermostatMode::Off,
+            setpoint: 20.0,
+            regulator: Default::default(),
+            schedule: vec![],
+        }
+    }
+}
+
+impl ThermostatSettings {
+    pub fn validate(&self) -> Result<()> {
+        let setpoints = self.schedule.iter().map(|point| point.setpoint);
+        for setpoint in setpoints.chain(Some(self.setpoint)) {
+            if !(MIN_SETPOINT..=MAX_SETPOINT).contains(&setpoint) {
+                return Err(Error::msg(format!("Invalid setpoint: {}", setpoint)));
+            }
+        }
+
+        let valid = match self.regulator {
+            Regulator::Hysteresis { band } => band > 0.0,
+            Regulator::Pi { kp, ki, cycle_secs } => kp >= 0.0 && ki >= 0.0 && cycle_secs > 0,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(Error::msg(format!(
+                "Invalid regulator: {:?}",
+                self.regulator
+            )))
+        }
+    }
+
+    /// Active setpoint at the time of day, None if the heating is off.
+    fn setpoint_at(&self, time: NaiveTime) -> Option<f64> {
+        match self.mode {
+            ThermostatMode::Off => None,
+            ThermostatMode::Manual => Some(self.setpoint),
+            ThermostatMode::Schedule => {
+                // The last point before the time wins, before the first one the last point of the day.
+                let mut points = self.schedule.clone();
+                points.sort_by_key(|point| point.at);
+                let point = points
+                    .iter()
+                    .rev()
+                    .find(|point| point.at <= time)
+                    .or_else(|| points.last());
+                Some(point.map_or(self.setpoint, |point| point.setpoint))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct LoopState {
+    temperature: Option<f64>,
+    heating: bool,
+    integral: f64,
+    duty: f64,
+    cycle_start: Option<DateTime<Utc>>,
+    last_tick: Option<DateTime<Utc>>,
+}
+
+impl LoopState {
+    fn demand(
+        &mut self,
+        regulator: Regulator,
+        temperature: f64,
+        setpoint: f64,
+        now: DateTime<Utc>,
+    ) -> bool {
+        match regulator {
+            Regulator::Hysteresis { band } => {
+                if temperature <= setpoint - band / 2.0 {
+                    true
+                } else if temperature >= setpoint + band / 2.0 {
+                    false
+                } else {
+                    self.heating
+                }
+            }
+            Regulator::Pi { kp, ki, cycle_secs } => {
+                let error = setpoint - temperature;
+                let dt = self
+                    .last_tick
+                    .map_or(0.0, |last| (now - last).num_milliseconds() as f64 / 1000.0);
+                self.last_tick = Some(now);
+                // Anti windup: the integral part alone never exceeds the full output.
+                let max_integral = if ki > 0.0 { 1.0 / ki } else { 0.0 };
+                self.integral = (self.integral + error * dt).clamp(0.0, max_integral);
+
+                let cycle = ChronoDuration::seconds(cycle_secs as i64);
+                let start = match self.cycle_start {
+                    Some(start) if now - start < cycle => start,
+                    _ => {
+                        self.duty = (kp * error + ki * self.integral).clamp(0.0, 1.0);
+                        self.cycle_start = Some(now);
+                        now
+                    }
+                };
+                ((now - start).num_milliseconds() as f64) < cycle_secs as f64 * 1000.0 * self.duty
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.heating = false;
+        self.integral = 0.0;
+        self.duty = 0.0;
+        self.cycle_start = None;
+        self.last_tick = None;
+    }
+}
+
+///
+/// Room heating: keeps the temperature of the analog sensor at the setpoint by switching the actuator.
+///
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct Thermostat {
+    id: Arc<String>,
+    sensor: AnalogSensor,
+    #[derivative(Debug = "ignore")]
+    actuator: Arc<dyn Switch + Send + Sync>,
+    settings: Arc<RwLock<ThermostatSettings>>,
+    state: Arc<RwLock<LoopState>>,
+    locks: Locks,
+}
+
+impl Thermostat {
+    pub fn new<A>(
+        io: &mut IOMut,
+        id: &str,
+        sensor: AnalogSensor,
+        actuator: A,
+        settings: ThermostatSettings,
+    ) -> Thermostat
+    where
+        A: Switch + Send + Sync + 'static,
+    {
+        let dev = Thermostat {
+            id: Arc::new(id.to_owned()),
+            sensor,
+            actuator: Arc::new(actuator),
+            settings: Arc::new(RwLock::new(settings)),
+            state: Default::default(),
+            locks: io.shared().locks().clone(),
+        };
+        io.reg_device(Box::new(dev.clone()));
+        dev
+    }
+
+    pub(super) fn schema() -> Schema {
+        let setpoint = || Schema::Number {
+            min: MIN_SETPOINT,
+            max: MAX_SETPOINT,
+        };
+        let gain = || Schema::Number {
+            min: 0.0,
+            max: 100.0,
+        };
+        Schema::object(vec![
+            ("mode", Schema::Enum(vec!["off", "manual", "schedule"])),
+            ("setpoint", setpoint()),
+            (
+                "regulator",
+                Schema::OneOf(vec![
+                    Schema::variant(
+                        "hysteresis",
+                        Schema::record(vec![(
+                            "band",
+                            Schema::Number {
+                                min: 0.0,
+                                max: 10.0,
+                            },
+                        )]),
+                    ),
+                    Schema::variant(
+                        "pi",
+                        Schema::record(vec![
+                            ("kp", gain()),
+                            ("ki", gain()),
+                            ("cycle_secs", Schema::integer(1, MAX_CYCLE_SECS)),
+                        ]),
+                    ),
+                ]),
+            ),
+            (
+                "schedule",
+                Schema::Array(Box::new(Schema::record(vec![
+                    // Time of day "HH:MM:SS".
+                    ("at", Schema::String),
+                    ("setpoint", setpoint()),
+                ]))),
+            ),
+        ])
+    }
+
+    /// One step of the control loop.
+    fn tick(&self, now: DateTime<Utc>, time: NaiveTime) -> Result<()> {
+        let settings = self.settings.read().unwrap().clone();
+        let heating = {
+            let mut state = self.state.write().unwrap();
+            state.temperature = self
+                .sensor
+                .reading()
+                .filter(|reading| (now - reading.time).num_seconds() < SENSOR_TIMEOUT_SECS)
+                .map(|reading| reading.value);
+
+            let heating = match (settings.setpoint_at(time), state.temperature) {
+                (Some(setpoint), Some(temperature)) => {
+                    state.demand(settings.regulator, temperature, setpoint, now)
+                }
+                (Some(_), None) => {
+                    warn!(
+                        "Thermostat {}: no temperature of {}",
+                        self.id,
+                        self.sensor.id()
+                    );
+                    state.reset();
+                    false
+                }
+                (None, _) => {
+                    state.reset();
+                    false
+                }
+            };
+            state.heating = heating;
+            heating
+        };
+
+        if self.actuator.is_on() != heating {
+            info!("Thermostat {}: heating {}", self.id, heating);
+            self.actuator.switch(heating)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+///
+/// State {mode, setpoint, regulator, schedule: [{at, setpoint}], target, temperature, heating, duty}
+/// Update accepts any of mode, setpoint, regulator and schedule.
+///
+impl Control for Thermostat {
+    fn id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    fn dev_type(&self) -> DeviceType {
+        DeviceType::Thermostat
+    }
+
+    fn load(&self) -> Value {
+        let settings = self.settings.read().unwrap();
+        let state = self.state.read().unwrap();
+        let mut value = serde_json::to_value(&*settings).unwrap();
+        value["target"] = json!(settings.setpoint_at(Local::now().time()));
+        value["temperature"] = json!(state.temperature);
+        value["heating"] = json!(state.heating);
+        value["duty"] = json!(state.duty);
+        self.locks.describe(&self.id, &mut value);
+        value
+    }
+
+    fn update(&self, value: Value) -> Result<()> {
+        self.locks.check(&self.id)?;
+        {
+            let mut settings = self.settings.write().unwrap();
+            let mut merged = serde_json::to_value(&*settings)?;
+            for key in &["mode", "setpoint", "regulator", "schedule"] {
+                if let Some(val) = value.get(*key) {
+                    merged[*key] = val.clone();
+                }
+            }
+            let updated: ThermostatSettings = serde_json::from_value(merged)?;
+            updated.validate()?;
+            if updated.regulator != settings.regulator {
+                self.state.write().unwrap().reset();
+            }
+            *settings = updated;
+        }
+        self.tick(Utc::now(), Local::now().time())
+    }
+
+    /// The control loop.
+    fn start(&self, rt: &Runtime) -> Option<Background> {
+        let thermostat = self.clone();
+        Some(Background::every(rt, CONTROL_INTERVAL, true, move || {
+            log_error!(&thermostat.tick(Utc::now(), Local::now().time()));
+        }))
+    }
+}
+
+impl Flush for Thermostat {
+    fn flush(&self) -> Result<()> {
+        let heating = self.state.read().unwrap().heating;
+        self.actuator.switch(heating)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::thermostat::{
+        LoopState, Regulator, SchedulePoint, ThermostatMode, ThermostatSettings,
+    };
+    use crate::devices::{Control, SerialSwitch, Thermostat};
+    use crate::io::{Cmd, Recorder, IO};
+    use crate::runtime::Runtime;
+    use crate::sensors::AnalogSensor;
+    use chrono::{Duration, NaiveTime, Utc};
+
+    fn time(hour: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        let sensor = AnalogSensor::new(&mut io, "bedroom_temperature");
+        let relay = SerialSwitch::new(&mut io, "bedroom_heating", 0x20);
+        let thermostat = Thermostat::new(
+            &mut io,
+            "bedroom_thermostat",
+            sensor.clone(),
+            relay,
+            ThermostatSettings::default(),
+        );
+
+        sensor.set(19.0);
+        thermostat
+            .update(json!({"mode": "manual", "setpoint": 21.0}))
+            .unwrap();
+        assert_eq!(recorder.serial(), vec![Cmd::new(0x02, 0x20, 0x01)]);
+        assert_eq!(thermostat.load()["heating"], json!(true));
+        assert_eq!(thermostat.load()["temperature"], json!(19.0));
+
+        // Inside of the band the heating keeps going.
+        sensor.set(21.1);
+        thermostat.tick(Utc::now(), time(12)).unwrap();
+        assert_eq!(thermostat.load()["heating"], json!(true));
+        sensor.set(21.3);
+        thermostat.tick(Utc::now(), time(12)).unwrap();
+        assert_eq!(recorder.serial()[1], Cmd::new(0x02, 0x20, 0x02));
+
+        // Stale readings stop the heating.
+        sensor.set(15.0);
+        thermostat
+            .tick(Utc::now() + Duration::minutes(30), time(12))
+            .unwrap();
+        assert_eq!(thermostat.load()["heating"], json!(false));
+        assert_eq!(recorder.serial().len(), 2);
+
+        assert!(thermostat.update(json!({"setpoint": 45.0})).is_err());
+        assert!(thermostat.update(json!({"mode": "boost"})).is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let settings = ThermostatSettings {
+            mode: ThermostatMode::Schedule,
+            setpoint: 18.0,
+            regulator: Default::default(),
+            schedule: vec![
+                SchedulePoint {
+                    at: time(22),
+                    setpoint: 17.0,
+                },
+                SchedulePoint {
+                    at: time(6),
+                    setpoint: 21.0,
+                },
+            ],
+        };
+        assert_eq!(settings.setpoint_at(time(3)), Some(17.0));
+        assert_eq!(settings.setpoint_at(time(6)), Some(21.0));
+        assert_eq!(settings.setpoint_at(time(23)), Some(17.0));
+
+        let off = ThermostatSettings {
+            mode: ThermostatMode::Off,
+            ..settings
+        };
+        assert_eq!(off.setpoint_at(time(12)), None);
+    }
+
+    #[test]
+    fn test_pi() {
+        let regulator = Regulator::Pi {
+            kp: 0.5,
+            ki: 0.0,
+            cycle_secs: 100,
+        };
+        let mut state = LoopState::default();
+        let start = Utc::now();
+        // 1 degree below the setpoint: half of the cycle on.
+        assert!(state.demand(regulator, 20.0, 21.0, start));
+        assert_eq!(state.duty, 0.5);
+        assert!(state.demand(regulator, 20.0, 21.0, start + Duration::seconds(40)));
+        assert!(!state.demand(regulator, 20.0, 21.0, start + Duration::seconds(60)));
+        // The next cycle takes the new error.
+        assert!(!state.demand(regulator, 21.5, 21.0, start + Duration::seconds(100)));
+        assert_eq!(state.duty, 0.0);
+    }
+}
@@ -0,0 +1,423 @@
+use crate::devices::web::Reconciler;
+use crate::devices::{Control, DeviceType, Flush, Schema, Switch};
+use crate::io::{IOMut, Output, WebCmd, IO};
+use crate::log_error;
+use crate::runtime::{Background, Runtime};
+use crate::sensors::AnalogSensor;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::error::Error as StdError;
+use std::fmt::{Display, Error as FmtError, Formatter};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+const CONTROL_INTERVAL: Duration = Duration::from_secs(1);
+/// Readings of the leak sensor from this value mean water on the floor.
+const LEAK_THRESHOLD: f64 = 0.5;
+const FAIL_SAFE_SECS: u64 = 60;
+
+///
+/// Change refused by the safety rules of the device.
+///
+#[derive(Debug)]
+pub struct SafetyError(String);
+
+impl Display for SafetyError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl StdError for SafetyError {}
+
+///
+/// Safety rules of a valve.
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValveSettings {
+    /// Main supplies are closed through the API only with {"confirm": true}.
+    #[serde(default)]
+    pub main_supply: bool,
+    /// The valve closes after it has been open this long, e.g. the return pump.
+    #[serde(default)]
+    pub max_open_secs: Option<u64>,
+    /// State the device takes by itself if it gets no command for `fail_safe_secs`,
+    /// closed while the leak sensor reports water.
+    #[serde(default)]
+    pub fail_safe_open: bool,
+    #[serde(default = "ValveSettings::default_fail_safe_secs")]
+    pub fail_safe_secs: u64,
+}
+
+impl Default for ValveSettings {
+    fn default() -> Self {
+        ValveSettings {
+            main_supply: false,
+            max_open_secs: None,
+            fail_safe_open: false,
+            fail_safe_secs: FAIL_SAFE_SECS,
+        }
+    }
+}
+
+impl ValveSettings {
+    /// Main water supply, stays open if the controller is gone.
+    pub fn main_supply() -> ValveSettings {
+        ValveSettings {
+            main_supply: true,
+            fail_safe_open: true,
+            ..Default::default()
+        }
+    }
+
+    /// Pump which must not run longer than `max_open_secs`.
+    pub fn pump(max_open_secs: u64) -> ValveSettings {
+        ValveSettings {
+            max_open_secs: Some(max_open_secs),
+            ..Default::default()
+        }
+    }
+
+    fn default_fail_safe_secs() -> u64 {
+        FAIL_SAFE_SECS
+    }
+}
+
+#[derive(Debug, Default)]
+struct ValveState {
+    is_open: bool,
+    opened_at: Option<DateTime<Utc>>,
+    leak: bool,
+    last_sent: Option<DateTime<Utc>>,
+}
+
+///
+/// Water valve on a web device. Closes on a leak and after the max open time, even if locked,
+/// and keeps the device informed of its fail-safe state.
+///
+#[derive(Debug, Clone)]
+pub struct Valve {
+    id: Arc<String>,
+    io: IO,
+    leak_sensor: Option<AnalogSensor>,
+    settings: Arc<ValveSettings>,
+    state: Arc<RwLock<ValveState>>,
+    reconciler: Reconciler,
+}
+
+impl Valve {
+    pub fn new(
+        io: &mut IOMut,
+        id: &str,
+        leak_sensor: Option<AnalogSensor>,
+        settings: ValveSettings,
+    ) -> Valve {
+        let dev = Valve {
+            id: Arc::new(id.to_owned()),
+            io: io.shared(),
+            leak_sensor,
+            state: Arc::new(RwLock::new(ValveState {
+                // The device is in the fail-safe state until the first command.
+                is_open: settings.fail_safe_open,
+                last_sent: Some(Utc::now()),
+                ..Default::default()
+            })),
+            settings: Arc::new(settings),
+            reconciler: Default::default(),
+        };
+        io.reg_device(Box::new(dev.clone()));
+        dev
+    }
+
+    pub(super) fn schema() -> Schema {
+        Schema::object(vec![("is_on", Schema::Bool), ("confirm", Schema::Bool)])
+    }
+
+    fn leaking(&self) -> bool {
+        self.leak_sensor
+            .as_ref()
+            .and_then(|sensor| sensor.reading())
+            .is_some_and(|reading| reading.value >= LEAK_THRESHOLD)
+    }
+
+    /// One step of the safety loop.
+    fn tick(&self, now: DateTime<Utc>) -> Result<()> {
+        let leak = self.leaking();
+        let (close, heartbeat) = {
+            let mut state = self.state.write().unwrap();
+            // The fail-safe state of the device follows the leak.
+            let leak_changed = leak != state.leak;
+            if leak_changed {
+                state.leak = leak;
+                if leak {
+                    warn!("Valve {}: leak detected", self.id);
+                }
+            }
+            let expired = match (self.settings.max_open_secs, state.opened_at) {
+                (Some(max_open), Some(opened_at)) if state.is_open => {
+                    (now - opened_at).num_seconds() >= max_open as i64
+                }
+                _ => false,
+            };
+            if expired {
+                info!("Valve {}: open for too long", self.id);
+            }
+            // The device falls back to the fail-safe state if it misses a few heartbeats.
+            let heartbeat = leak_changed
+                || state.last_sent.is_none_or(|sent| {
+                    (now - sent).num_seconds() >= (self.settings.fail_safe_secs / 3) as i64
+                });
+            (state.is_open && (leak || expired), heartbeat)
+        };
+
+        if close {
+            self.set(false, now)
+        } else if heartbeat {
+            self.send(now)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn set(&self, is_open: bool, now: DateTime<Utc>) -> Result<()> {
+        {
+            let mut state = self.state.write().unwrap();
+            if is_open && !state.is_open {
+                state.opened_at = Some(now);
+            } else if !is_open {
+                state.opened_at = None;
+            }
+            state.is_open = is_open;
+        }
+        self.send(now)
+    }
+
+    fn send(&self, now: DateTime<Utc>) -> Result<()> {
+        self.state.write().unwrap().last_sent = Some(now);
+        self.io.send(&self.id, self.cmd())
+    }
+
+    ///
+    /// v1 args ["ON|OFF:power", "FS:<0|1>:timeout_secs"],
+    /// v2 state {is_on, power, fail_safe: {is_on, timeout_secs}}
+    ///
+    fn cmd(&self) -> WebCmd {
+        let (is_open, leak) = {
+            let state = self.state.read().unwrap();
+            (state.is_open, state.leak)
+        };
+        let fail_safe_open = self.settings.fail_safe_open && !leak;
+        WebCmd::new(
+            vec![
+                format!("{}:{}", if is_open { "ON" } else { "OFF" }, 100),
+                format!(
+                    "FS:{}:{}",
+                    fail_safe_open as u8, self.settings.fail_safe_secs
+                ),
+            ],
+            json!({
+                "is_on": is_open,
+                "power": 100,
+                "fail_safe": {
+                    "is_on": fail_safe_open,
+                    "timeout_secs": self.settings.fail_safe_secs
+                }
+            }),
+        )
+    }
+}
+
+impl Switch for Valve {
+    fn is_on(&self) -> bool {
+        self.state.read().unwrap().is_open
+    }
+
+    /// Opening is refused while the leak sensor reports water.
+    fn switch(&self, is_on: bool) -> Result<()> {
+        self.io.locks().check(&self.id)?;
+        if is_on && self.leaking() {
+            return Err(
+                SafetyError(format!("Valve {} is closed because of a leak", self.id)).into(),
+            );
+        }
+        self.set(is_on, Utc::now())
+    }
+}
+
+///
+/// State {is_on, leak, opened_at, settings, reported}
+/// Update accepts is_on and confirm.
+///
+impl Control for Valve {
+    fn id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    fn dev_type(&self) -> DeviceType {
+        DeviceType::Valve
+    }
+
+    fn load(&self) -> Value {
+        let state = self.state.read().unwrap();
+        let mut value = json!({
+            "is_on": state.is_open,
+            "leak": state.leak,
+            "opened_at": state.opened_at,
+            "settings": &*self.settings,
+            "reported": self.reconciler.info()
+        });
+        self.io.locks().describe(&self.id, &mut value);
+        value
+    }
+
+    /// An open valve draws power, e.g. the pump of the return line.
+    fn power_level(&self) -> Option<f64> {
+        Some(if self.is_on() { 1.0 } else { 0.0 })
+    }
+
+    fn guard(&self, state: &Value) -> Result<()> {
+        let closing = state["is_on"].as_bool() == Some(false) && self.is_on();
+        if self.settings.main_supply && closing && state["confirm"].as_bool() != Some(true) {
+            return Err(SafetyError(format!(
+                "Closing the main supply {} requires confirmation",
+                self.id
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    fn update(&self, state: Value) -> Result<()> {
+        if let Some(is_on) = state["is_on"].as_bool() {
+            self.switch(is_on)?;
+        }
+        Ok(())
+    }
+
+    fn reconcile(&self) -> Result<()> {
+        self.state.write().unwrap().last_sent = Some(Utc::now());
+        self.reconciler.reconcile(&self.io, &self.id, self.cmd())
+    }
+
+    /// The safety loop runs on its own runtime: blocking sends of other devices on the shared
+    /// runtime must not delay the leak shutdown.
+    fn start(&self, _rt: &Runtime) -> Option<Background> {
+        let valve = self.clone();
+        Some(Background::every(
+            &Runtime::new(1),
+            CONTROL_INTERVAL,
+            true,
+            move || log_error!(&valve.tick(Utc::now())),
+        ))
+    }
+}
+
+impl Flush for Valve {
+    fn flush(&self) -> Result<()> {
+        self.send(Utc::now())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::{Control, SafetyError, Switch, Valve, ValveSettings};
+    use crate::io::{Input, Output, Recorder, WebCmd, IO};
+    use crate::runtime::{Background, Runtime};
+    use crate::sensors::AnalogSensor;
+    use chrono::{Duration, Utc};
+    use std::thread;
+    use std::time::{Duration as StdDuration, Instant};
+
+    #[test]
+    fn test_safety() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        let leak = AnalogSensor::new(&mut io, "leak");
+        let supply = Valve::new(
+            &mut io,
+            "cold_water",
+            Some(leak.clone()),
+            ValveSettings::main_supply(),
+        );
+        let pump = Valve::new(&mut io, "return_water", None, ValveSettings::pump(600));
+        let io = io.freeze();
+        assert!(supply.is_on());
+        assert!(!pump.is_on());
+
+        let err = io
+            .update_device("cold_water", json!({"is_on": false}))
+            .unwrap_err();
+        assert!(err.downcast_ref::<SafetyError>().is_some());
+        assert!(supply.is_on());
+        io.update_device("cold_water", json!({"is_on": false, "confirm": true}))
+            .unwrap();
+        assert!(!supply.is_on());
+        io.update_device("cold_water", json!({"is_on": true}))
+            .unwrap();
+        assert_eq!(
+            recorder.sent("cold_water").last().unwrap().state,
+            json!({"is_on": true, "power": 100, "fail_safe": {"is_on": true, "timeout_secs": 60}})
+        );
+
+        // Water on the floor closes the supply and keeps it closed.
+        leak.set(1.0);
+        supply.tick(Utc::now()).unwrap();
+        assert!(!supply.is_on());
+        assert!(supply.switch(true).is_err());
+        // Without the controller the device stays closed as well.
+        let cmd = recorder.sent("cold_water").pop().unwrap();
+        assert_eq!(cmd.state["fail_safe"]["is_on"], json!(false));
+        assert_eq!(cmd.args, vec!["OFF:100".to_owned(), "FS:0:60".to_owned()]);
+        leak.set(0.0);
+        supply.switch(true).unwrap();
+
+        let start = Utc::now();
+        pump.set(true, start).unwrap();
+        pump.tick(start + Duration::seconds(599)).unwrap();
+        assert!(pump.is_on());
+        assert_eq!(pump.power_level(), Some(1.0));
+        pump.tick(start + Duration::seconds(600)).unwrap();
+        assert!(!pump.is_on());
+
+        // Heartbeats keep the device out of the fail-safe state.
+        let sent = recorder.sent("return_water").len();
+        pump.tick(start + Duration::seconds(610)).unwrap();
+        assert_eq!(recorder.sent("return_water").len(), sent);
+        pump.tick(start + Duration::seconds(620)).unwrap();
+        assert_eq!(recorder.sent("return_water").len(), sent + 1);
+    }
+
+    #[test]
+    fn test_leak_while_sends_hang() {
+        let recorder = Recorder::new();
+        let rt = Runtime::new(2);
+        let mut io = IO::with_output(&rt, recorder.clone());
+        let leak = AnalogSensor::new(&mut io, "leak");
+        let supply = Valve::new(
+            &mut io,
+            "cold_water",
+            Some(leak.clone()),
+            ValveSettings::main_supply(),
+        );
+        let io = io.freeze();
+
+        // Every thread of the shared runtime waits for an unreachable device.
+        recorder.set_delay("beam", StdDuration::from_secs(10));
+        let hanging = io.clone();
+        let _sends = Background::every(&rt, StdDuration::from_millis(10), true, move || {
+            let cmd = WebCmd::new(vec!["ON".to_owned()], json!({"is_on": true}));
+            let _ = hanging.send("beam", cmd);
+        });
+        thread::sleep(StdDuration::from_millis(100));
+
+        let _safety = supply.start(&rt);
+        leak.set(1.0);
+        let start = Instant::now();
+        while supply.is_on() && start.elapsed() < StdDuration::from_secs(5) {
+            thread::sleep(StdDuration::from_millis(50));
+        }
+        assert!(!supply.is_on());
+        assert!(start.elapsed() < StdDuration::from_secs(3));
+    }
+}
@@ -1,6 +1,7 @@
-use crate::devices::{Control, DeviceType, Flush, Switch};
-use crate::io::{IOMut, Output, IO};
+use crate::devices::{Control, DeviceType, Flush, Schema, Switch};
+use crate::io::{IOMut, Output, WebCmd, WebState, IO};
 use anyhow::{Error, Result};
+use chrono::{DateTime, Utc};
 use serde_json::Value;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
@@ -21,38 +22,33 @@ pub struct Noise {
 }
 
 impl Noise {
-    //    pub fn new(hue_start: u8, hue_gap: u8, noise_step: u8) -> Noise {
-    //        let mut noise = Self::default();
-    //        noise.hue_gap = hue_gap;
-    //        noise.noise_step = noise_step;
-    //        noise.hue_start = hue_start;
-    //        noise
-    //    }
-    //    pub fn preset_1() -> Noise {
-    //        Noise {
-    //            hue_start: 0,
-    //            hue_gap: 50,
-    //            noise_step: 50,
-    //            min_bright: 245,
-    //            max_bright: 255,
-    //            min_sat: 245,
-    //            max_sat: 255,
-    //            delay: 40,
-    //        }
-    //    }
-    //
-    //    pub fn preset_2() -> Noise {
-    //        Noise {
-    //            hue_start: 180,
-    //            hue_gap: 255,
-    //            noise_step: 50,
-    //            min_bright: 100,
-    //            max_bright: 255,
-    //            min_sat: 250,
-    //            max_sat: 255,
-    //            delay: 40,
-    //        }
-    //    }
+    /// Red to orange hues.
+    pub fn warm() -> Noise {
+        Noise {
+            hue_start: 0,
+            hue_gap: 50,
+            noise_step: 50,
+            min_bright: 245,
+            max_bright: 255,
+            min_sat: 245,
+            max_sat: 255,
+            delay: 40,
+        }
+    }
+
+    /// Every hue starting from cyan.
+    pub fn spectrum() -> Noise {
+        Noise {
+            hue_start: 180,
+            hue_gap: 255,
+            noise_step: 50,
+            min_bright: 100,
+            max_bright: 255,
+            min_sat: 250,
+            max_sat: 255,
+            delay: 40,
+        }
+    }
 }
 
 impl Default for Noise {
@@ -76,9 +72,46 @@ pub enum LedMode {
     Rainbow(SpeedAndBrightness),
     Borealis(SpeedAndBrightness),
     Noise(Noise),
+    /// Blend from the first color to the second one along the strip.
+    Gradient(Color, Color),
+    Fire(SpeedAndBrightness),
+    /// Color pulsing with the speed.
+    Breathe(Color, u8),
+    /// Dawn simulation from dark red to daylight, duration in minutes.
+    Sunrise(u8),
 }
 
 impl LedMode {
+    pub(super) fn schema() -> Schema {
+        let color = Schema::Tuple(vec![Schema::byte(); 3]);
+        let speed_and_brightness = Schema::Tuple(vec![Schema::byte(); 2]);
+        Schema::OneOf(vec![
+            Schema::variant("Color", color.clone()),
+            Schema::variant("Rainbow", speed_and_brightness.clone()),
+            Schema::variant("Borealis", speed_and_brightness.clone()),
+            Schema::variant(
+                "Noise",
+                Schema::record(vec![
+                    ("hue_start", Schema::byte()),
+                    ("hue_gap", Schema::byte()),
+                    ("noise_step", Schema::byte()),
+                    ("min_bright", Schema::byte()),
+                    ("max_bright", Schema::byte()),
+                    ("min_sat", Schema::byte()),
+                    ("max_sat", Schema::byte()),
+                    ("delay", Schema::byte()),
+                ]),
+            ),
+            Schema::variant(
+                "Gradient",
+                Schema::Tuple(vec![color.clone(), color.clone()]),
+            ),
+            Schema::variant("Fire", speed_and_brightness),
+            Schema::variant("Breathe", Schema::Tuple(vec![color, Schema::byte()])),
+            Schema::variant("Sunrise", Schema::integer(1, 255)),
+        ])
+    }
+
     fn arg(&self) -> String {
         match self {
             LedMode::Color((r, g, b)) => format!("color:{}:{}:{}", r, g, b),
@@ -95,6 +128,12 @@ impl LedMode {
                 n.max_sat,
                 n.delay
             ),
+            LedMode::Gradient((r1, g1, b1), (r2, g2, b2)) => {
+                format!("gradient:{}:{}:{}:{}:{}:{}", r1, g1, b1, r2, g2, b2)
+            }
+            LedMode::Fire((speed, power)) => format!("fire:{}:{}", speed, power),
+            LedMode::Breathe((r, g, b), speed) => format!("breathe:{}:{}:{}:{}", r, g, b, speed),
+            LedMode::Sunrise(minutes) => format!("sunrise:{}", minutes),
         }
     }
 }
@@ -106,15 +145,15 @@ pub struct LedState {
 }
 
 impl LedState {
-    fn merge(&mut self, value: &Value) {
+    fn merge(&mut self, value: &Value) -> Result<()> {
+        if let Some(mode) = value.get("mode") {
+            self.mode = serde_json::from_value(mode.clone())
+                .map_err(|err| Error::msg(format!("Invalid led mode {}: {}", mode, err)))?;
+        }
         if let Some(is_on) = value["is_on"].as_bool() {
             self.is_on = is_on;
         }
-        if let Some(mode) = value.get("mode") {
-            if let Ok(mode) = serde_json::from_value(mode.clone()) {
-                self.mode = mode;
-            }
-        }
+        Ok(())
     }
 }
 
@@ -135,6 +174,17 @@ struct BeamState {
 }
 
 impl BeamState {
+    fn schema() -> Schema {
+        Schema::object(vec![
+            ("is_on", Schema::Bool),
+            ("is_spot_on", Schema::Bool),
+            (
+                "led_state",
+                Schema::object(vec![("is_on", Schema::Bool), ("mode", LedMode::schema())]),
+            ),
+        ])
+    }
+
     pub fn set_state(&mut self, spot: Option<bool>, led: Option<LedState>) {
         if let Some(spot) = spot {
             self.is_spot_on = spot;
@@ -159,7 +209,17 @@ impl BeamState {
         format!("{}:{}:{}", spot_state, led_stat, self.led_state.mode.arg())
     }
 
-    pub fn merge_state(&mut self, value: &Value) {
+    fn state(&self) -> Value {
+        json!({
+            "spot": self.is_on && self.is_spot_on,
+            "led": self.is_on && self.led_state.is_on,
+            "mode": self.led_state.mode,
+        })
+    }
+
+    pub fn merge_state(&mut self, value: &Value) -> Result<()> {
+        self.led_state.merge(&value["led_state"])?;
+
         if let Some(is_on) = value["is_on"].as_bool() {
             self.is_on = is_on;
         }
@@ -167,8 +227,57 @@ impl BeamState {
         if let Some(is_spot_on) = value["is_spot_on"].as_bool() {
             self.is_spot_on = is_spot_on;
         }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Reported {
+    state: Option<WebState>,
+    mismatches: u64,
+    last_check: Option<DateTime<Utc>>,
+}
+
+///
+/// Desired vs reported state of a web device.
+///
+#[derive(Debug, Clone, Default)]
+pub(super) struct Reconciler {
+    reported: Arc<RwLock<Reported>>,
+}
+
+impl Reconciler {
+    /// Re-sends the desired state only if the device reports a different one
+    /// or can't be asked for its state.
+    pub(super) fn reconcile(&self, io: &IO, id: &str, desired: WebCmd) -> Result<()> {
+        match io.query(id) {
+            Ok(Some(state)) => {
+                let differs = !desired.matches(&state);
+                {
+                    let mut reported = self.reported.write().unwrap();
+                    if differs {
+                        reported.mismatches += 1;
+                    }
+                    reported.state = Some(state);
+                    reported.last_check = Some(Utc::now());
+                }
+                if differs {
+                    info!("Device {} state differs from desired, re-send", id);
+                    io.send(id, desired)
+                } else {
+                    Ok(())
+                }
+            }
+            Ok(None) => io.send(id, desired),
+            Err(err) => {
+                warn!("Failed to query device {}, re-send: {}", id, err);
+                io.send(id, desired)
+            }
+        }
+    }
 
-        self.led_state.merge(&value["led_state"]);
+    pub(super) fn info(&self) -> Value {
+        serde_json::to_value(&*self.reported.read().unwrap()).unwrap()
     }
 }
 
@@ -178,6 +287,7 @@ pub struct WebBeam {
     io: IO,
     channel_1: Arc<RwLock<BeamState>>,
     channel_2: Arc<RwLock<BeamState>>,
+    reconciler: Reconciler,
 }
 
 impl WebBeam {
@@ -195,18 +305,49 @@ impl WebBeam {
                 led_state: LedState::default(),
                 is_spot_on: true,
             })),
+            reconciler: Default::default(),
         };
         io.reg_device(Box::new(dev.clone()));
 
         dev
     }
 
-    pub fn channel_1(&self, spot: Option<bool>, led: Option<LedState>) {
+    pub(super) fn schema() -> Schema {
+        Schema::object(vec![
+            ("channel_1", BeamState::schema()),
+            ("channel_2", BeamState::schema()),
+        ])
+    }
+
+    fn cmd(&self) -> WebCmd {
+        let channel_1 = self.channel_1.read().unwrap();
+        let channel_2 = self.channel_2.read().unwrap();
+        WebBeam::encode(&channel_1, &channel_2)
+    }
+
+    ///
+    /// v1 args [channel_1, channel_2], v2 state {channel_1: {spot, led, mode}, channel_2: {..}}
+    ///
+    fn encode(channel_1: &BeamState, channel_2: &BeamState) -> WebCmd {
+        WebCmd::new(
+            vec![channel_1.args(), channel_2.args()],
+            json!({
+                "channel_1": channel_1.state(),
+                "channel_2": channel_2.state(),
+            }),
+        )
+    }
+
+    pub fn channel_1(&self, spot: Option<bool>, led: Option<LedState>) -> Result<()> {
+        self.io.locks().check(&self.id)?;
         self.channel_1.write().unwrap().set_state(spot, led);
+        Ok(())
     }
 
-    pub fn channel_2(&self, spot: Option<bool>, led: Option<LedState>) {
+    pub fn channel_2(&self, spot: Option<bool>, led: Option<LedState>) -> Result<()> {
+        self.io.locks().check(&self.id)?;
         self.channel_2.write().unwrap().set_state(spot, led);
+        Ok(())
     }
 }
 
@@ -216,14 +357,15 @@ impl Switch for WebBeam {
     }
 
     fn switch(&self, is_on: bool) -> Result<()> {
-        let args = {
+        self.io.locks().check(&self.id)?;
+        let cmd = {
             let mut channel_1 = self.channel_1.write().unwrap();
             let mut channel_2 = self.channel_2.write().unwrap();
             channel_1.is_on = is_on;
             channel_2.is_on = is_on;
-            vec![channel_1.args(), channel_2.args()]
+            WebBeam::encode(&channel_1, &channel_2)
         };
-        self.io.send(&self.id, args)
+        self.io.send(&self.id, cmd)
     }
 }
 
@@ -234,7 +376,7 @@ struct WebBeamState {
 }
 
 ///
-/// state {is_on, channel_1:"{is_on, is_spot_on, led:"{}"}", channel_2:"{}"}
+/// state {is_on, channel_1:"{is_on, is_spot_on, led:"{}"}", channel_2:"{}", reported: {}}
 ///
 impl Control for WebBeam {
     fn id(&self) -> &str {
@@ -250,32 +392,44 @@ impl Control for WebBeam {
             channel_1: self.channel_1.read().unwrap().clone(),
             channel_2: self.channel_2.read().unwrap().clone(),
         };
-        serde_json::to_value(&state).unwrap()
+        let mut state = serde_json::to_value(&state).unwrap();
+        state["reported"] = self.reconciler.info();
+        self.io.locks().describe(&self.id, &mut state);
+        state
+    }
+
+    /// Half of the power per channel.
+    fn power_level(&self) -> Option<f64> {
+        let channels = [
+            self.channel_1.read().unwrap().is_on,
+            self.channel_2.read().unwrap().is_on,
+        ];
+        Some(channels.iter().filter(|is_on| **is_on).count() as f64 / 2.0)
     }
 
     fn update(&self, state: Value) -> Result<()> {
+        self.io.locks().check(&self.id)?;
         {
             self.channel_1
                 .write()
                 .unwrap()
-                .merge_state(&state["channel_1"]);
+                .merge_state(&state["channel_1"])?;
             self.channel_2
                 .write()
                 .unwrap()
-                .merge_state(&state["channel_2"]);
+                .merge_state(&state["channel_2"])?;
         }
         self.flush()
     }
+
+    fn reconcile(&self) -> Result<()> {
+        self.reconciler.reconcile(&self.io, &self.id, self.cmd())
+    }
 }
 
 impl Flush for WebBeam {
     fn flush(&self) -> Result<(), Error> {
-        let args = {
-            let channel_1 = self.channel_1.read().unwrap();
-            let channel_2 = self.channel_2.read().unwrap();
-            vec![channel_1.args(), channel_2.args()]
-        };
-        self.io.send(&self.id, args)
+        self.io.send(&self.id, self.cmd())
     }
 }
 
@@ -284,6 +438,7 @@ pub struct WebSwitch {
     id: Arc<String>,
     io: IO,
     is_on: Arc<AtomicBool>,
+    reconciler: Reconciler,
 }
 
 impl WebSwitch {
@@ -292,11 +447,27 @@ impl WebSwitch {
             io: io.shared(),
             id: Arc::new(id.to_owned()),
             is_on: Arc::new(AtomicBool::new(false)),
+            reconciler: Default::default(),
         };
         io.reg_device(Box::new(dev.clone()));
 
         dev
     }
+
+    pub(super) fn schema() -> Schema {
+        Schema::object(vec![("is_on", Schema::Bool)])
+    }
+
+    ///
+    /// v1 args ["ON|OFF:power"], v2 state {is_on, power}
+    ///
+    fn cmd(&self) -> WebCmd {
+        let is_on = self.is_on.load(Ordering::SeqCst);
+        WebCmd::new(
+            vec![format!("{}:{}", if is_on { "ON" } else { "OFF" }, 100)],
+            json!({"is_on": is_on, "power": 100}),
+        )
+    }
 }
 
 impl Switch for WebSwitch {
@@ -305,6 +476,7 @@ impl Switch for WebSwitch {
     }
 
     fn switch(&self, is_on: bool) -> Result<()> {
+        self.io.locks().check(&self.id)?;
         self.is_on.store(is_on, Ordering::SeqCst);
         self.flush()
     }
@@ -320,9 +492,16 @@ impl Control for WebSwitch {
     }
 
     fn load(&self) -> Value {
-        json!({
-            "is_on": self.is_on.load(Ordering::SeqCst)
-        })
+        let mut state = json!({
+            "is_on": self.is_on.load(Ordering::SeqCst),
+            "reported": self.reconciler.info()
+        });
+        self.io.locks().describe(&self.id, &mut state);
+        state
+    }
+
+    fn power_level(&self) -> Option<f64> {
+        Some(if self.is_on() { 1.0 } else { 0.0 })
     }
 
     fn update(&self, state: Value) -> Result<()> {
@@ -331,12 +510,53 @@ impl Control for WebSwitch {
         }
         Ok(())
     }
+
+    fn reconcile(&self) -> Result<()> {
+        self.reconciler.reconcile(&self.io, &self.id, self.cmd())
+    }
 }
 
 impl Flush for WebSwitch {
     fn flush(&self) -> Result<()> {
-        let is_on = self.is_on.load(Ordering::SeqCst);
-        let arg = format!("{}:{}", if is_on { "ON" } else { "OFF" }, 100);
-        self.io.send(&self.id, vec![arg])
+        self.io.send(&self.id, self.cmd())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::{Control, Switch, WebSwitch};
+    use crate::io::{Recorder, WebState, IO};
+    use crate::runtime::Runtime;
+
+    #[test]
+    fn test_reconcile() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        let valve = WebSwitch::new(&mut io, "hot_water");
+        valve.switch(true).unwrap();
+        assert_eq!(
+            recorder.sent("hot_water")[0].args,
+            vec!["ON:100".to_owned()]
+        );
+
+        // No status: always re-send.
+        valve.reconcile().unwrap();
+        assert_eq!(recorder.sent("hot_water").len(), 2);
+
+        recorder.report("hot_water", WebState::Args(vec!["ON:100".to_owned()]));
+        valve.reconcile().unwrap();
+        assert_eq!(recorder.sent("hot_water").len(), 2);
+
+        recorder.report(
+            "hot_water",
+            WebState::State(json!({"is_on": true, "power": 100})),
+        );
+        valve.reconcile().unwrap();
+        assert_eq!(recorder.sent("hot_water").len(), 2);
+
+        recorder.report("hot_water", WebState::Args(vec!["OFF:100".to_owned()]));
+        valve.reconcile().unwrap();
+        assert_eq!(recorder.sent("hot_water").len(), 3);
+        assert_eq!(valve.load()["reported"]["mismatches"], 1);
     }
 }
@@ -42,8 +42,12 @@ fn update_web_devices(io: &IO) {
         .devices()
         .iter()
         .for_each(|(_, device)| match device.dev_type() {
-            DeviceType::WebBeam => {
-                log_error!(&device.flush());
+            DeviceType::WebBeam
+            | DeviceType::WebSwitch
+            | DeviceType::ColorLight
+            | DeviceType::Cover
+            | DeviceType::Valve => {
+                log_error!(&device.reconcile());
             }
             _ => {}
         });
@@ -1,8 +1,8 @@
 use anyhow::Error;
 use dashmap::DashMap;
-use serde::export::fmt::Debug;
 use serde::Serialize;
 use serde_json::Value;
+use std::fmt::Debug;
 use std::sync::Arc;
 use std::collections::HashMap;
 
@@ -25,12 +25,26 @@ impl Configuration {
     pub fn get_value(&self, key: &str) -> Option<Value> {
         self.inner.get(key).map(|v| v.value.clone())
     }
+
+    pub fn update(&self, key: &str, value: Value) -> Result<(), Error> {
+        // The handler runs without the entry guard, it may read the configuration itself.
+        let on_update = self
+            .inner
+            .get(key)
+            .map(|cfg| cfg.on_update.clone())
+            .ok_or_else(|| Error::msg(format!("Unknown config: {}", key)))?;
+        on_update.on_update(value.clone())?;
+        if let Some(mut cfg) = self.inner.get_mut(key) {
+            cfg.value = value;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct ConfigValue {
     value: Value,
-    on_update: Box<dyn OnUpdate>,
+    on_update: Arc<dyn OnUpdate>,
 }
 
 impl ConfigValue {
@@ -41,7 +55,7 @@ impl ConfigValue {
     {
         Ok(ConfigValue {
             value: serde_json::to_value(val)?,
-            on_update: Box::new(on_update),
+            on_update: Arc::new(on_update),
         })
     }
 }
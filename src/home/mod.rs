@@ -3,6 +3,10 @@ pub mod configuration;
 mod rooms;
 pub(crate) mod scripts;
 
+use crate::devices::{
+    Aggregate, DeviceGroup, DeviceMeta, DimmerCalibrations, Follow, Followers, LedPresets, Member,
+    PowerOnPolicy, Source,
+};
 use crate::home::configuration::Configuration;
 use crate::home::rooms::bad_room::BadRoom;
 use crate::home::rooms::balcony::Balcony;
@@ -13,11 +17,42 @@ use crate::home::rooms::living_room::LivingRoom;
 use crate::home::rooms::toilet::Toilet;
 use crate::home::scripts::{Runner, Script};
 use crate::io::IOMut;
+use crate::log_error;
+use crate::storage::Storage;
+#[cfg(test)]
+use crate::io::{Recorder, IO};
+#[cfg(test)]
+use crate::runtime::Runtime;
 use anyhow::{Error, Result};
 pub use automation::BackgroundProcess;
 use serde_json::Value;
+use std::collections::BTreeMap;
 use std::{collections::HashMap, sync::Arc};
 
+/// (id, room, name) of the devices of the rooms.
+const DEVICES: [(&str, Option<&str>, &str); 20] = [
+    ("bedroom_lamp", Some("bedroom"), "Bedroom chandelier"),
+    ("balcony_lamp", Some("balcony"), "Balcony lamp"),
+    ("bathroom_lamp", Some("bathroom"), "Bathroom lamp"),
+    ("bathroom_fun", Some("bathroom"), "Bathroom fan"),
+    ("hot_water", Some("bathroom"), "Hot water valve"),
+    ("cold_water", Some("bathroom"), "Cold water valve"),
+    ("return_water", Some("bathroom"), "Return water valve"),
+    ("corridor_lamp", Some("corridor"), "Corridor lamp"),
+    ("corridor_beam", Some("corridor"), "Corridor beam"),
+    ("kitchen_beam", Some("kitchen"), "Kitchen beam"),
+    ("kitchen_lamp", Some("kitchen"), "Kitchen lamp"),
+    ("living_room_lamp", Some("living_room"), "Living room chandelier"),
+    ("cupboard_lamp", Some("living_room"), "Cupboard lamp"),
+    ("lounge_beam", Some("living_room"), "Lounge beam"),
+    ("toilet_lamp", Some("toilet"), "Toilet lamp"),
+    ("toilet_fun", Some("toilet"), "Toilet fan"),
+    ("all_lights", None, "All lights"),
+    ("all_devices", None, "All lights and fans"),
+    ("all_beams", None, "All beams"),
+    ("kitchen_lights", Some("kitchen"), "Kitchen lights"),
+];
+
 #[derive(Debug, Clone)]
 pub struct Home {
     pub bad_room: Arc<BadRoom>,
@@ -27,23 +62,120 @@ pub struct Home {
     pub corridor: Arc<Corridor>,
     pub toilet: Arc<Toilet>,
     pub bathroom: Arc<Bathroom>,
+    /// Lights and fans, switched off by the switch_off_all script.
+    pub all_devices: DeviceGroup,
+    pub led_presets: LedPresets,
+    pub followers: Followers,
     pub scripts: Arc<HashMap<String, Script>>,
 }
 
 impl Home {
-    pub fn new(io: &mut IOMut, config: &Configuration) -> Home {
-        let home = Home {
-            bad_room: Arc::new(BadRoom::new(io)),
-            living_room: Arc::new(LivingRoom::new(io)),
-            kitchen: Arc::new(Kitchen::new(io)),
-            balcony: Arc::new(Balcony::new(io)),
-            corridor: Arc::new(Corridor::new(io)),
-            toilet: Arc::new(Toilet::new(io)),
-            bathroom: Arc::new(Bathroom::new(io)),
+    pub fn new(io: &mut IOMut, config: &Configuration, storage: &Storage) -> Home {
+        let bad_room = Arc::new(BadRoom::new(io));
+        let living_room = Arc::new(LivingRoom::new(io));
+        let kitchen = Arc::new(Kitchen::new(io));
+        let balcony = Arc::new(Balcony::new(io));
+        let corridor = Arc::new(Corridor::new(io));
+        let toilet = Arc::new(Toilet::new(io));
+        let bathroom = Arc::new(Bathroom::new(io));
+
+        let all_lights: Vec<Box<dyn Member>> = vec![
+            Box::new(corridor.beam.clone()),
+            Box::new(bad_room.chandelier.clone()),
+            Box::new(bathroom.lamp.clone()),
+            Box::new(toilet.lamp.clone()),
+            Box::new(kitchen.beam.clone()),
+            Box::new(kitchen.kitchen_lamp.clone()),
+            Box::new(balcony.lamp.clone()),
+            Box::new(living_room.chandelier.clone()),
+            Box::new(living_room.beam.clone()),
+            Box::new(living_room.cupboard_lamp.clone()),
+        ];
+        DeviceGroup::new(io, "all_lights", Aggregate::AnyOn, all_lights);
+        let all_devices: Vec<Box<dyn Member>> = vec![
+            Box::new(corridor.beam.clone()),
+            Box::new(bad_room.chandelier.clone()),
+            Box::new(bathroom.lamp.clone()),
+            Box::new(bathroom.fun.clone()),
+            Box::new(toilet.fun.clone()),
+            Box::new(toilet.lamp.clone()),
+            Box::new(kitchen.beam.clone()),
+            Box::new(kitchen.kitchen_lamp.clone()),
+            Box::new(balcony.lamp.clone()),
+            Box::new(living_room.chandelier.clone()),
+            Box::new(living_room.beam.clone()),
+            Box::new(living_room.cupboard_lamp.clone()),
+        ];
+        let all_devices = DeviceGroup::new(io, "all_devices", Aggregate::AnyOn, all_devices);
+        let all_beams: Vec<Box<dyn Member>> = vec![
+            Box::new(living_room.beam.clone()),
+            Box::new(corridor.beam.clone()),
+            Box::new(kitchen.beam.clone()),
+        ];
+        DeviceGroup::new(io, "all_beams", Aggregate::AnyOn, all_beams);
+        let kitchen_lights: Vec<Box<dyn Member>> = vec![
+            Box::new(kitchen.beam.clone()),
+            Box::new(kitchen.kitchen_lamp.clone()),
+        ];
+        DeviceGroup::new(io, "kitchen_lights", Aggregate::AnyOn, kitchen_lights);
+        for (id, room, name) in DEVICES.iter() {
+            log_error!(io.describe(id, DeviceMeta::new(*room, name)));
+        }
+        let led_presets = LedPresets::new(storage.clone());
+        log_error!(led_presets.register(config));
+        let dimmers = vec![
+            bathroom.lamp.clone(),
+            toilet.lamp.clone(),
+            kitchen.kitchen_lamp.clone(),
+            corridor.lamp.clone(),
+        ];
+        log_error!(DimmerCalibrations::new(storage.clone(), dimmers).register(config));
+        let switchable: Vec<Box<dyn Member>> = vec![
+            Box::new(bathroom.lamp.clone()),
+            Box::new(bathroom.fun.clone()),
+            Box::new(toilet.lamp.clone()),
+            Box::new(toilet.fun.clone()),
+        ];
+        let followers = Followers::new(io.rt(), storage.clone(), switchable, Home::followers());
+        log_error!(followers.register(config));
+
+        Home {
+            bad_room,
+            living_room,
+            kitchen,
+            balcony,
+            corridor,
+            toilet,
+            bathroom,
+            all_devices,
+            led_presets,
+            followers,
             scripts: Arc::new(scripts::scripts()),
-        };
+        }
+    }
+}
+
+impl Home {
+    /// Devices which must not come back on after a restart, editable as the power_on config.
+    pub fn power_on_policies() -> HashMap<String, PowerOnPolicy> {
+        ["bathroom_lamp", "toilet_lamp", "toilet_fun", "return_water"]
+            .iter()
+            .map(|id| (id.to_string(), PowerOnPolicy::Off))
+            .collect()
+    }
 
-        home
+    /// Fans run with the lamps and air the room out after a long visit.
+    fn followers() -> BTreeMap<String, Follow> {
+        let mut followers = BTreeMap::new();
+        followers.insert(
+            "toilet_fun".to_owned(),
+            Follow::new("toilet_lamp", 0, 3 * 60, 30),
+        );
+        followers.insert(
+            "bathroom_fun".to_owned(),
+            Follow::new("bathroom_lamp", 60, 5 * 60, 5 * 60),
+        );
+        followers
     }
 }
 
@@ -52,6 +184,21 @@ impl Runner for Home {
         self.scripts
             .get(name)
             .ok_or_else(|| Error::msg(format!("Unknown script: {}", name)))
-            .and_then(|script| script.run(self, value))
+            .and_then(|script| Source::Script.run(|| script.run(self, value)))
+    }
+}
+
+#[cfg(test)]
+impl Home {
+    /// Home wired to a Recorder instead of the real transports, sensors included.
+    pub fn with_recorder() -> (Home, IO, Recorder) {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        io.add_sensor_source(recorder.clone());
+        let home = Home::new(&mut io, &Configuration::default(), &Storage::temp());
+        let io = io.freeze();
+        io.route_sensors(&home);
+        recorder.clear();
+        (home, io, recorder)
     }
 }
@@ -26,7 +26,7 @@ impl Balcony {
 
     fn on_balcony_switch_2(home: &Home) -> Result<()> {
         let lamp = &home.kitchen.kitchen_lamp;
-        lamp.set_power(1);
+        lamp.set_power(1)?;
         lamp.toggle()
     }
 }
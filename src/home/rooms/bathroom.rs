@@ -1,36 +1,74 @@
-use crate::devices::{SerialDimmer, SerialSwitch, Switch as SwitchTrait, WebSwitch};
+use crate::devices::{SerialDimmer, SerialSwitch, Switch as SwitchTrait, Valve, ValveSettings};
 use crate::home::Home;
 use crate::io::IOMut;
-use crate::log_error;
-use crate::sensors::Switch;
+use crate::sensors::{AnalogSensor, Switch};
 use anyhow::Result;
 
+/// The return pump circulates hot water at most this long at a time.
+const RETURN_PUMP_SECS: u64 = 30 * 60;
+
 #[derive(Debug)]
 pub struct Bathroom {
     pub lamp: SerialDimmer,
     pub fun: SerialSwitch,
-    pub hot_water: WebSwitch,
-    pub cold_water: WebSwitch,
-    pub return_water: WebSwitch,
+    pub hot_water: Valve,
+    pub cold_water: Valve,
+    pub return_water: Valve,
     pub switch: Switch,
 }
 
 impl Bathroom {
     pub fn new(io: &mut IOMut) -> Bathroom {
-        let lamp = SerialDimmer::new(io, "bathroom_lamp", 0x01, 20, 100);
-        log_error!(lamp.switch(false));
-
+        let leak = AnalogSensor::new(io, "bathroom_leak");
         Bathroom {
-            lamp,
+            lamp: SerialDimmer::new(io, "bathroom_lamp", 0x01, 20, 100),
             fun: SerialSwitch::new(io, "bathroom_fun", 0x04),
-            hot_water: WebSwitch::new(io, "hot_water"),
-            cold_water: WebSwitch::new(io, "cold_water"),
-            return_water: WebSwitch::new(io, "return_water"),
+            hot_water: Valve::new(
+                io,
+                "hot_water",
+                Some(leak.clone()),
+                ValveSettings::main_supply(),
+            ),
+            cold_water: Valve::new(
+                io,
+                "cold_water",
+                Some(leak.clone()),
+                ValveSettings::main_supply(),
+            ),
+            return_water: Valve::new(
+                io,
+                "return_water",
+                Some(leak),
+                ValveSettings::pump(RETURN_PUMP_SECS),
+            ),
             switch: Switch::toggle(io, "bathroom", Bathroom::on_switch),
         }
     }
 
     fn on_switch(home: &Home) -> Result<()> {
-        home.bathroom.lamp.toggle()
+        home.bathroom.lamp.toggle()?;
+        home.followers.sync();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::{SafetyError, Switch};
+    use crate::home::Home;
+    use crate::sensors::ActionType;
+
+    #[test]
+    fn test_sensors() {
+        let (home, _io, recorder) = Home::with_recorder();
+
+        recorder.press("bathroom", ActionType::Toggle);
+        assert!(home.bathroom.lamp.is_on());
+        let cmd = recorder.serial()[0];
+        assert_eq!((cmd.cmd_type(), cmd.id()), (0x01, 0x01));
+
+        recorder.measure("bathroom_leak", 1.0);
+        let err = home.bathroom.hot_water.switch(true).unwrap_err();
+        assert!(err.downcast_ref::<SafetyError>().is_some());
     }
 }
@@ -111,7 +111,7 @@ impl Corridor {
     fn ir_handler(home: &Home, is_on: bool, sensor_name: SensorName) -> Result<()> {
         if is_on {
             let power = Corridor::calc_power(home, sensor_name);
-            home.corridor.lamp.set_power(power);
+            home.corridor.lamp.set_power(power)?;
             home.corridor.lamp.switch(is_on)
         } else {
             home.corridor.lamp.switch(is_on)
@@ -23,7 +23,7 @@ impl Kitchen {
     }
 
     fn on_kitchen_switch_1(home: &Home) -> Result<()> {
-        home.kitchen.kitchen_lamp.set_power(100);
+        home.kitchen.kitchen_lamp.set_power(100)?;
         home.kitchen.kitchen_lamp.toggle()
     }
 
@@ -3,7 +3,7 @@ use crate::home::scripts::switch::SWITCH_OFF_ALL;
 use crate::home::scripts::{Runner, Script};
 use crate::home::Home;
 use crate::sensors::ActionType;
-use anyhow::Result;
+use anyhow::{Error, Result};
 use serde_json::Value;
 use std::collections::HashMap;
 
@@ -15,19 +15,19 @@ pub fn scheme_scripts(scripts: &mut HashMap<String, Script>) {
     scripts.insert("color_scheme".to_owned(), Script::new(color_scheme));
 }
 
-fn all_beam(home: &Home, spot: Option<bool>, led: Option<LedState>) {
-    home.living_room.beam.channel_1(spot, led);
-    home.living_room.beam.channel_2(spot, led);
+fn all_beam(home: &Home, spot: Option<bool>, led: Option<LedState>) -> Result<()> {
+    home.living_room.beam.channel_1(spot, led)?;
+    home.living_room.beam.channel_2(spot, led)?;
 
-    home.corridor.beam.channel_1(spot, led);
-    home.corridor.beam.channel_2(spot, led);
+    home.corridor.beam.channel_1(spot, led)?;
+    home.corridor.beam.channel_2(spot, led)?;
 
-    home.kitchen.beam.channel_1(spot, led);
-    home.kitchen.beam.channel_2(spot, led);
+    home.kitchen.beam.channel_1(spot, led)?;
+    home.kitchen.beam.channel_2(spot, led)
 }
 
 fn default_color_scheme(home: &Home, _value: Value) -> Result<()> {
-    all_beam(home, Some(true), Some(LedState::default()));
+    all_beam(home, Some(true), Some(LedState::default()))?;
     home.corridor.enable_ir();
 
     if home.living_room.beam.is_on() {
@@ -46,6 +46,16 @@ fn default_color_scheme(home: &Home, _value: Value) -> Result<()> {
 
 fn color_scheme(home: &Home, value: Value) -> Result<()> {
     let scheme: ColorScheme = serde_json::from_value(value)?;
+    let led = match (scheme.led_mod, &scheme.preset) {
+        (Some(_), Some(_)) => {
+            return Err(Error::msg("led_mod and preset can't be used together"));
+        }
+        (led, None) => led,
+        (None, Some(preset)) => Some(LedState {
+            is_on: true,
+            mode: home.led_presets.get(preset)?,
+        }),
+    };
 
     if let Some(enable_ir) = scheme.enable_ir {
         if enable_ir {
@@ -56,7 +66,7 @@ fn color_scheme(home: &Home, value: Value) -> Result<()> {
         }
     }
 
-    all_beam(home, scheme.is_spot_on, scheme.led_mod);
+    all_beam(home, scheme.is_spot_on, led)?;
 
     if scheme.switch_to {
         home.run_script(SWITCH_OFF_ALL, Value::Null)?;
@@ -72,7 +82,76 @@ fn color_scheme(home: &Home, value: Value) -> Result<()> {
 #[derive(Debug, Serialize, Deserialize)]
 struct ColorScheme {
     led_mod: Option<LedState>,
+    /// Name of the led preset.
+    #[serde(default)]
+    preset: Option<String>,
     is_spot_on: Option<bool>,
     enable_ir: Option<bool>,
     switch_to: bool,
 }
+
+#[cfg(test)]
+mod test {
+    use crate::devices::Switch;
+    use crate::home::scripts::Runner;
+    use crate::home::Home;
+    use crate::io::{Call, Cmd};
+
+    #[test]
+    fn test_color_scheme() {
+        let (home, _io, recorder) = Home::with_recorder();
+        home.run_script(
+            "color_scheme",
+            json!({
+                "led_mod": {"is_on": true, "mode": {"Color": [255, 0, 0]}},
+                "is_spot_on": false,
+                "enable_ir": false,
+                "switch_to": true
+            }),
+        )
+        .unwrap();
+
+        let calls = recorder.calls();
+        assert_eq!(calls[0], Call::Serial(Cmd::new(0x01, 0x03, 255)));
+
+        let on = vec!["OFF:ON:color:255:0:0".to_owned(); 2];
+        assert_eq!(
+            recorder.last_sent("lounge_beam").map(|cmd| cmd.args),
+            Some(on.clone())
+        );
+        assert_eq!(
+            recorder.last_sent("corridor_beam").map(|cmd| cmd.args),
+            Some(on.clone())
+        );
+        assert_eq!(
+            recorder.last_sent("kitchen_beam").map(|cmd| cmd.args),
+            Some(on)
+        );
+        assert!(home.living_room.beam.is_on());
+        assert!(!home.living_room.chandelier.is_on());
+        assert!(!home.corridor.lamp.is_on());
+    }
+
+    #[test]
+    fn test_preset_scheme() {
+        let (home, _io, recorder) = Home::with_recorder();
+        home.kitchen.beam.switch(true).unwrap();
+        home.run_script(
+            "color_scheme",
+            json!({"preset": "warm_noise", "is_spot_on": false, "switch_to": false}),
+        )
+        .unwrap();
+        home.kitchen.beam.switch(true).unwrap();
+        assert_eq!(
+            recorder.last_sent("kitchen_beam").unwrap().args[0],
+            "OFF:ON:noise:0:50:50:245:255:245:255:40"
+        );
+
+        assert!(home
+            .run_script(
+                "color_scheme",
+                json!({"preset": "unknown", "switch_to": false})
+            )
+            .is_err());
+    }
+}
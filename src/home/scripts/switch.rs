@@ -13,31 +13,58 @@ pub fn switch_scripts(scripts: &mut HashMap<String, Script>) {
 }
 
 pub fn switch_off_all(home: &Home, _value: Value) -> Result<()> {
-    let corridor = &home.corridor;
-    log_error!(corridor.beam.switch(false));
-
-    let bad_room = &home.bad_room;
-    log_error!(bad_room.chandelier.switch(false));
-
-    let bathroom = &home.bathroom;
-    log_error!(bathroom.lamp.switch(false));
-    log_error!(bathroom.fun.switch(false));
-
-    let toilet = &home.toilet;
-    log_error!(toilet.fun.switch(false));
-    log_error!(toilet.lamp.switch(false));
+    log_error!(home.all_devices.switch(false));
+    Ok(())
+}
 
-    let kitchen = &home.kitchen;
-    log_error!(kitchen.beam.switch(false));
-    log_error!(kitchen.kitchen_lamp.switch(false));
+#[cfg(test)]
+mod test {
+    use crate::devices::Switch;
+    use crate::home::scripts::{Runner, SWITCH_OFF_ALL};
+    use crate::home::Home;
+    use crate::io::{Call, Cmd, WebCmd};
+    use serde_json::Value;
 
-    let balcony = &home.balcony;
-    log_error!(balcony.lamp.switch(false));
+    fn send(id: &str) -> Call {
+        let off = "OFF:OFF:rainbow:100:100".to_owned();
+        let state = json!({"spot": false, "led": false, "mode": {"Rainbow": [100, 100]}});
+        Call::Send {
+            id: id.to_owned(),
+            cmd: WebCmd::new(
+                vec![off.clone(), off],
+                json!({"channel_1": state, "channel_2": state}),
+            ),
+        }
+    }
 
-    let living_room = &home.living_room;
-    log_error!(living_room.chandelier.switch(false));
-    log_error!(living_room.beam.switch(false));
-    log_error!(living_room.cupboard_lamp.switch(false));
+    #[test]
+    fn test_switch_off_all() {
+        let (home, _io, recorder) = Home::with_recorder();
+        home.living_room.chandelier.switch(true).unwrap();
+        home.kitchen.beam.switch(true).unwrap();
+        home.toilet.lamp.switch(true).unwrap();
+        recorder.clear();
 
-    Ok(())
+        home.run_script(SWITCH_OFF_ALL, Value::Null).unwrap();
+        assert_eq!(
+            recorder.calls(),
+            vec![
+                send("corridor_beam"),
+                Call::Serial(Cmd::new(0x02, 0x01, 0x02)),
+                Call::Serial(Cmd::new(0x01, 0x01, 255)),
+                Call::Serial(Cmd::new(0x02, 0x04, 0x02)),
+                Call::Serial(Cmd::new(0x02, 0x03, 0x02)),
+                Call::Serial(Cmd::new(0x01, 0x02, 255)),
+                send("kitchen_beam"),
+                Call::Serial(Cmd::new(0x01, 0x04, 255)),
+                Call::Serial(Cmd::new(0x02, 0x05, 0x02)),
+                Call::Serial(Cmd::new(0x02, 0x02, 0x02)),
+                send("lounge_beam"),
+                Call::Serial(Cmd::new(0x02, 0x06, 0x02)),
+            ]
+        );
+        assert!(!home.living_room.chandelier.is_on());
+        assert!(!home.kitchen.beam.is_on());
+        assert!(!home.toilet.lamp.is_on());
+    }
 }
@@ -0,0 +1,255 @@
+use crate::io::web::SendError;
+use crate::io::{Cmd, Output, WebCmd, WebState};
+use anyhow::Result;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use dashmap::DashMap;
+use std::cell::Cell;
+use std::fmt::{Debug, Error as FmtError, Formatter};
+use std::sync::Arc;
+
+/// First retry delay of an undelivered state, doubled after every failure.
+const RETRY_DELAY_SECS: i64 = 5;
+const MAX_RETRY_DELAY_SECS: i64 = 5 * 60;
+/// Undelivered states older than this are dropped, the device is likely gone for good.
+const MAX_PENDING_SECS: i64 = 60 * 60;
+
+thread_local! {
+    static TRANSIENT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs the action with every web command treated as transient, e.g. a frame of an animation.
+pub fn transient<R, A: FnOnce() -> R>(action: A) -> R {
+    let _restore = RestoreTransient(TRANSIENT.with(|transient| transient.replace(true)));
+    action()
+}
+
+/// Puts the previous mode back, even if the action panics.
+struct RestoreTransient(bool);
+
+impl Drop for RestoreTransient {
+    fn drop(&mut self) {
+        TRANSIENT.with(|transient| transient.set(self.0));
+    }
+}
+
+///
+/// Last desired state of a web device which could not be delivered.
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pending {
+    pub cmd: WebCmd,
+    pub since: DateTime<Utc>,
+    pub failures: u32,
+    pub next_retry: DateTime<Utc>,
+}
+
+impl Pending {
+    fn retry_delay(failures: u32) -> ChronoDuration {
+        let delay = RETRY_DELAY_SECS.saturating_mul(1 << failures.min(16));
+        ChronoDuration::seconds(delay.min(MAX_RETRY_DELAY_SECS))
+    }
+
+    fn expired(&self, now: DateTime<Utc>) -> bool {
+        (now - self.since).num_seconds() >= MAX_PENDING_SECS
+    }
+}
+
+///
+/// Output which keeps the last undelivered state of every web device and
+/// replays it when the device registers again or a retry succeeds.
+/// Web devices always get the full state, so only the last args are kept.
+/// Only states the device may accept later are kept, and for MAX_PENDING_SECS at most.
+/// Transient commands are never kept.
+///
+#[derive(Clone)]
+pub struct OfflineBuffer {
+    output: Arc<dyn Output + Send + Sync>,
+    pending: Arc<DashMap<String, Pending>>,
+}
+
+impl OfflineBuffer {
+    pub fn new(output: Arc<dyn Output + Send + Sync>) -> OfflineBuffer {
+        OfflineBuffer {
+            output,
+            pending: Default::default(),
+        }
+    }
+
+    pub fn pending(&self, id: &str) -> Option<Pending> {
+        self.pending.get(id).map(|pending| pending.clone())
+    }
+
+    /// Re-sends the undelivered state of the device, if any.
+    pub fn replay(&self, id: &str) -> Result<()> {
+        match self.pending(id) {
+            Some(pending) if pending.expired(Utc::now()) => {
+                warn!(
+                    "Drop state of web device {}, undelivered since {}",
+                    id, pending.since
+                );
+                self.pending.remove(id);
+                Ok(())
+            }
+            Some(pending) => {
+                info!("Replay state of web device {}", id);
+                self.send(id, pending.cmd)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Replays every state whose retry time has come.
+    pub fn retry(&self) {
+        let now = Utc::now();
+        let due = self
+            .pending
+            .iter()
+            .filter(|pending| pending.next_retry <= now)
+            .map(|pending| pending.key().to_owned())
+            .collect::<Vec<_>>();
+        for id in due {
+            if let Err(err) = self.replay(&id) {
+                debug!("Web device {} is still offline: {}", id, err);
+            }
+        }
+    }
+
+    fn hold(&self, id: &str, cmd: WebCmd) {
+        let now = Utc::now();
+        let mut pending = self.pending.entry(id.to_owned()).or_insert(Pending {
+            cmd: cmd.clone(),
+            since: now,
+            failures: 0,
+            next_retry: now,
+        });
+        pending.cmd = cmd;
+        pending.next_retry = now + Pending::retry_delay(pending.failures);
+        pending.failures += 1;
+    }
+}
+
+impl Output for OfflineBuffer {
+    fn serial_write(&self, cmd: Cmd) -> Result<()> {
+        self.output.serial_write(cmd)
+    }
+
+    fn send(&self, id: &str, cmd: WebCmd) -> Result<()> {
+        match self.output.send(id, cmd.clone()) {
+            Ok(()) => {
+                if self.pending.remove(id).is_some() {
+                    info!("Web device {} is back online", id);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                let retry = err
+                    .downcast_ref::<SendError>()
+                    .is_some_and(SendError::retry);
+                if retry && !cmd.transient && !TRANSIENT.with(Cell::get) {
+                    self.hold(id, cmd);
+                } else {
+                    // Replaying the state won't help or comes too late, and the older one is stale.
+                    self.pending.remove(id);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn query(&self, id: &str) -> Result<Option<WebState>> {
+        self.output.query(id)
+    }
+}
+
+impl Debug for OfflineBuffer {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "OfflineBuffer {{ pending: {} }}", self.pending.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::{Control, Switch, WebSwitch};
+    use crate::io::buffer::{transient, OfflineBuffer};
+    use crate::io::{Input, Output, Recorder, WebCmd, IO};
+    use crate::runtime::Runtime;
+    use chrono::{Duration as ChronoDuration, Utc};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn wait_for<F: Fn() -> bool>(cond: F) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if cond() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn test_replay_on_registration() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        let valve = WebSwitch::new(&mut io, "hot_water");
+        let io = io.freeze();
+
+        recorder.set_offline("hot_water");
+        assert!(valve.switch(true).is_err());
+        assert!(valve.switch(false).is_err());
+        let pending = io.pending("hot_water").unwrap();
+        assert_eq!(pending.cmd.args, vec!["OFF:100".to_owned()]);
+        assert_eq!(pending.failures, 2);
+
+        recorder.set_online("hot_water");
+        io.reg_web_devices(vec!["hot_water".to_owned()], "192.168.0.10:80".to_owned())
+            .unwrap();
+        assert!(wait_for(|| !recorder.sent("hot_water").is_empty()));
+        assert_eq!(recorder.sent("hot_water"), vec![pending.cmd]);
+        assert!(io.pending("hot_water").is_none());
+    }
+
+    #[test]
+    fn test_replay_when_reachable() {
+        let recorder = Recorder::new();
+        let mut io = IO::with_output(&Runtime::new(1), recorder.clone());
+        let valve = WebSwitch::new(&mut io, "cold_water");
+        let io = io.freeze();
+
+        recorder.set_offline("cold_water");
+        assert!(valve.switch(true).is_err());
+        assert!(valve.reconcile().is_err());
+        assert_eq!(io.pending("cold_water").unwrap().failures, 2);
+
+        recorder.set_online("cold_water");
+        valve.reconcile().unwrap();
+        assert_eq!(
+            recorder.sent("cold_water")[0].args,
+            vec!["ON:100".to_owned()]
+        );
+        assert!(io.pending("cold_water").is_none());
+    }
+
+    #[test]
+    fn test_drop_undeliverable() {
+        let recorder = Recorder::new();
+        let buffer = OfflineBuffer::new(Arc::new(recorder.clone()));
+        let cmd = WebCmd::new(vec!["ON".to_owned()], json!({"is_on": true}));
+
+        recorder.set_rejecting("beam");
+        assert!(buffer.send("beam", cmd.clone()).is_err());
+        assert!(buffer.pending("beam").is_none());
+
+        recorder.set_offline("beam");
+        assert!(transient(|| buffer.send("beam", cmd.clone())).is_err());
+        assert!(buffer.pending("beam").is_none());
+        assert!(buffer.send("beam", cmd.clone()).is_err());
+        buffer.pending.get_mut("beam").unwrap().since = Utc::now() - ChronoDuration::hours(2);
+        recorder.set_online("beam");
+        buffer.replay("beam").unwrap();
+        assert!(buffer.pending("beam").is_none());
+        assert!(recorder.sent("beam").is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::io::registration::{RegistrationError, PROTOCOL_VERSION};
+use crate::io::web::{Transport, WebChannel};
+use anyhow::{Error, Result};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::env;
+use std::fmt::{Debug, Error as FmtError, Formatter};
+use std::sync::Arc;
+use std::thread;
+
+/// Enables mDNS discovery if set.
+const MDNS_DISCOVERY: &str = "MDNS_DISCOVERY";
+/// Service advertised by web devices. TXT: ids=id_1:id_2
+/// The TXT record is not signed, so only registered devices are followed to a new host.
+pub const DEVICE_SERVICE: &str = "_odin._tcp.local.";
+/// Service advertised by the controller. TXT: api=/odin/api, protocol={PROTOCOL_VERSION}
+pub const CONTROLLER_SERVICE: &str = "_odin-ctrl._tcp.local.";
+const CONTROLLER_INSTANCE: &str = "odin";
+const CONTROLLER_HOST: &str = "odin-controller.local.";
+const CONTROLLER_PORT: u16 = 1884;
+const API_PATH: &str = "/odin/api";
+
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    pub device_service: String,
+    pub controller_service: String,
+    pub controller_port: u16,
+    /// Also browse and advertise on the loopback interface.
+    pub loopback: bool,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            device_service: DEVICE_SERVICE.to_owned(),
+            controller_service: CONTROLLER_SERVICE.to_owned(),
+            controller_port: CONTROLLER_PORT,
+            loopback: false,
+        }
+    }
+}
+
+///
+/// Browses the LAN for registered web devices and updates their hosts in the WebChannel.
+/// Advertises the controller so devices can find the registration api.
+///
+#[derive(Clone)]
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    clones: Arc<()>,
+}
+
+impl Discovery {
+    pub fn from_env(web: &WebChannel) -> Option<Discovery> {
+        env::var(MDNS_DISCOVERY).ok()?;
+        match Discovery::start(web, DiscoveryConfig::default()) {
+            Ok(discovery) => Some(discovery),
+            Err(err) => {
+                error!("Failed to start mdns discovery: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn start(web: &WebChannel, config: DiscoveryConfig) -> Result<Discovery> {
+        let daemon = ServiceDaemon::new().map_err(mdns_error)?;
+        if config.loopback {
+            daemon
+                .enable_interface(IfKind::LoopbackV4)
+                .map_err(mdns_error)?;
+        }
+
+        let properties = [
+            ("api", API_PATH.to_owned()),
+            ("protocol", PROTOCOL_VERSION.to_string()),
+        ];
+        let controller = ServiceInfo::new(
+            &config.controller_service,
+            CONTROLLER_INSTANCE,
+            CONTROLLER_HOST,
+            "",
+            config.controller_port,
+            &properties[..],
+        )
+        .map_err(mdns_error)?
+        .enable_addr_auto();
+        daemon.register(controller).map_err(mdns_error)?;
+
+        let events = daemon.browse(&config.device_service).map_err(mdns_error)?;
+        let web = web.clone();
+        thread::spawn(move || {
+            for event in events {
+                match event {
+                    ServiceEvent::ServiceResolved(info) => on_resolved(&web, &info),
+                    ServiceEvent::ServiceRemoved(_, name) => {
+                        debug!("mdns service removed: {}", name)
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        info!("mdns discovery started for {}", config.device_service);
+        Ok(Discovery {
+            daemon,
+            clones: Arc::new(()),
+        })
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        // The daemon is shared between clones, only the last one stops it.
+        if Arc::strong_count(&self.clones) == 1 {
+            let _ = self.daemon.shutdown();
+        }
+    }
+}
+
+impl Debug for Discovery {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "Discovery")
+    }
+}
+
+fn on_resolved(web: &WebChannel, info: &ServiceInfo) {
+    let ids: Vec<String> = match info.get_property_val_str("ids") {
+        Some(ids) if !ids.is_empty() => ids.split(':').map(ToOwned::to_owned).collect(),
+        _ => {
+            warn!("mdns service {} has no ids", info.get_fullname());
+            return;
+        }
+    };
+    let addr = match info.get_addresses_v4().into_iter().next() {
+        Some(addr) => *addr,
+        None => {
+            warn!("mdns service {} has no ipv4 address", info.get_fullname());
+            return;
+        }
+    };
+    let host = format!("{}:{}", addr, info.get_port());
+
+    let (ids, unknown): (Vec<_>, Vec<_>) = ids.into_iter().partition(|id| web.host(id).is_some());
+    if !unknown.is_empty() {
+        debug!("skip discovered devices {:?}: not registered", unknown);
+    }
+    if ids.is_empty() {
+        return;
+    }
+    info!("discovered web device id:{:?}, host: {}", ids, host);
+    if let Err(err) = web.reg_device(ids, host, Transport::Http) {
+        match err.downcast_ref::<RegistrationError>() {
+            // Discovery can't prove the identity of the device.
+            Some(RegistrationError::AuthRequired(id)) => {
+                debug!(
+                    "skip discovered device {}: signed registration required",
+                    id
+                )
+            }
+            _ => warn!("Failed to register discovered device: {}", err),
+        }
+    }
+}
+
+fn mdns_error(err: mdns_sd::Error) -> Error {
+    Error::msg(format!("mdns error: {}", err))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::io::discovery::{Discovery, DiscoveryConfig};
+    use crate::io::web::{Transport, WebChannel};
+    use crate::storage::Storage;
+    use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_discovery() {
+        let config = DiscoveryConfig {
+            device_service: format!("_odin-{}._tcp.local.", std::process::id()),
+            controller_service: format!("_odin-ctrl-{}._tcp.local.", std::process::id()),
+            controller_port: 18840,
+            loopback: true,
+        };
+        let web = WebChannel::new(Storage::temp());
+        let ids = vec!["kitchen_beam".to_owned(), "kitchen_switch".to_owned()];
+        web.reg_device(ids, "192.168.0.10:80".to_owned(), Transport::Http)
+            .unwrap();
+        let _discovery = Discovery::start(&web, config.clone()).unwrap();
+
+        // Local responder playing the device.
+        let device = ServiceDaemon::new().unwrap();
+        device.enable_interface(IfKind::LoopbackV4).unwrap();
+        let service = ServiceInfo::new(
+            &config.device_service,
+            "kitchen",
+            "kitchen-device.local.",
+            "127.0.0.1",
+            8080,
+            &[("ids", "kitchen_beam:kitchen_switch:intruder")][..],
+        )
+        .unwrap();
+        device.register(service).unwrap();
+
+        let start = Instant::now();
+        while web.host("kitchen_switch").unwrap() != "127.0.0.1:8080"
+            && start.elapsed() < Duration::from_secs(10)
+        {
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(web.host("kitchen_beam").unwrap(), "127.0.0.1:8080");
+        assert_eq!(web.host("kitchen_switch").unwrap(), "127.0.0.1:8080");
+        assert_eq!(web.transport("kitchen_beam"), Some(Transport::Http));
+        assert!(web.host("intruder").is_none());
+
+        let events = device.browse(&config.controller_service).unwrap();
+        let controller = loop {
+            match events.recv_timeout(Duration::from_secs(10)).unwrap() {
+                ServiceEvent::ServiceResolved(info) => break info,
+                _ => continue,
+            }
+        };
+        assert_eq!(controller.get_port(), 18840);
+        assert_eq!(controller.get_property_val_str("api"), Some("/odin/api"));
+        device.shutdown().unwrap();
+    }
+}
@@ -1,49 +1,175 @@
+mod buffer;
+mod discovery;
+mod mqtt;
+mod protocol;
+#[cfg(test)]
+mod recorder;
+mod registration;
 mod serial;
+mod simulator;
 mod web;
 
-use crate::devices::Control;
+use crate::devices::{Control, DeviceFilter, DeviceInfo, DeviceLock, DeviceMeta, Locks, Source};
+use crate::home::configuration::{ConfigValue, Configuration};
 use crate::home::Home;
+use crate::io::buffer::OfflineBuffer;
+pub use crate::io::buffer::{transient, Pending};
+use crate::io::discovery::Discovery;
+use crate::io::mqtt::MqttChannel;
+pub use crate::io::protocol::{WebCmd, WebState};
+#[cfg(test)]
+pub use crate::io::recorder::{Call, Recorder};
+pub use crate::io::registration::{DeviceRegistration, RegistrationError};
 pub use crate::io::serial::Cmd;
 use crate::io::serial::SerialChannel;
-use crate::io::web::WebChannel;
-use crate::runtime::Runtime;
-use crate::sensors::{ActionType, Switch};
+pub use crate::io::simulator::SerialSimulator;
+pub use crate::io::web::{Transport, WebDeviceInfo};
+use crate::io::web::{WebChannel, WEB_TRANSPORT};
+use crate::log_error;
+use crate::runtime::{Background, Runtime};
+use crate::sensors::{ActionType, AnalogSensor, Switch};
+use crate::storage::Storage;
 use anyhow::{Error, Result};
 use serde_json::Value;
 use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
 use std::fmt::{Debug, Error as FmtError, Formatter};
 use std::sync::Arc;
+use std::time::Duration;
+
+/// Delay before the state is replayed to a registered device, so it can finish its request.
+const REPLAY_DELAY: Duration = Duration::from_millis(500);
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
 
 pub trait Input {
     fn update_device(&self, name: &str, value: Value) -> Result<()>;
-    fn act(&self, home: &Home, sensor_name: &str, action_type: ActionType) -> Result<()>;
-    fn reg_web_devices(&self, ids: Vec<String>, host: String);
-    fn devices_list(&self) -> Vec<String>;
+    fn act(
+        &self,
+        home: &Home,
+        sensor_name: &str,
+        action_type: ActionType,
+        source: Source,
+    ) -> Result<()>;
+    fn update_sensor(&self, sensor_name: &str, value: f64) -> Result<()>;
+    fn reg_web_devices(&self, ids: Vec<String>, host: String) -> Result<()>;
+    fn register_web_device(&self, reg: DeviceRegistration) -> Result<()>;
+    fn web_devices(&self) -> Vec<WebDeviceInfo>;
+    fn update_web_device(
+        &self,
+        id: &str,
+        host: Option<String>,
+        transport: Option<Transport>,
+    ) -> Result<()>;
+    fn forget_web_device(&self, id: &str) -> Result<()>;
+    fn devices_list(&self, filter: &DeviceFilter) -> Vec<DeviceInfo>;
     fn get_device(&self, name: &str) -> Result<Value>;
+    fn lock_device(&self, name: &str, lock: DeviceLock) -> Result<()>;
+    fn unlock_device(&self, name: &str) -> Result<()>;
 }
 
 pub trait Output {
     fn serial_write(&self, cmd: Cmd) -> Result<()>;
-    fn send(&self, id: &str, args: Vec<String>) -> Result<()>;
+    fn send(&self, id: &str, cmd: WebCmd) -> Result<()>;
+    fn query(&self, id: &str) -> Result<Option<WebState>>;
+}
+
+pub type SensorHandler = dyn Fn(&str, ActionType) + Send + Sync + 'static;
+pub type AnalogHandler = dyn Fn(&str, f64) + Send + Sync + 'static;
+
+///
+/// Transport which delivers sensor actions and analog sensor values (mqtt, see Recorder in tests).
+///
+pub trait SensorSource {
+    fn on_sensor(&self, handler: Box<SensorHandler>);
+    fn on_analog(&self, handler: Box<AnalogHandler>);
 }
 
 #[derive(Clone)]
 pub struct IO {
-    serial: SerialChannel,
+    output: OfflineBuffer,
     web: WebChannel,
+    sources: Vec<Arc<dyn SensorSource + Send + Sync>>,
+    // Keeps the mdns daemon alive while the IO is in use.
+    _discovery: Option<Discovery>,
     sensors: Arc<SensorsHolder>,
     devices: Arc<DevicesHolder>,
+    locks: Locks,
     rt: Runtime,
 }
 
 impl IO {
     pub fn with_runtime(rt: &Runtime) -> IOMut {
+        IO::with_serial(rt, SerialChannel::new())
+    }
+
+    /// IO which talks to the serial simulator instead of the real Arduino.
+    pub fn with_simulator(rt: &Runtime, simulator: &SerialSimulator) -> IOMut {
+        IO::with_serial(rt, SerialChannel::with_port_name(simulator.port_name()))
+    }
+
+    /// IO which passes every command to the given output (see Recorder).
+    #[cfg(test)]
+    pub fn with_output<O: Output + Send + Sync + 'static>(rt: &Runtime, output: O) -> IOMut {
+        let storage = Storage::temp();
+        IO::build(
+            rt,
+            Arc::new(output),
+            WebChannel::new(storage.clone()),
+            Locks::load(storage),
+            None,
+            None,
+        )
+    }
+
+    fn with_serial(rt: &Runtime, serial: SerialChannel) -> IOMut {
+        let storage = Storage::from_env();
+        let web = WebChannel::new(storage.clone());
+        let mqtt = MqttChannel::from_env(&web);
+        let channels = Channels {
+            serial,
+            web: web.clone(),
+            mqtt: mqtt.clone(),
+        };
+        let discovery = Discovery::from_env(&web);
+        IO::build(
+            rt,
+            Arc::new(channels),
+            web,
+            Locks::load(storage),
+            mqtt,
+            discovery,
+        )
+    }
+
+    fn build(
+        rt: &Runtime,
+        output: Arc<dyn Output + Send + Sync>,
+        web: WebChannel,
+        locks: Locks,
+        mqtt: Option<MqttChannel>,
+        discovery: Option<Discovery>,
+    ) -> IOMut {
+        let output = OfflineBuffer::new(output);
+        IO::replay_on_register(rt, &output, &web);
+        let retry = output.clone();
+        rt.create_task(
+            Arc::new(Box::new(move || retry.retry())),
+            RETRY_INTERVAL,
+            true,
+            true,
+        );
+
         let io = IO {
-            serial: SerialChannel::new(),
-            web: WebChannel::new(),
+            output,
+            web,
+            sources: mqtt
+                .into_iter()
+                .map(|mqtt| Arc::new(mqtt) as Arc<dyn SensorSource + Send + Sync>)
+                .collect(),
+            _discovery: discovery,
             sensors: Default::default(),
             devices: Default::default(),
+            locks,
             rt: rt.clone(),
         };
 
@@ -54,6 +180,33 @@ impl IO {
         }
     }
 
+    fn replay_on_register(rt: &Runtime, output: &OfflineBuffer, web: &WebChannel) {
+        let rt = rt.clone();
+        let output = output.clone();
+        web.on_register(move |ids| {
+            let ids = ids
+                .iter()
+                .filter(|id| output.pending(id).is_some())
+                .cloned()
+                .collect::<Vec<_>>();
+            if ids.is_empty() {
+                return;
+            }
+            let output = output.clone();
+            let replay = move || {
+                for id in &ids {
+                    log_error!(output.replay(id));
+                }
+            };
+            rt.create_task(Arc::new(Box::new(replay)), REPLAY_DELAY, true, false);
+        });
+    }
+
+    /// Undelivered state of the web device.
+    pub fn pending(&self, id: &str) -> Option<Pending> {
+        self.output.pending(id)
+    }
+
     pub fn device_holder(&self) -> &DevicesHolder {
         self.devices.as_ref()
     }
@@ -61,15 +214,94 @@ impl IO {
     pub fn runtime(&self) -> &Runtime {
         &self.rt
     }
+
+    pub fn locks(&self) -> &Locks {
+        &self.locks
+    }
+
+    /// Starts the control loops of the devices, they run while the handles are kept.
+    pub fn start_devices(&self) -> Vec<Background> {
+        self.devices
+            .devices()
+            .values()
+            .filter_map(|dev| dev.start(&self.rt))
+            .collect()
+    }
+
+    /// Routes sensor actions and values received over mqtt to the home.
+    pub fn route_sensors(&self, home: &Home) {
+        for source in &self.sources {
+            let io = self.clone();
+            let home = home.clone();
+            source.on_sensor(Box::new(move |sensor, action| {
+                log_error!(io.act(&home, sensor, action, Source::Switch));
+            }));
+            let io = self.clone();
+            source.on_analog(Box::new(move |sensor, value| {
+                log_error!(io.update_sensor(sensor, value));
+            }));
+        }
+    }
+
+    pub fn register_config(&self, config: &Configuration) -> Result<()> {
+        let settings = self.web.settings();
+        config.add(
+            WEB_TRANSPORT,
+            ConfigValue::new(settings.get(), settings.clone())?,
+        );
+        Ok(())
+    }
 }
 
 impl Output for IO {
+    fn serial_write(&self, cmd: Cmd) -> Result<()> {
+        self.output.serial_write(cmd)
+    }
+
+    fn send(&self, id: &str, cmd: WebCmd) -> Result<()> {
+        self.output.send(id, cmd)
+    }
+
+    fn query(&self, id: &str) -> Result<Option<WebState>> {
+        self.output.query(id)
+    }
+}
+
+///
+/// Real transports: serial port, http and mqtt web devices.
+///
+#[derive(Clone)]
+struct Channels {
+    serial: SerialChannel,
+    web: WebChannel,
+    mqtt: Option<MqttChannel>,
+}
+
+impl Channels {
+    fn mqtt(&self) -> Result<&MqttChannel> {
+        self.mqtt
+            .as_ref()
+            .ok_or_else(|| Error::msg("Mqtt transport is not configured"))
+    }
+}
+
+impl Output for Channels {
     fn serial_write(&self, cmd: Cmd) -> Result<()> {
         self.serial.send(cmd)
     }
 
-    fn send(&self, id: &str, args: Vec<String>) -> Result<()> {
-        self.web.send(id, args)
+    fn send(&self, id: &str, cmd: WebCmd) -> Result<()> {
+        match self.web.transport(id) {
+            Some(Transport::Mqtt) => self.mqtt()?.send(id, &cmd),
+            _ => self.web.send(id, &cmd),
+        }
+    }
+
+    fn query(&self, id: &str) -> Result<Option<WebState>> {
+        match self.web.transport(id) {
+            Some(Transport::Mqtt) => self.mqtt()?.query(id),
+            _ => self.web.query(id),
+        }
     }
 }
 
@@ -78,25 +310,71 @@ impl Input for IO {
         self.devices.update_device(name, value)
     }
 
-    fn act(&self, home: &Home, sensor_name: &str, action_type: ActionType) -> Result<()> {
-        self.sensors.act(home, sensor_name, action_type)
+    fn act(
+        &self,
+        home: &Home,
+        sensor_name: &str,
+        action_type: ActionType,
+        source: Source,
+    ) -> Result<()> {
+        self.sensors.act(home, sensor_name, action_type, source)
     }
 
-    fn reg_web_devices(&self, ids: Vec<String>, host: String) {
-        self.web.reg_device(ids, host);
+    fn update_sensor(&self, sensor_name: &str, value: f64) -> Result<()> {
+        self.sensors.update(sensor_name, value)
     }
 
-    fn devices_list(&self) -> Vec<String> {
-        self.devices
-            .devices()
-            .keys()
-            .map(ToOwned::to_owned)
-            .collect()
+    fn reg_web_devices(&self, ids: Vec<String>, host: String) -> Result<()> {
+        self.web.reg_device(ids, host, Transport::Http)
+    }
+
+    fn register_web_device(&self, reg: DeviceRegistration) -> Result<()> {
+        let host = reg
+            .host
+            .clone()
+            .ok_or_else(|| RegistrationError::Invalid("host is required".to_owned()))?;
+        self.web.register(reg, host, Transport::Http)
+    }
+
+    fn web_devices(&self) -> Vec<WebDeviceInfo> {
+        let mut devices = self.web.devices();
+        for dev in &mut devices {
+            dev.pending = self.pending(&dev.id);
+        }
+        devices
+    }
+
+    fn update_web_device(
+        &self,
+        id: &str,
+        host: Option<String>,
+        transport: Option<Transport>,
+    ) -> Result<()> {
+        self.web.update_device(id, host, transport)
+    }
+
+    fn forget_web_device(&self, id: &str) -> Result<()> {
+        self.web.forget_device(id)
+    }
+
+    fn devices_list(&self, filter: &DeviceFilter) -> Vec<DeviceInfo> {
+        self.devices.list(filter)
     }
 
     fn get_device(&self, name: &str) -> Result<Value> {
         self.devices.get_device(name)
     }
+
+    fn lock_device(&self, name: &str, lock: DeviceLock) -> Result<()> {
+        if !self.devices.devices().contains_key(name) {
+            return Err(Error::msg(format!("device {} not found", name)));
+        }
+        self.locks.lock(name, lock)
+    }
+
+    fn unlock_device(&self, name: &str) -> Result<()> {
+        self.locks.unlock(name)
+    }
 }
 
 impl Debug for IO {
@@ -131,10 +409,33 @@ impl IOMut {
         self.sensors.as_mut().insert(switch.id().to_owned(), switch);
     }
 
+    /// Sensor actions and values of the source are routed by `IO::route_sensors`.
+    #[cfg(test)]
+    pub fn add_sensor_source<S: SensorSource + Send + Sync + 'static>(&mut self, source: S) {
+        self.io.sources.push(Arc::new(source));
+    }
+
+    pub fn add_analog_sensor(&mut self, sensor: AnalogSensor) {
+        self.sensors.analog.insert(sensor.id().to_owned(), sensor);
+    }
+
+    pub fn analog_sensor(&self, id: &str) -> Option<AnalogSensor> {
+        self.sensors.analog.get(id).cloned()
+    }
+
     pub fn reg_device(&mut self, device: Box<dyn Control>) {
         self.devices.as_mut().insert(device.id().to_owned(), device);
     }
 
+    /// Sets the room and the name of a registered device.
+    pub fn describe(&mut self, id: &str, meta: DeviceMeta) -> Result<()> {
+        if !self.devices.devices.contains_key(id) {
+            return Err(Error::msg(format!("Can't describe unknown device: {}", id)));
+        }
+        self.devices.meta.insert(id.to_owned(), meta);
+        Ok(())
+    }
+
     pub fn rt(&self) -> &Runtime {
         &self.io.rt
     }
@@ -143,12 +444,19 @@ impl IOMut {
 #[derive(Default)]
 pub struct SensorsHolder {
     sensors: HashMap<String, Switch>,
+    analog: HashMap<String, AnalogSensor>,
 }
 
 impl SensorsHolder {
-    fn act(&self, home: &Home, sensor_name: &str, action_type: ActionType) -> Result<()> {
+    fn act(
+        &self,
+        home: &Home,
+        sensor_name: &str,
+        action_type: ActionType,
+        source: Source,
+    ) -> Result<()> {
         if let Some(switch) = self.sensors.get(sensor_name) {
-            switch.act(home, action_type)
+            source.run(|| switch.act(home, action_type))
         } else {
             Err(Error::msg(format!(
                 "Sensor with name '{}' not found.",
@@ -156,6 +464,18 @@ impl SensorsHolder {
             )))
         }
     }
+
+    fn update(&self, sensor_name: &str, value: f64) -> Result<()> {
+        if let Some(sensor) = self.analog.get(sensor_name) {
+            sensor.set(value);
+            Ok(())
+        } else {
+            Err(Error::msg(format!(
+                "Analog sensor with name '{}' not found.",
+                sensor_name
+            )))
+        }
+    }
 }
 
 impl AsMut<HashMap<String, Switch>> for SensorsHolder {
@@ -167,6 +487,7 @@ impl AsMut<HashMap<String, Switch>> for SensorsHolder {
 #[derive(Default)]
 pub struct DevicesHolder {
     devices: HashMap<String, Box<dyn Control>>,
+    meta: HashMap<String, DeviceMeta>,
 }
 
 impl DevicesHolder {
@@ -174,7 +495,11 @@ impl DevicesHolder {
         self.devices
             .get(name)
             .ok_or_else(|| Error::msg(format!("device {} not found", name)))
-            .and_then(|dev| dev.update(value))
+            .and_then(|dev| {
+                dev.dev_type().schema().validate(&value)?;
+                dev.guard(&value)?;
+                dev.update(value)
+            })
     }
 
     pub fn get_device(&self, name: &str) -> Result<Value> {
@@ -184,6 +509,22 @@ impl DevicesHolder {
             .map(|dev| dev.load())
     }
 
+    /// Devices matching the filter sorted by id.
+    pub fn list(&self, filter: &DeviceFilter) -> Vec<DeviceInfo> {
+        let mut list = self
+            .devices
+            .values()
+            .map(|dev| DeviceInfo::new(dev.as_ref(), self.meta.get(dev.id())))
+            .filter(|info| filter.matches(info))
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| a.id.cmp(&b.id));
+        list
+    }
+
+    pub fn meta(&self, id: &str) -> Option<&DeviceMeta> {
+        self.meta.get(id)
+    }
+
     pub fn devices(&self) -> &HashMap<String, Box<dyn Control>> {
         &self.devices
     }
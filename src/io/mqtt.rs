@@ -0,0 +1,348 @@
+use crate::io::protocol::{WebCmd, WebState};
+use crate::io::registration::DeviceRegistration;
+use crate::io::web::{SendError, Transport, WebChannel};
+use crate::io::{AnalogHandler, SensorHandler, SensorSource};
+use crate::sensors::ActionType;
+use anyhow::{Error, Result};
+use dashmap::DashMap;
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+use std::env;
+use std::fmt::{Debug, Error as FmtError, Formatter};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// Broker address (host:port). MQTT transport is disabled if not set.
+const MQTT_BROKER: &str = "MQTT_BROKER";
+const MQTT_PREFIX: &str = "MQTT_PREFIX";
+const DEFAULT_PREFIX: &str = "odin";
+const DEFAULT_PORT: u16 = 1883;
+
+///
+/// Topics:
+/// {prefix}/devices/{id}/set   - commands to the device {"args": [..]} or {"state": {..}} (v2)
+/// {prefix}/devices/{id}/state - state reported by the device {"args": [..]} or {"state": {..}}
+/// {prefix}/sensors/{sensor}   - sensor actions: On | Off | Toggle
+/// {prefix}/analog/{sensor}    - analog sensor values, e.g. 21.5
+/// {prefix}/register           - signed device registration (see DeviceRegistration)
+///
+#[derive(Clone)]
+pub struct MqttChannel {
+    client: Client,
+    broker: Arc<String>,
+    prefix: Arc<String>,
+    web: WebChannel,
+    reported: Arc<DashMap<String, WebState>>,
+    on_sensor: Arc<RwLock<Option<Box<SensorHandler>>>>,
+    on_analog: Arc<RwLock<Option<Box<AnalogHandler>>>>,
+}
+
+impl MqttChannel {
+    pub fn from_env(web: &WebChannel) -> Option<MqttChannel> {
+        let broker = env::var(MQTT_BROKER).ok()?;
+        let prefix = env::var(MQTT_PREFIX).unwrap_or_else(|_| DEFAULT_PREFIX.to_owned());
+        match MqttChannel::connect(&broker, &prefix, web) {
+            Ok(channel) => Some(channel),
+            Err(err) => {
+                error!("Failed to start mqtt transport: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn connect(broker: &str, prefix: &str, web: &WebChannel) -> Result<MqttChannel> {
+        let (host, port) = parse_broker(broker)?;
+        let mut options = MqttOptions::new(format!("{}-controller", prefix), host, port);
+        options.set_keep_alive(Duration::from_secs(5));
+        let (client, mut connection) = Client::new(options, 10);
+
+        let channel = MqttChannel {
+            client,
+            broker: Arc::new(broker.to_owned()),
+            prefix: Arc::new(prefix.to_owned()),
+            web: web.clone(),
+            reported: Default::default(),
+            on_sensor: Default::default(),
+            on_analog: Default::default(),
+        };
+
+        let loop_channel = channel.clone();
+        thread::spawn(move || {
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to mqtt broker {}", loop_channel.broker);
+                        loop_channel.subscribe();
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        loop_channel.on_message(&publish.topic, &publish.payload);
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!("Mqtt connection error: {}", err);
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                }
+            }
+        });
+
+        Ok(channel)
+    }
+
+    pub fn send(&self, id: &str, cmd: &WebCmd) -> Result<()> {
+        let payload = serde_json::to_vec(&cmd.encode(self.web.protocol(id)))?;
+        self.client
+            .try_publish(
+                format!("{}/devices/{}/set", self.prefix, id),
+                QoS::AtLeastOnce,
+                false,
+                payload,
+            )
+            .map_err(|err| {
+                SendError::new(format!("Failed to publish to {}: {}", id, err), true).into()
+            })
+    }
+
+    /// Last state reported by the device.
+    pub fn query(&self, id: &str) -> Result<Option<WebState>> {
+        Ok(self.reported.get(id).map(|state| state.clone()))
+    }
+
+    fn subscribe(&self) {
+        let topics = vec![
+            format!("{}/devices/+/state", self.prefix),
+            format!("{}/sensors/+", self.prefix),
+            format!("{}/analog/+", self.prefix),
+            format!("{}/register", self.prefix),
+        ];
+        for topic in topics {
+            if let Err(err) = self.client.try_subscribe(topic.as_str(), QoS::AtLeastOnce) {
+                error!("Failed to subscribe to {}: {}", topic, err);
+            }
+        }
+    }
+
+    fn on_message(&self, topic: &str, payload: &[u8]) {
+        let topic = match topic
+            .strip_prefix(self.prefix.as_str())
+            .and_then(|topic| topic.strip_prefix('/'))
+        {
+            Some(topic) => topic,
+            None => return,
+        };
+        let path = topic.split('/').collect::<Vec<_>>();
+
+        match path.as_slice() {
+            ["devices", id, "state"] => match serde_json::from_slice::<WebState>(payload) {
+                Ok(state) => {
+                    self.reported.insert((*id).to_owned(), state);
+                }
+                Err(err) => warn!("Invalid state of {}: {}", id, err),
+            },
+            ["sensors", sensor] => {
+                let action = match payload {
+                    b"On" => ActionType::On,
+                    b"Off" => ActionType::Off,
+                    b"Toggle" => ActionType::Toggle,
+                    _ => {
+                        warn!("Unknown action of sensor {}", sensor);
+                        return;
+                    }
+                };
+                if let Some(handler) = self.on_sensor.read().unwrap().as_ref() {
+                    handler(sensor, action);
+                } else {
+                    warn!("Sensor {} ignored: home is not ready", sensor);
+                }
+            }
+            ["analog", sensor] => {
+                let value = match std::str::from_utf8(payload)
+                    .ok()
+                    .and_then(|value| value.trim().parse::<f64>().ok())
+                {
+                    Some(value) => value,
+                    None => {
+                        warn!("Invalid value of analog sensor {}", sensor);
+                        return;
+                    }
+                };
+                if let Some(handler) = self.on_analog.read().unwrap().as_ref() {
+                    handler(sensor, value);
+                } else {
+                    warn!("Analog sensor {} ignored: home is not ready", sensor);
+                }
+            }
+            ["register"] => match serde_json::from_slice::<DeviceRegistration>(payload) {
+                Ok(reg) => {
+                    info!("reg mqtt device id:{:?}", reg.ids);
+                    let host = self.broker.as_ref().to_owned();
+                    if let Err(err) = self.web.register(reg, host, Transport::Mqtt) {
+                        warn!("Mqtt registration rejected: {}", err);
+                    }
+                }
+                Err(err) => warn!("Invalid mqtt registration: {}", err),
+            },
+            _ => debug!("Unknown mqtt topic: {}", topic),
+        }
+    }
+}
+
+impl SensorSource for MqttChannel {
+    fn on_sensor(&self, handler: Box<SensorHandler>) {
+        *self.on_sensor.write().unwrap() = Some(handler);
+    }
+
+    fn on_analog(&self, handler: Box<AnalogHandler>) {
+        *self.on_analog.write().unwrap() = Some(handler);
+    }
+}
+
+impl Debug for MqttChannel {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "MqttChannel {{ {} }}", self.prefix)
+    }
+}
+
+fn parse_broker(broker: &str) -> Result<(String, u16)> {
+    let mut parts = broker.splitn(2, ':');
+    let host = parts.next().unwrap_or_default().to_owned();
+    let port = match parts.next() {
+        Some(port) => port
+            .parse()
+            .map_err(|_| Error::msg(format!("Invalid mqtt broker port: {}", broker)))?,
+        None => DEFAULT_PORT,
+    };
+    Ok((host, port))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::io::mqtt::MqttChannel;
+    use crate::io::protocol::{WebCmd, WebState};
+    use crate::io::registration::DeviceRegistration;
+    use crate::io::web::{Transport, WebChannel};
+    use crate::io::SensorSource;
+    use crate::sensors::ActionType;
+    use crate::storage::Storage;
+    use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+    use rumqttd::{Broker, Config};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn start_broker() -> u16 {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config: Config = serde_json::from_value(json!({
+            "id": 0,
+            "router": {
+                "max_connections": 10,
+                "max_outgoing_packet_count": 200,
+                "max_segment_size": 104857600,
+                "max_segment_count": 10
+            },
+            "v4": {
+                "1": {
+                    "name": "v4-1",
+                    "listen": format!("127.0.0.1:{}", port),
+                    "next_connection_delay_ms": 1,
+                    "connections": {
+                        "connection_timeout_ms": 60000,
+                        "max_payload_size": 20480,
+                        "max_inflight_count": 100,
+                        "dynamic_filters": true
+                    }
+                }
+            }
+        }))
+        .unwrap();
+        thread::spawn(move || Broker::new(config).start().unwrap());
+        port
+    }
+
+    fn wait_for<F: Fn() -> bool>(cond: F) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(10) {
+            if cond() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn test_mqtt_transport() {
+        let port = start_broker();
+        let web = WebChannel::new(Storage::temp());
+        web.secrets().insert("beam", "secret");
+        let mqtt = MqttChannel::connect(&format!("127.0.0.1:{}", port), "test", &web).unwrap();
+        let (sensor_tx, sensor_rx) = channel();
+        mqtt.on_sensor(Box::new(move |sensor, action| {
+            let action = match action {
+                ActionType::On => "On",
+                ActionType::Off => "Off",
+                ActionType::Toggle => "Toggle",
+            };
+            sensor_tx.send(format!("{}:{}", sensor, action)).unwrap();
+        }));
+
+        let (device, mut connection) =
+            Client::new(MqttOptions::new("test-device", "127.0.0.1", port), 10);
+        device
+            .subscribe("test/devices/beam/set", QoS::AtLeastOnce)
+            .unwrap();
+        let (cmd_tx, cmd_rx) = channel();
+        thread::spawn(move || {
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        let _ = cmd_tx.send(publish.payload.to_vec());
+                    }
+                    Ok(_) => {}
+                    Err(_) => thread::sleep(Duration::from_millis(100)),
+                }
+            }
+        });
+
+        let reg =
+            serde_json::to_vec(&DeviceRegistration::signed(&["beam"], None, "secret")).unwrap();
+        assert!(wait_for(|| {
+            device
+                .publish("test/register", QoS::AtLeastOnce, false, reg.clone())
+                .unwrap();
+            web.transport("beam") == Some(Transport::Mqtt)
+        }));
+
+        device
+            .publish(
+                "test/devices/beam/state",
+                QoS::AtLeastOnce,
+                false,
+                r#"{"args":["ON"]}"#,
+            )
+            .unwrap();
+        assert!(wait_for(
+            || mqtt.query("beam").unwrap() == Some(WebState::Args(vec!["ON".to_owned()]))
+        ));
+
+        device
+            .publish("test/sensors/exit_1", QoS::AtLeastOnce, false, "Toggle")
+            .unwrap();
+        assert_eq!(
+            sensor_rx.recv_timeout(Duration::from_secs(10)).unwrap(),
+            "exit_1:Toggle"
+        );
+
+        let cmd = WebCmd::new(vec!["OFF".to_owned()], json!({"is_on": false}));
+        mqtt.send("beam", &cmd).unwrap();
+        assert_eq!(
+            cmd_rx.recv_timeout(Duration::from_secs(10)).unwrap(),
+            br#"{"state":{"is_on":false}}"#.to_vec()
+        );
+    }
+}
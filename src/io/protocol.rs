@@ -0,0 +1,62 @@
+use serde_json::Value;
+
+/// Legacy protocol: GET http://host/{id}?arg_0=..&arg_1=.. with colon encoded args.
+pub const PROTOCOL_V1: u32 = 1;
+/// POST http://host/v2/{id} with the json state document.
+pub const PROTOCOL_V2: u32 = 2;
+
+///
+/// Command to a web device in the encodings of every protocol.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebCmd {
+    /// Protocol v1 args.
+    pub args: Vec<String>,
+    /// Protocol v2 state document.
+    pub state: Value,
+    /// Valid only at the moment it's sent, never replayed to the device.
+    #[serde(default)]
+    pub transient: bool,
+}
+
+impl WebCmd {
+    pub fn new(args: Vec<String>, state: Value) -> WebCmd {
+        WebCmd {
+            args,
+            state,
+            transient: false,
+        }
+    }
+
+    /// The command, e.g. a motor motion, must not be delivered late.
+    pub fn transient(mut self) -> WebCmd {
+        self.transient = true;
+        self
+    }
+
+    pub fn encode(&self, protocol: u32) -> WebState {
+        if protocol >= PROTOCOL_V2 {
+            WebState::State(self.state.clone())
+        } else {
+            WebState::Args(self.args.clone())
+        }
+    }
+
+    /// Checks that the reported state is the state of this command.
+    pub fn matches(&self, reported: &WebState) -> bool {
+        match reported {
+            WebState::Args(args) => args == &self.args,
+            WebState::State(state) => state == &self.state,
+        }
+    }
+}
+
+///
+/// State of a web device on the wire: {"args": [..]} (v1) or {"state": {..}} (v2).
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebState {
+    Args(Vec<String>),
+    State(Value),
+}
@@ -0,0 +1,153 @@
+use crate::io::web::SendError;
+use crate::io::{AnalogHandler, Cmd, Output, SensorHandler, SensorSource, WebCmd, WebState};
+use crate::sensors::ActionType;
+use anyhow::Result;
+use dashmap::DashMap;
+use std::fmt::{Debug, Error as FmtError, Formatter};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    Serial(Cmd),
+    Send { id: String, cmd: WebCmd },
+}
+
+///
+/// Output which records every command in order instead of sending it.
+/// Query returns the state set by `report`.
+/// As a sensor source it delivers the actions and values given to `press` and `measure`.
+///
+#[derive(Clone, Default)]
+pub struct Recorder {
+    calls: Arc<RwLock<Vec<Call>>>,
+    reported: Arc<DashMap<String, WebState>>,
+    /// Unreachable devices, `true` if they may accept a later retry.
+    offline: Arc<DashMap<String, bool>>,
+    delays: Arc<DashMap<String, Duration>>,
+    on_sensor: Arc<RwLock<Option<Box<SensorHandler>>>>,
+    on_analog: Arc<RwLock<Option<Box<AnalogHandler>>>>,
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder::default()
+    }
+
+    /// All recorded calls in order.
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.read().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.calls.write().unwrap().clear();
+    }
+
+    pub fn serial(&self) -> Vec<Cmd> {
+        self.calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::Serial(cmd) => Some(cmd),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Every command sent to the given web device.
+    pub fn sent(&self, id: &str) -> Vec<WebCmd> {
+        self.calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::Send { id: dev_id, cmd } if dev_id == id => Some(cmd),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn last_sent(&self, id: &str) -> Option<WebCmd> {
+        self.sent(id).pop()
+    }
+
+    /// State the device reports on query.
+    pub fn report(&self, id: &str, state: WebState) {
+        self.reported.insert(id.to_owned(), state);
+    }
+
+    /// Sends to the device fail until `set_online`.
+    pub fn set_offline(&self, id: &str) {
+        self.offline.insert(id.to_owned(), true);
+    }
+
+    /// Sends to the device fail like rejected by the device until `set_online`.
+    pub fn set_rejecting(&self, id: &str) {
+        self.offline.insert(id.to_owned(), false);
+    }
+
+    pub fn set_online(&self, id: &str) {
+        self.offline.remove(id);
+    }
+
+    /// Sends to the device block this long, e.g. an unreachable http device.
+    pub fn set_delay(&self, id: &str, delay: Duration) {
+        self.delays.insert(id.to_owned(), delay);
+    }
+
+    /// Sensor action as if received from the transport, e.g. a wall switch.
+    pub fn press(&self, sensor: &str, action: ActionType) {
+        let handler = self.on_sensor.read().unwrap();
+        handler.as_ref().expect("sensors are not routed")(sensor, action);
+    }
+
+    /// Analog sensor value as if received from the transport.
+    pub fn measure(&self, sensor: &str, value: f64) {
+        let handler = self.on_analog.read().unwrap();
+        handler.as_ref().expect("sensors are not routed")(sensor, value);
+    }
+}
+
+impl SensorSource for Recorder {
+    fn on_sensor(&self, handler: Box<SensorHandler>) {
+        *self.on_sensor.write().unwrap() = Some(handler);
+    }
+
+    fn on_analog(&self, handler: Box<AnalogHandler>) {
+        *self.on_analog.write().unwrap() = Some(handler);
+    }
+}
+
+impl Debug for Recorder {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(
+            f,
+            "Recorder {{ calls: {} }}",
+            self.calls.read().unwrap().len()
+        )
+    }
+}
+
+impl Output for Recorder {
+    fn serial_write(&self, cmd: Cmd) -> Result<()> {
+        self.calls.write().unwrap().push(Call::Serial(cmd));
+        Ok(())
+    }
+
+    fn send(&self, id: &str, cmd: WebCmd) -> Result<()> {
+        let delay = self.delays.get(id).map(|delay| *delay);
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+        if let Some(retry) = self.offline.get(id) {
+            return Err(SendError::new(format!("Web device {} is offline", id), *retry).into());
+        }
+        self.calls.write().unwrap().push(Call::Send {
+            id: id.to_owned(),
+            cmd,
+        });
+        Ok(())
+    }
+
+    fn query(&self, id: &str) -> Result<Option<WebState>> {
+        Ok(self.reported.get(id).map(|state| state.clone()))
+    }
+}
@@ -0,0 +1,205 @@
+use crate::io::protocol::PROTOCOL_V2;
+use crate::storage::Storage;
+use chrono::Utc;
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error as StdError;
+use std::fmt::{Display, Error as FmtError, Formatter};
+use std::sync::Arc;
+
+/// Latest supported web device protocol.
+pub const PROTOCOL_VERSION: u32 = PROTOCOL_V2;
+const DEVICE_SECRETS: &str = "device_secrets";
+const MAX_CLOCK_SKEW_SECS: i64 = 5 * 60;
+
+///
+/// Signed device registration.
+/// signature = hex(HMAC-SHA256(secret, "{id_1:id_2}\n{host}\n{firmware}\n{protocol}\n{timestamp}"))
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceRegistration {
+    pub ids: Vec<String>,
+    /// host:port of the device. Not used by mqtt devices.
+    #[serde(default)]
+    pub host: Option<String>,
+    pub firmware: String,
+    pub protocol: u32,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Unix time in seconds (see v1/time).
+    pub timestamp: i64,
+    pub signature: String,
+}
+
+impl DeviceRegistration {
+    fn message(&self) -> String {
+        format!(
+            "{}\n{}\n{}\n{}\n{}",
+            self.ids.join(":"),
+            self.host.as_deref().unwrap_or_default(),
+            self.firmware,
+            self.protocol,
+            self.timestamp
+        )
+    }
+
+    fn mac(&self, secret: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC can take key of any size");
+        mac.update(self.message().as_bytes());
+        mac
+    }
+
+    pub fn verify(&self, secret: &str) -> bool {
+        match hex::decode(&self.signature) {
+            Ok(signature) => self.mac(secret).verify_slice(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// The latest protocol both the device and the controller support.
+    pub fn negotiated_protocol(&self) -> u32 {
+        self.protocol.min(PROTOCOL_VERSION)
+    }
+
+    /// Checks the payload itself: ids, protocol and timestamp.
+    pub fn validate(&self) -> Result<(), RegistrationError> {
+        if self.ids.is_empty() {
+            return Err(RegistrationError::Invalid("empty ids".to_owned()));
+        }
+        let mut ids = HashSet::new();
+        for id in &self.ids {
+            if id.is_empty() || !ids.insert(id) {
+                return Err(RegistrationError::Invalid(format!("invalid id '{}'", id)));
+            }
+        }
+
+        if self.protocol == 0 {
+            return Err(RegistrationError::UnsupportedProtocol(self.protocol));
+        }
+
+        if (Utc::now().timestamp() - self.timestamp).abs() > MAX_CLOCK_SKEW_SECS {
+            return Err(RegistrationError::Expired);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl DeviceRegistration {
+    pub fn signed(ids: &[&str], host: Option<&str>, secret: &str) -> DeviceRegistration {
+        let mut reg = DeviceRegistration {
+            ids: ids.iter().map(|id| (*id).to_owned()).collect(),
+            host: host.map(ToOwned::to_owned),
+            firmware: "1.0.0".to_owned(),
+            protocol: PROTOCOL_VERSION,
+            capabilities: vec!["status".to_owned()],
+            timestamp: Utc::now().timestamp(),
+            signature: String::new(),
+        };
+        reg.sign(secret);
+        reg
+    }
+
+    pub fn sign(&mut self, secret: &str) {
+        self.signature = hex::encode(self.mac(secret).finalize().into_bytes());
+    }
+}
+
+#[derive(Debug)]
+pub enum RegistrationError {
+    Invalid(String),
+    UnsupportedProtocol(u32),
+    Expired,
+    UnknownDevice(String),
+    InvalidSignature(String),
+    Conflict {
+        id: String,
+        host: String,
+    },
+    /// The device has a secret and must use signed registration.
+    AuthRequired(String),
+    /// The registration is not newer than the last accepted one.
+    Replayed(String),
+}
+
+impl Display for RegistrationError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match self {
+            RegistrationError::Invalid(msg) => write!(f, "Invalid registration: {}", msg),
+            RegistrationError::UnsupportedProtocol(protocol) => {
+                write!(f, "Unsupported protocol version: {}", protocol)
+            }
+            RegistrationError::Expired => write!(f, "Registration timestamp is out of range"),
+            RegistrationError::UnknownDevice(id) => write!(f, "Unknown device: {}", id),
+            RegistrationError::InvalidSignature(id) => write!(f, "Invalid signature for {}", id),
+            RegistrationError::Conflict { id, host } => {
+                write!(f, "Device {} is already registered by {}", id, host)
+            }
+            RegistrationError::AuthRequired(id) => {
+                write!(f, "Device {} requires signed registration", id)
+            }
+            RegistrationError::Replayed(id) => {
+                write!(f, "Registration of {} is not newer than the last one", id)
+            }
+        }
+    }
+}
+
+impl StdError for RegistrationError {}
+
+///
+/// Per-device shared secrets, stored in `device_secrets.json` as {id: secret}.
+///
+#[derive(Debug, Clone, Default)]
+pub struct DeviceSecrets {
+    secrets: Arc<DashMap<String, String>>,
+}
+
+impl DeviceSecrets {
+    pub fn load(storage: &Storage) -> DeviceSecrets {
+        let secrets = DashMap::new();
+        match storage.load::<BTreeMap<String, String>>(DEVICE_SECRETS) {
+            Ok(Some(stored)) => {
+                for (id, secret) in stored {
+                    secrets.insert(id, secret);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => error!("Failed to load device secrets: {}", err),
+        }
+        DeviceSecrets {
+            secrets: Arc::new(secrets),
+        }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.secrets.contains_key(id)
+    }
+
+    /// Checks that every id is known and the registration is signed with its secret.
+    pub fn verify(&self, reg: &DeviceRegistration) -> Result<(), RegistrationError> {
+        for id in &reg.ids {
+            let secret = self
+                .secrets
+                .get(id)
+                .ok_or_else(|| RegistrationError::UnknownDevice(id.to_owned()))?;
+            if !reg.verify(secret.value()) {
+                return Err(RegistrationError::InvalidSignature(id.to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn insert(&self, id: &str, secret: &str) {
+        self.secrets.insert(id.to_owned(), secret.to_owned());
+    }
+
+    #[cfg(test)]
+    pub fn remove(&self, id: &str) {
+        self.secrets.remove(id);
+    }
+}
@@ -17,17 +17,32 @@ const SETTINGS: uart::PortSettings = uart::PortSettings {
 
 pub struct SerialChannel {
     port: Arc<Mutex<Option<TTYPort>>>,
+    port_name: Option<Arc<String>>,
 }
 
 impl SerialChannel {
     pub fn new() -> SerialChannel {
         SerialChannel {
             port: Arc::new(Mutex::new(None)),
+            port_name: None,
+        }
+    }
+
+    /// Channel bound to the given tty instead of the first USB Arduino.
+    pub fn with_port_name(port_name: &str) -> SerialChannel {
+        SerialChannel {
+            port: Arc::new(Mutex::new(None)),
+            port_name: Some(Arc::new(port_name.to_owned())),
         }
     }
 
     pub fn make_port(&self) -> Option<TTYPort> {
-        get_port_name().and_then(|p| match uart::open(&p) {
+        let port_name = match &self.port_name {
+            Some(name) => Some(name.as_ref().to_owned()),
+            None => get_port_name(),
+        };
+
+        port_name.and_then(|p| match uart::open(&p) {
             Ok(mut port) => {
                 if let Err(err) = port.configure(&SETTINGS) {
                     info!("Failed to config port [{}] {:?}", p, err);
@@ -88,11 +103,12 @@ impl Clone for SerialChannel {
     fn clone(&self) -> Self {
         SerialChannel {
             port: self.port.clone(),
+            port_name: self.port_name.clone(),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Cmd {
     _type: u8,
     id: u8,
@@ -103,4 +119,16 @@ impl Cmd {
     pub fn new(_type: u8, id: u8, args: u8) -> Cmd {
         Cmd { _type, id, args }
     }
+
+    pub fn cmd_type(&self) -> u8 {
+        self._type
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn args(&self) -> u8 {
+        self.args
+    }
 }
@@ -0,0 +1,383 @@
+use crate::io::serial::Cmd;
+use anyhow::{Error, Result};
+use libc::{c_char, c_int};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::{mem, ptr};
+
+/// Dimmer command: args is the inverted power (255 - off).
+const DIMMER: u8 = 0x01;
+/// Switch command: args 0x01 - on, 0x02 - off.
+const SWITCH: u8 = 0x02;
+/// Pwm channel of a color light: args is the level.
+const CHANNEL: u8 = 0x03;
+
+const POLL_TIMEOUT_MS: c_int = 100;
+/// Commands kept for inspection, older ones are dropped.
+const MAX_COMMANDS: usize = 1000;
+
+///
+/// Fake Arduino on a pseudo-terminal.
+/// Reads 3 byte frames from the master side, records every command and keeps
+/// the state of the lamps and fans addressed by them.
+///
+#[derive(Debug)]
+pub struct SerialSimulator {
+    port_name: String,
+    state: Arc<RwLock<SimulatorState>>,
+    is_run: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    // Keeps the tty alive while the controller reopens the port.
+    _slave: File,
+}
+
+#[derive(Debug, Default)]
+struct SimulatorState {
+    commands: VecDeque<Cmd>,
+    dimmers: HashMap<u8, u8>,
+    switches: HashMap<u8, bool>,
+    channels: HashMap<u8, u8>,
+}
+
+impl SimulatorState {
+    fn apply(&mut self, cmd: Cmd) {
+        match cmd.cmd_type() {
+            DIMMER => {
+                self.dimmers.insert(cmd.id(), cmd.args());
+            }
+            SWITCH => {
+                self.switches.insert(cmd.id(), cmd.args() == 0x01);
+            }
+            CHANNEL => {
+                self.channels.insert(cmd.id(), cmd.args());
+            }
+            _ => warn!("Simulator: unknown command type {:?}", cmd),
+        }
+        if self.commands.len() == MAX_COMMANDS {
+            self.commands.pop_front();
+        }
+        self.commands.push_back(cmd);
+    }
+}
+
+///
+/// Splits the byte stream into 3 byte frames.
+/// A frame never starts with an unknown type byte, so a lost byte misaligns at most one command.
+///
+#[derive(Debug, Default)]
+struct FrameReader {
+    frame: Vec<u8>,
+}
+
+impl FrameReader {
+    fn push(&mut self, byte: u8) -> Option<Cmd> {
+        if self.frame.is_empty() && !is_cmd_type(byte) {
+            debug!("Simulator: skip unexpected byte {:#04x}", byte);
+            return None;
+        }
+        self.frame.push(byte);
+        if self.frame.len() < 3 {
+            return None;
+        }
+        let cmd = Cmd::new(self.frame[0], self.frame[1], self.frame[2]);
+        self.frame.clear();
+        Some(cmd)
+    }
+}
+
+fn is_cmd_type(byte: u8) -> bool {
+    byte == DIMMER || byte == SWITCH || byte == CHANNEL
+}
+
+impl SerialSimulator {
+    pub fn start() -> Result<SerialSimulator> {
+        let mut master: c_int = 0;
+        let mut slave: c_int = 0;
+        let mut name = [0 as c_char; 128];
+
+        let res = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                name.as_mut_ptr(),
+                ptr::null(),
+                ptr::null(),
+            )
+        };
+        if res != 0 {
+            return Err(Error::msg(format!(
+                "Failed to open pty: {}",
+                io::Error::last_os_error()
+            )));
+        }
+
+        let master_file = unsafe { File::from_raw_fd(master) };
+        let slave_file = unsafe { File::from_raw_fd(slave) };
+        make_raw(slave)?;
+
+        let port_name = unsafe { CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        let state = Arc::new(RwLock::new(SimulatorState::default()));
+        let is_run = Arc::new(AtomicBool::new(true));
+
+        let thread_state = state.clone();
+        let thread_is_run = is_run.clone();
+        let thread = thread::spawn(move || {
+            Self::read_loop(master_file, thread_state, thread_is_run);
+        });
+
+        info!("Serial simulator started on {}", port_name);
+        Ok(SerialSimulator {
+            port_name,
+            state,
+            is_run,
+            thread: Some(thread),
+            _slave: slave_file,
+        })
+    }
+
+    /// Path of the tty the controller should open.
+    pub fn port_name(&self) -> &str {
+        &self.port_name
+    }
+
+    ///
+    /// State {commands: [[type, id, args]], dimmers: {p_id: args}, switches: {p_id: is_on},
+    /// channels: {p_id: level}}
+    ///
+    pub fn state(&self) -> Value {
+        let state = self.state.read().unwrap();
+        let commands = state
+            .commands
+            .iter()
+            .map(|cmd| json!([cmd.cmd_type(), cmd.id(), cmd.args()]))
+            .collect::<Vec<_>>();
+        json!({
+            "port": self.port_name,
+            "commands": commands,
+            "dimmers": state.dimmers,
+            "switches": state.switches,
+            "channels": state.channels,
+        })
+    }
+
+    fn read_loop(mut master: File, state: Arc<RwLock<SimulatorState>>, is_run: Arc<AtomicBool>) {
+        let fd = master.as_raw_fd();
+        let mut reader = FrameReader::default();
+        let mut buf = [0u8; 64];
+
+        while is_run.load(Ordering::SeqCst) {
+            if !wait_read(fd) {
+                continue;
+            }
+
+            match master.read(&mut buf) {
+                Ok(0) => thread::sleep(Duration::from_millis(POLL_TIMEOUT_MS as u64)),
+                Ok(len) => {
+                    for byte in &buf[..len] {
+                        if let Some(cmd) = reader.push(*byte) {
+                            debug!("Simulator: received {:?}", cmd);
+                            state.write().unwrap().apply(cmd);
+                        }
+                    }
+                }
+                Err(err) => {
+                    // EIO until the controller opens the slave side.
+                    debug!("Simulator: read error {:?}", err);
+                    thread::sleep(Duration::from_millis(POLL_TIMEOUT_MS as u64));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl SerialSimulator {
+    /// All received commands in order.
+    pub fn commands(&self) -> Vec<Cmd> {
+        self.state
+            .read()
+            .unwrap()
+            .commands
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    /// Waits until at least `count` commands are received or the timeout expires.
+    pub fn wait_commands(&self, count: usize, timeout: Duration) -> Vec<Cmd> {
+        let start = std::time::Instant::now();
+        loop {
+            let commands = self.commands();
+            if commands.len() >= count || start.elapsed() > timeout {
+                return commands;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    pub fn clear(&self) {
+        self.state.write().unwrap().commands.clear();
+    }
+
+    /// Last raw value sent to the dimmer with the given port id.
+    pub fn dimmer(&self, p_id: u8) -> Option<u8> {
+        self.state.read().unwrap().dimmers.get(&p_id).copied()
+    }
+
+    pub fn is_dimmer_on(&self, p_id: u8) -> bool {
+        self.dimmer(p_id).map(|val| val != 255).unwrap_or(false)
+    }
+
+    pub fn is_switch_on(&self, p_id: u8) -> bool {
+        self.state
+            .read()
+            .unwrap()
+            .switches
+            .get(&p_id)
+            .copied()
+            .unwrap_or(false)
+    }
+}
+
+impl Drop for SerialSimulator {
+    fn drop(&mut self) {
+        self.is_run.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Failed to stop serial simulator.");
+            }
+        }
+    }
+}
+
+fn wait_read(fd: RawFd) -> bool {
+    let mut fds = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let res = unsafe { libc::poll(&mut fds, 1, POLL_TIMEOUT_MS) };
+    res > 0 && fds.revents & libc::POLLIN != 0
+}
+
+fn make_raw(fd: RawFd) -> Result<()> {
+    unsafe {
+        let mut termios: libc::termios = mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(Error::msg(format!(
+                "Failed to read pty settings: {}",
+                io::Error::last_os_error()
+            )));
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(Error::msg(format!(
+                "Failed to configure pty: {}",
+                io::Error::last_os_error()
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devices::{SerialDimmer, SerialSwitch, Switch};
+    use crate::io::serial::{Cmd, SerialChannel};
+    use crate::io::simulator::{FrameReader, SerialSimulator, SimulatorState, MAX_COMMANDS};
+    use crate::io::IO;
+    use crate::runtime::Runtime;
+    use std::time::Duration;
+
+    #[test]
+    fn test_record_commands() {
+        let simulator = SerialSimulator::start().unwrap();
+        let channel = SerialChannel::with_port_name(simulator.port_name());
+
+        channel.send(Cmd::new(0x02, 0x04, 0x01)).unwrap();
+        channel.send(Cmd::new(0x01, 0x03, 120)).unwrap();
+        channel.send(Cmd::new(0x01, 0x02, 255)).unwrap();
+
+        let commands = simulator.wait_commands(3, Duration::from_secs(5));
+        assert_eq!(
+            commands,
+            vec![
+                Cmd::new(0x02, 0x04, 0x01),
+                Cmd::new(0x01, 0x03, 120),
+                Cmd::new(0x01, 0x02, 255)
+            ]
+        );
+        assert!(simulator.is_switch_on(0x04));
+        assert!(simulator.is_dimmer_on(0x03));
+        assert_eq!(simulator.dimmer(0x03), Some(120));
+        assert!(!simulator.is_dimmer_on(0x02));
+
+        simulator.clear();
+        channel.send(Cmd::new(0x02, 0x04, 0x02)).unwrap();
+        assert_eq!(
+            simulator.wait_commands(1, Duration::from_secs(5)),
+            vec![Cmd::new(0x02, 0x04, 0x02)]
+        );
+        assert!(!simulator.is_switch_on(0x04));
+    }
+
+    #[test]
+    fn test_resync_frames() {
+        let mut reader = FrameReader::default();
+        // The type byte of the first dimmer command is lost.
+        let bytes = [0x10, 200, 0x02, 0x04, 0x02, 0x01, 0x03, 120];
+        let commands = bytes
+            .iter()
+            .filter_map(|byte| reader.push(*byte))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            vec![Cmd::new(0x02, 0x04, 0x02), Cmd::new(0x01, 0x03, 120)]
+        );
+    }
+
+    #[test]
+    fn test_bound_commands() {
+        let mut state = SimulatorState::default();
+        for i in 0..MAX_COMMANDS + 10 {
+            state.apply(Cmd::new(0x01, 0x01, i as u8));
+        }
+        assert_eq!(state.commands.len(), MAX_COMMANDS);
+        assert_eq!(state.commands.front(), Some(&Cmd::new(0x01, 0x01, 10)));
+    }
+
+    #[test]
+    fn test_serial_devices() {
+        let simulator = SerialSimulator::start().unwrap();
+        let rt = Runtime::new(1);
+        let mut io = IO::with_simulator(&rt, &simulator);
+        let fun = SerialSwitch::new(&mut io, "fun", 0x04);
+        let lamp = SerialDimmer::new(&mut io, "lamp", 0x01, 20, 100);
+
+        fun.switch(true).unwrap();
+        lamp.switch(true).unwrap();
+        lamp.switch(false).unwrap();
+
+        assert_eq!(
+            simulator.wait_commands(3, Duration::from_secs(5)),
+            vec![
+                Cmd::new(0x02, 0x04, 0x01),
+                Cmd::new(0x01, 0x01, 26),
+                Cmd::new(0x01, 0x01, 255)
+            ]
+        );
+        assert!(simulator.is_switch_on(0x04));
+        assert!(!simulator.is_dimmer_on(0x01));
+    }
+}
@@ -1,54 +1,927 @@
-use anyhow::Result;
+use crate::home::configuration::OnUpdate;
+use crate::io::buffer::Pending;
+use crate::io::protocol::{WebCmd, WebState, PROTOCOL_V1, PROTOCOL_V2};
+use crate::io::registration::{DeviceRegistration, DeviceSecrets, RegistrationError};
+use crate::storage::Storage;
+use anyhow::{Error, Result};
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
-use reqwest::blocking;
-use std::fmt::Write;
-use std::sync::Arc;
-use std::time::Duration;
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt::{Debug, Display, Error as FmtError, Formatter, Write};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WEB_DEVICES: &str = "web_devices";
+/// Timestamps of the last signed registrations, kept after a device is forgotten.
+const WEB_DEVICE_TIMESTAMPS: &str = "web_device_timestamps";
+/// Another host can't take over an id within this time after a signed registration
+/// without a timestamp: such a registration can't prove that it is newer.
+const CONFLICT_WINDOW_SECS: i64 = 5 * 60;
+pub const WEB_TRANSPORT: &str = "web_transport";
+const MAX_RETRIES: u32 = 3;
+/// Upper bound of a single retry delay.
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_TIMEOUT: Duration = Duration::from_secs(5);
+/// No retry is started after this time, the offline buffer delivers the state later.
+const SEND_BUDGET: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransportConfig {
+    pub timeout: Duration,
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every next one.
+    pub backoff: Duration,
+}
+
+impl TransportConfig {
+    /// Delay before the retry after the failed attempt, capped by MAX_BACKOFF.
+    fn delay(&self, attempt: u32) -> Duration {
+        2u32.checked_pow(attempt)
+            .and_then(|factor| self.backoff.checked_mul(factor))
+            .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF))
+    }
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        TransportConfig {
+            timeout: Duration::from_secs(1),
+            retries: 2,
+            backoff: Duration::from_millis(200),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TransportSettings {
+    inner: Arc<RwLock<TransportConfig>>,
+}
+
+impl TransportSettings {
+    pub fn get(&self) -> TransportConfig {
+        self.inner.read().unwrap().clone()
+    }
+}
+
+impl OnUpdate for TransportSettings {
+    fn on_update(&self, value: Value) -> Result<(), Error> {
+        let config: TransportConfig = serde_json::from_value(value)?;
+        if config.retries > MAX_RETRIES {
+            return Err(Error::msg(format!(
+                "Too many retries: {} > {}",
+                config.retries, MAX_RETRIES
+            )));
+        }
+        if config.timeout > MAX_TIMEOUT {
+            return Err(Error::msg(format!(
+                "Too long timeout: {:?} > {:?}",
+                config.timeout, MAX_TIMEOUT
+            )));
+        }
+        if config.backoff > MAX_BACKOFF {
+            return Err(Error::msg(format!(
+                "Too long backoff: {:?} > {:?}",
+                config.backoff, MAX_BACKOFF
+            )));
+        }
+        info!("Update web transport: {:?}", config);
+        *self.inner.write().unwrap() = config;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SendStats {
+    pub success: u64,
+    pub failure: u64,
+    pub retries: u64,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+///
+/// Failed delivery to a device. `retry` is set if the device may accept the state later,
+/// e.g. it's unreachable or answers with 5xx.
+///
+#[derive(Debug)]
+pub struct SendError {
+    msg: String,
+    retry: bool,
+}
+
+impl SendError {
+    pub(super) fn new(msg: String, retry: bool) -> SendError {
+        SendError { msg, retry }
+    }
+
+    /// The device is declared but not registered yet, the state is delivered on registration.
+    pub fn offline(id: &str) -> SendError {
+        SendError::new(format!("Web device {} is not registered yet", id), true)
+    }
+
+    pub fn retry(&self) -> bool {
+        self.retry
+    }
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl StdError for SendError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Transport {
+    #[default]
+    Http,
+    Mqtt,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Registration {
+    pub host: String,
+    pub last_registration: DateTime<Utc>,
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default)]
+    pub authenticated: bool,
+    #[serde(default)]
+    pub firmware: Option<String>,
+    /// Negotiated by signed registration only, devices without a secret speak the v1 protocol.
+    #[serde(default)]
+    pub protocol: Option<u32>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Device timestamp of the last signed registration, a signed registration must be newer.
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+}
+
+impl Registration {
+    fn legacy(host: String, transport: Transport) -> Registration {
+        Registration {
+            host,
+            last_registration: Utc::now(),
+            transport,
+            authenticated: false,
+            firmware: None,
+            protocol: None,
+            capabilities: vec![],
+            timestamp: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebDeviceInfo {
+    pub id: String,
+    #[serde(flatten)]
+    pub registration: Registration,
+    pub stats: SendStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending: Option<Pending>,
+}
+
+pub type RegisterHandler = dyn Fn(&[String]) + Send + Sync + 'static;
+
+/// Called with the ids of every accepted registration.
+#[derive(Clone, Default)]
+struct RegisterHook {
+    handler: Arc<RwLock<Option<Box<RegisterHandler>>>>,
+}
+
+impl RegisterHook {
+    fn notify(&self, ids: &[String]) {
+        if let Some(handler) = self.handler.read().unwrap().as_ref() {
+            handler(ids);
+        }
+    }
+}
+
+impl Debug for RegisterHook {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "RegisterHook")
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct WebChannel {
-    devices: Arc<DashMap<String, Arc<String>>>,
+    devices: Arc<DashMap<String, Registration>>,
+    timestamps: Arc<DashMap<String, i64>>,
+    on_register: RegisterHook,
+    secrets: DeviceSecrets,
+    stats: Arc<DashMap<String, SendStats>>,
+    settings: TransportSettings,
+    client: Client,
+    storage: Storage,
 }
 
 impl WebChannel {
-    pub fn new() -> WebChannel {
+    pub fn new(storage: Storage) -> WebChannel {
+        let devices = DashMap::new();
+        match storage.load::<BTreeMap<String, Registration>>(WEB_DEVICES) {
+            Ok(Some(registrations)) => {
+                info!("Loaded {} web device registrations", registrations.len());
+                for (id, reg) in registrations {
+                    devices.insert(id, reg);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => error!("Failed to load web devices: {}", err),
+        }
+
+        let timestamps = DashMap::new();
+        match storage.load::<BTreeMap<String, i64>>(WEB_DEVICE_TIMESTAMPS) {
+            Ok(Some(stored)) => {
+                for (id, timestamp) in stored {
+                    timestamps.insert(id, timestamp);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => error!("Failed to load web device timestamps: {}", err),
+        }
+        for reg in devices.iter() {
+            if let Some(timestamp) = reg.timestamp {
+                let mut last = timestamps.entry(reg.key().to_owned()).or_insert(timestamp);
+                *last = (*last).max(timestamp);
+            }
+        }
+
         WebChannel {
-            devices: Default::default(),
+            devices: Arc::new(devices),
+            timestamps: Arc::new(timestamps),
+            on_register: Default::default(),
+            secrets: DeviceSecrets::load(&storage),
+            stats: Default::default(),
+            settings: Default::default(),
+            client: Client::new(),
+            storage,
         }
     }
 
-    pub fn reg_device(&self, ids: Vec<String>, host: String) {
-        let host = Arc::new(host);
-        ids.into_iter().for_each(|id| {
-            self.devices.insert(id, host.clone());
+    /// Unsigned registration of the old firmware. Devices with a secret or a signed registration
+    /// must use `register`.
+    /// The device gets the v1 protocol: an unsigned announcement can't be trusted to pick one.
+    pub fn reg_device(&self, ids: Vec<String>, host: String, transport: Transport) -> Result<()> {
+        if let Some(id) = ids
+            .iter()
+            .find(|id| self.secrets.contains(id) || self.timestamps.contains_key(*id))
+        {
+            return Err(RegistrationError::AuthRequired(id.to_owned()).into());
+        }
+
+        ids.iter().for_each(|id| {
+            self.devices
+                .insert(id.to_owned(), Registration::legacy(host.clone(), transport));
         });
+        self.save();
+        self.on_register.notify(&ids);
+        Ok(())
     }
 
-    pub fn host(&self, id: &str) -> Option<Arc<String>> {
-        if let Some(host) = self.devices.get(id) {
-            Some(host.clone())
-        } else {
-            None
+    /// Signed registration with capability announcement.
+    pub fn register(
+        &self,
+        reg: DeviceRegistration,
+        host: String,
+        transport: Transport,
+    ) -> Result<()> {
+        reg.validate()?;
+        self.secrets.verify(&reg)?;
+
+        let now = Utc::now();
+        for id in &reg.ids {
+            // A captured registration can't be sent again within the clock skew,
+            // even after the device is forgotten.
+            if let Some(last) = self.timestamps.get(id) {
+                if reg.timestamp <= *last {
+                    return Err(RegistrationError::Replayed(id.to_owned()).into());
+                }
+            }
+            // A newer signed registration moves the device at once, e.g. after a DHCP renew.
+            if let Some(current) = self.devices.get(id) {
+                if current.authenticated
+                    && current.timestamp.is_none()
+                    && current.host != host
+                    && (now - current.last_registration).num_seconds() < CONFLICT_WINDOW_SECS
+                {
+                    return Err(RegistrationError::Conflict {
+                        id: id.to_owned(),
+                        host: current.host.clone(),
+                    }
+                    .into());
+                }
+            }
         }
+
+        for id in &reg.ids {
+            self.devices.insert(
+                id.to_owned(),
+                Registration {
+                    host: host.clone(),
+                    last_registration: now,
+                    transport,
+                    authenticated: true,
+                    firmware: Some(reg.firmware.clone()),
+                    protocol: Some(reg.negotiated_protocol()),
+                    capabilities: reg.capabilities.clone(),
+                    timestamp: Some(reg.timestamp),
+                },
+            );
+            self.timestamps.insert(id.to_owned(), reg.timestamp);
+        }
+        self.save();
+        self.on_register.notify(&reg.ids);
+        Ok(())
+    }
+
+    /// Sets the handler of accepted registrations (http, mqtt and discovery).
+    pub fn on_register<H>(&self, handler: H)
+    where
+        H: Fn(&[String]) + Send + Sync + 'static,
+    {
+        *self.on_register.handler.write().unwrap() = Some(Box::new(handler));
     }
 
-    pub fn send(&self, id: &str, args: Vec<String>) -> Result<()> {
-        if let Some(host) = self.host(id) {
-            let mut url = String::new();
-            write!(url, "http://{}/{}?", &host, id)?;
-            for (i, arg) in args.iter().enumerate() {
-                write!(url, "arg_{}={}&", i, arg)?;
+    /// Changes the registration of the old firmware. Devices with a secret or a signed
+    /// registration must use `register`.
+    pub fn update_device(
+        &self,
+        id: &str,
+        host: Option<String>,
+        transport: Option<Transport>,
+    ) -> Result<()> {
+        if self.secrets.contains(id) || self.timestamps.contains_key(id) {
+            return Err(RegistrationError::AuthRequired(id.to_owned()).into());
+        }
+        if let Some(mut reg) = self.devices.get_mut(id) {
+            if let Some(host) = host {
+                reg.host = host;
+            }
+            if let Some(transport) = transport {
+                reg.transport = transport;
             }
-            url.pop();
-            let resp = blocking::Client::builder()
-                .timeout(Duration::from_secs(1))
-                .build()?
-                .get(&url)
-                .send();
-            debug!("resp => {:?}", resp);
         } else {
-            debug!("Unknown device:{}", id)
+            return Err(Error::msg(format!("Unknown web device: {}", id)));
+        }
+        self.save();
+        Ok(())
+    }
+
+    /// Removes the registration. The timestamp of the last signed registration is kept,
+    /// so the device must register with a newer one.
+    pub fn forget_device(&self, id: &str) -> Result<()> {
+        if self.devices.remove(id).is_none() {
+            return Err(Error::msg(format!("Unknown web device: {}", id)));
         }
+        self.save();
         Ok(())
     }
+
+    pub fn devices(&self) -> Vec<WebDeviceInfo> {
+        let mut devices = self
+            .devices
+            .iter()
+            .map(|r| WebDeviceInfo {
+                id: r.key().to_owned(),
+                registration: r.value().clone(),
+                stats: self
+                    .stats
+                    .get(r.key())
+                    .map(|stats| stats.clone())
+                    .unwrap_or_default(),
+                pending: None,
+            })
+            .collect::<Vec<_>>();
+        devices.sort_by(|a, b| a.id.cmp(&b.id));
+        devices
+    }
+
+    pub fn host(&self, id: &str) -> Option<String> {
+        self.devices.get(id).map(|reg| reg.host.clone())
+    }
+
+    pub fn transport(&self, id: &str) -> Option<Transport> {
+        self.devices.get(id).map(|reg| reg.transport)
+    }
+
+    /// Negotiated protocol. Devices without a signed registration use the legacy protocol.
+    pub fn protocol(&self, id: &str) -> u32 {
+        self.devices
+            .get(id)
+            .and_then(|reg| reg.protocol)
+            .unwrap_or(PROTOCOL_V1)
+    }
+
+    pub fn settings(&self) -> &TransportSettings {
+        &self.settings
+    }
+
+    pub fn send(&self, id: &str, cmd: &WebCmd) -> Result<()> {
+        let host = self.host(id).ok_or_else(|| SendError::offline(id))?;
+
+        let protocol = self.protocol(id);
+        let request = || -> Result<RequestBuilder> {
+            if protocol >= PROTOCOL_V2 {
+                let url = format!("http://{}/v2/{}", &host, id);
+                Ok(self.client.post(&url).json(&cmd.encode(PROTOCOL_V2)))
+            } else {
+                let mut url = String::new();
+                write!(url, "http://{}/{}?", &host, id)?;
+                for (i, arg) in cmd.args.iter().enumerate() {
+                    write!(url, "arg_{}={}&", i, arg)?;
+                }
+                url.pop();
+                Ok(self.client.get(&url))
+            }
+        };
+
+        let config = self.settings.get();
+        let start = Instant::now();
+        let mut attempt = 0;
+        loop {
+            match self.request(request()?, &config) {
+                Ok(()) => {
+                    self.on_success(id, attempt);
+                    return Ok(());
+                }
+                Err(err) => {
+                    if !err.retry
+                        || attempt >= config.retries
+                        || start.elapsed() + config.delay(attempt) >= SEND_BUDGET
+                    {
+                        let msg = format!(
+                            "Failed to send to web device {} after {} attempt(s): {}",
+                            id,
+                            attempt + 1,
+                            err.msg
+                        );
+                        self.on_failure(id, attempt, &msg);
+                        return Err(SendError::new(msg, err.retry).into());
+                    }
+                    debug!("Retry {} [{}]: {}", id, attempt + 1, err.msg);
+                    thread::sleep(config.delay(attempt));
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Reads the state reported by the device. `None` if the device can't report its state.
+    pub fn query(&self, id: &str) -> Result<Option<WebState>> {
+        let host = self
+            .host(id)
+            .ok_or_else(|| Error::msg(format!("Unknown web device: {}", id)))?;
+        let url = if self.protocol(id) >= PROTOCOL_V2 {
+            format!("http://{}/v2/{}", host, id)
+        } else {
+            format!("http://{}/{}/status", host, id)
+        };
+
+        let resp = self
+            .client
+            .get(&url)
+            .timeout(self.settings.get().timeout)
+            .send()
+            .map_err(|err| Error::msg(format!("Failed to query web device {}: {}", id, err)))?;
+
+        let status = resp.status();
+        if status == StatusCode::NOT_FOUND || status == StatusCode::NOT_IMPLEMENTED {
+            Ok(None)
+        } else if status.is_success() {
+            Ok(Some(resp.json()?))
+        } else {
+            Err(Error::msg(format!(
+                "Failed to query web device {}: unexpected status {}",
+                id, status
+            )))
+        }
+    }
+
+    fn request(&self, request: RequestBuilder, config: &TransportConfig) -> Result<(), SendError> {
+        let resp = request
+            .timeout(config.timeout)
+            .send()
+            .map_err(|err| SendError {
+                msg: if err.is_timeout() {
+                    format!("timeout after {:?}", config.timeout)
+                } else {
+                    err.to_string()
+                },
+                retry: true,
+            })?;
+        debug!("resp => {:?}", resp);
+
+        let status = resp.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(SendError {
+                msg: format!("unexpected status {}", status),
+                retry: status.is_server_error(),
+            })
+        }
+    }
+
+    fn on_success(&self, id: &str, retries: u32) {
+        let mut stats = self.stats.entry(id.to_owned()).or_default();
+        stats.success += 1;
+        stats.retries += retries as u64;
+        stats.last_success = Some(Utc::now());
+    }
+
+    fn on_failure(&self, id: &str, retries: u32, err: &str) {
+        let mut stats = self.stats.entry(id.to_owned()).or_default();
+        stats.failure += 1;
+        stats.retries += retries as u64;
+        stats.last_failure = Some(Utc::now());
+        stats.last_error = Some(err.to_owned());
+    }
+
+    fn save(&self) {
+        let registrations = self
+            .devices
+            .iter()
+            .map(|r| (r.key().to_owned(), r.value().clone()))
+            .collect::<BTreeMap<_, _>>();
+        if let Err(err) = self.storage.save(WEB_DEVICES, &registrations) {
+            error!("Failed to save web devices: {}", err);
+        }
+
+        let timestamps = self
+            .timestamps
+            .iter()
+            .map(|r| (r.key().to_owned(), *r.value()))
+            .collect::<BTreeMap<_, _>>();
+        if let Err(err) = self.storage.save(WEB_DEVICE_TIMESTAMPS, &timestamps) {
+            error!("Failed to save web device timestamps: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+impl WebChannel {
+    pub fn secrets(&self) -> &DeviceSecrets {
+        &self.secrets
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::home::configuration::OnUpdate;
+    use crate::io::protocol::{WebCmd, WebState, PROTOCOL_V2};
+    use crate::io::registration::{DeviceRegistration, RegistrationError};
+    use crate::io::web::{SendError, Transport, WebChannel, MAX_BACKOFF, SEND_BUDGET};
+    use crate::storage::Storage;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Http server which answers with the given statuses and bodies one by one.
+    fn serve(responses: Vec<(u16, &'static str)>) -> String {
+        serve_recorded(responses).0
+    }
+
+    /// Same as `serve`, also returns the received requests.
+    fn serve_recorded(responses: Vec<(u16, &'static str)>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let _ = tx.send(read_request(&mut stream));
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} STATUS\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        (addr, rx)
+    }
+
+    fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let len = stream.read(&mut buf).unwrap_or(0);
+            request.extend_from_slice(&buf[..len]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            let complete = match text.find("\r\n\r\n") {
+                Some(end) => {
+                    let length = text
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length: "))
+                        .and_then(|len| len.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    request.len() >= end + 4 + length
+                }
+                None => false,
+            };
+            if len == 0 || complete {
+                return text;
+            }
+        }
+    }
+
+    #[test]
+    fn test_send_errors_and_retries() {
+        let channel = WebChannel::new(Storage::temp());
+        channel
+            .settings()
+            .on_update(json!({
+                "timeout": {"secs": 1, "nanos": 0},
+                "retries": 2,
+                "backoff": {"secs": 0, "nanos": 1_000_000}
+            }))
+            .unwrap();
+        assert!(channel
+            .settings()
+            .on_update(json!({
+                "timeout": {"secs": 1, "nanos": 0},
+                "retries": 100,
+                "backoff": {"secs": 1, "nanos": 0}
+            }))
+            .is_err());
+        assert!(channel
+            .settings()
+            .on_update(json!({
+                "timeout": {"secs": 60, "nanos": 0},
+                "retries": 2,
+                "backoff": {"secs": 0, "nanos": 1_000_000}
+            }))
+            .is_err());
+        let config = channel.settings().get();
+        assert_eq!(config.delay(2), Duration::from_millis(4));
+        assert_eq!(config.delay(40), MAX_BACKOFF);
+
+        let cmd = WebCmd::new(vec!["ON".to_owned()], json!({"is_on": true}));
+        let err = channel.send("unknown", &cmd).unwrap_err();
+        assert!(err.downcast_ref::<SendError>().unwrap().retry());
+
+        channel
+            .reg_device(
+                vec!["beam".to_owned()],
+                serve(vec![(500, ""), (503, ""), (200, "")]),
+                Transport::Http,
+            )
+            .unwrap();
+        channel.send("beam", &cmd).unwrap();
+
+        channel
+            .reg_device(
+                vec!["beam".to_owned()],
+                serve(vec![(404, "")]),
+                Transport::Http,
+            )
+            .unwrap();
+        assert!(channel.send("beam", &cmd).is_err());
+
+        let stats = channel.devices().remove(0).stats;
+        assert_eq!(stats.success, 1);
+        assert_eq!(stats.failure, 1);
+        assert_eq!(stats.retries, 2);
+        assert!(stats.last_error.unwrap().contains("404"));
+    }
+
+    #[test]
+    fn test_send_budget() {
+        let channel = WebChannel::new(Storage::temp());
+        channel
+            .settings()
+            .on_update(json!({
+                "timeout": {"secs": 1, "nanos": 0},
+                "retries": 3,
+                "backoff": {"secs": 1, "nanos": 0}
+            }))
+            .unwrap();
+        // Accepts connections but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        channel
+            .reg_device(
+                vec!["beam".to_owned()],
+                listener.local_addr().unwrap().to_string(),
+                Transport::Http,
+            )
+            .unwrap();
+
+        let start = Instant::now();
+        let cmd = WebCmd::new(vec!["ON".to_owned()], json!({"is_on": true}));
+        let err = channel.send("beam", &cmd).unwrap_err();
+        assert!(start.elapsed() < SEND_BUDGET + Duration::from_secs(1));
+        assert!(err.downcast_ref::<SendError>().unwrap().retry());
+        assert!(channel.devices()[0].stats.retries < 3);
+    }
+
+    #[test]
+    fn test_restore_registrations() {
+        let storage = Storage::temp();
+        let channel = WebChannel::new(storage.clone());
+        channel
+            .reg_device(
+                vec!["kitchen_beam".to_owned(), "hot_water".to_owned()],
+                "192.168.0.10:80".to_owned(),
+                Transport::Http,
+            )
+            .unwrap();
+        channel
+            .reg_device(
+                vec!["cold_water".to_owned()],
+                "192.168.0.11:80".to_owned(),
+                Transport::Http,
+            )
+            .unwrap();
+        channel
+            .update_device(
+                "cold_water",
+                Some("192.168.0.12:80".to_owned()),
+                Some(Transport::Mqtt),
+            )
+            .unwrap();
+        channel.forget_device("hot_water").unwrap();
+        assert!(channel.forget_device("hot_water").is_err());
+
+        let channel = WebChannel::new(storage);
+        let devices = channel
+            .devices()
+            .into_iter()
+            .map(|dev| (dev.id, dev.registration.host, dev.registration.transport))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            devices,
+            vec![
+                (
+                    "cold_water".to_owned(),
+                    "192.168.0.12:80".to_owned(),
+                    Transport::Mqtt
+                ),
+                (
+                    "kitchen_beam".to_owned(),
+                    "192.168.0.10:80".to_owned(),
+                    Transport::Http
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_query() {
+        let channel = WebChannel::new(Storage::temp());
+        channel
+            .reg_device(
+                vec!["beam".to_owned()],
+                serve(vec![
+                    (200, r#"{"args":["ON:OFF:rainbow:100:100"]}"#),
+                    (404, ""),
+                ]),
+                Transport::Http,
+            )
+            .unwrap();
+
+        assert_eq!(
+            channel.query("beam").unwrap(),
+            Some(WebState::Args(vec!["ON:OFF:rainbow:100:100".to_owned()]))
+        );
+        assert_eq!(channel.query("beam").unwrap(), None);
+    }
+
+    #[test]
+    fn test_signed_registration() {
+        let channel = WebChannel::new(Storage::temp());
+        channel.secrets().insert("hot_water", "secret_1");
+        channel.secrets().insert("cold_water", "secret_1");
+
+        let reg_error = |res: anyhow::Result<()>| match res.unwrap_err().downcast() {
+            Ok(err) => err,
+            Err(err) => panic!("unexpected error: {}", err),
+        };
+
+        let reg = DeviceRegistration::signed(&["hot_water", "cold_water"], None, "secret_1");
+        channel
+            .register(reg.clone(), "192.168.0.10:80".to_owned(), Transport::Http)
+            .unwrap();
+        match reg_error(channel.register(reg, "192.168.0.10:80".to_owned(), Transport::Http)) {
+            RegistrationError::Replayed(_) => {}
+            err => panic!("unexpected error: {}", err),
+        }
+        let dev = channel.devices().remove(0).registration;
+        assert!(dev.authenticated);
+        assert_eq!(dev.capabilities, vec!["status".to_owned()]);
+
+        let reg = DeviceRegistration::signed(&["hot_water"], None, "secret_2");
+        match reg_error(channel.register(reg, "192.168.0.66:80".to_owned(), Transport::Http)) {
+            RegistrationError::InvalidSignature(_) => {}
+            err => panic!("unexpected error: {}", err),
+        }
+
+        // A newer signed registration moves the device to another host at once.
+        let mut reg = DeviceRegistration::signed(&["hot_water"], None, "secret_1");
+        reg.timestamp += 1;
+        reg.sign("secret_1");
+        channel
+            .register(reg.clone(), "192.168.0.11:80".to_owned(), Transport::Http)
+            .unwrap();
+        assert_eq!(channel.host("hot_water").unwrap(), "192.168.0.11:80");
+
+        // Forgetting the device doesn't allow to replay its registration.
+        channel.forget_device("hot_water").unwrap();
+        match reg_error(channel.register(reg, "192.168.0.66:80".to_owned(), Transport::Http)) {
+            RegistrationError::Replayed(_) => {}
+            err => panic!("unexpected error: {}", err),
+        }
+        let mut reg = DeviceRegistration::signed(&["hot_water"], None, "secret_1");
+        reg.timestamp += 2;
+        reg.sign("secret_1");
+        channel
+            .register(reg, "192.168.0.10:80".to_owned(), Transport::Http)
+            .unwrap();
+
+        let reg = DeviceRegistration::signed(&["kitchen_beam"], None, "secret_1");
+        match reg_error(channel.register(reg, "192.168.0.66:80".to_owned(), Transport::Http)) {
+            RegistrationError::UnknownDevice(_) => {}
+            err => panic!("unexpected error: {}", err),
+        }
+
+        let res = channel.reg_device(
+            vec!["hot_water".to_owned()],
+            "192.168.0.66:80".to_owned(),
+            Transport::Http,
+        );
+        match reg_error(res) {
+            RegistrationError::AuthRequired(_) => {}
+            err => panic!("unexpected error: {}", err),
+        }
+        let res = channel.update_device("hot_water", Some("192.168.0.66:80".to_owned()), None);
+        match reg_error(res) {
+            RegistrationError::AuthRequired(_) => {}
+            err => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(channel.host("hot_water").unwrap(), "192.168.0.10:80");
+
+        // A device without a secret can't be taken over once it registered with a signature.
+        channel.secrets().remove("cold_water");
+        let res = channel.reg_device(
+            vec!["cold_water".to_owned()],
+            "192.168.0.66:80".to_owned(),
+            Transport::Http,
+        );
+        match reg_error(res) {
+            RegistrationError::AuthRequired(_) => {}
+            err => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(channel.host("cold_water").unwrap(), "192.168.0.10:80");
+    }
+
+    #[test]
+    fn test_protocol_negotiation() {
+        let channel = WebChannel::new(Storage::temp());
+        channel.secrets().insert("beam", "secret");
+        let (host, requests) = serve_recorded(vec![
+            (200, ""),
+            (200, r#"{"state":{"is_on":true}}"#),
+            (200, ""),
+        ]);
+        let cmd = WebCmd::new(vec!["ON:OFF".to_owned()], json!({"is_on": true}));
+
+        let mut reg = DeviceRegistration::signed(&["beam"], None, "secret");
+        reg.protocol = 7;
+        reg.sign("secret");
+        channel
+            .register(reg, host.clone(), Transport::Http)
+            .unwrap();
+        assert_eq!(channel.protocol("beam"), PROTOCOL_V2);
+
+        channel.send("beam", &cmd).unwrap();
+        let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(request.starts_with("POST /v2/beam "));
+        assert!(request.ends_with(r#"{"state":{"is_on":true}}"#));
+
+        assert_eq!(
+            channel.query("beam").unwrap(),
+            Some(WebState::State(json!({"is_on": true})))
+        );
+        let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(request.starts_with("GET /v2/beam "));
+
+        // Old firmware.
+        channel
+            .reg_device(vec!["lamp".to_owned()], host, Transport::Http)
+            .unwrap();
+        channel.send("lamp", &cmd).unwrap();
+        let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(request.starts_with("GET /lamp?arg_0=ON:OFF "));
+    }
 }
@@ -18,20 +18,27 @@ mod home;
 mod io;
 mod runtime;
 mod sensors;
+mod storage;
 mod utils;
 mod web;
 
+use crate::devices::{load_devices, Animator, EnergyMeter, StateStore};
 use crate::home::configuration::Configuration;
 use crate::home::BackgroundProcess;
 use crate::runtime::Runtime;
+use crate::storage::Storage;
 use home::Home;
-use io::IO;
+use io::{SerialSimulator, IO};
 use sentry::integrations::log::LoggerOptions;
 use sentry::integrations::{env_logger::init, panic::register_panic_handler};
 use sentry::{capture_message, Level};
 use std::env;
 use web::AppState;
 
+const SIMULATE_SERIAL: &str = "--simulate-serial";
+/// Periodic tasks of devices, stores and automations share this pool.
+const RUNTIME_THREADS: usize = 8;
+
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
@@ -47,11 +54,37 @@ async fn main() -> std::io::Result<()> {
     };
 
     let config = Configuration::default();
-    let runtime = Runtime::new(2);
-    let mut io = IO::with_runtime(&runtime);
-    let home = Home::new(&mut io, &config);
+    let runtime = Runtime::new(RUNTIME_THREADS);
+    let simulator = if env::args().any(|arg| arg == SIMULATE_SERIAL) {
+        Some(SerialSimulator::start().expect("Failed to start serial simulator"))
+    } else {
+        None
+    };
+    let mut io = match &simulator {
+        Some(simulator) => IO::with_simulator(&runtime, simulator),
+        None => IO::with_runtime(&runtime),
+    };
+    let home = Home::new(&mut io, &config, &Storage::from_env());
+    load_devices(&mut io, &Storage::from_env());
     info!("home: {:?}", home);
     let io = io.freeze();
+    let store = StateStore::new(&io, Storage::from_env(), Home::power_on_policies());
+    store.restore();
+    store.register(&config).unwrap();
+    let _store_bg = store.start();
+    let energy = EnergyMeter::new(&io, Storage::from_env());
+    let _energy_bg = energy.start();
+    let _devices_bg = io.start_devices();
+    let _followers_bg = home.followers.start();
+    let animator = Animator::new(&io);
+    io.register_config(&config).unwrap();
+    io.route_sensors(&home);
     let bg = BackgroundProcess::new(&home, &io, &config).unwrap();
-    web::start_io(AppState::new(home, io, bg, config)).await
+    web::start_io(
+        AppState::new(home, io, bg, config)
+            .with_simulator(simulator)
+            .with_energy(energy)
+            .with_animator(animator),
+    )
+    .await
 }
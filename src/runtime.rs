@@ -1,7 +1,7 @@
 use derivative::Derivative;
-use serde::export::fmt::Debug;
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::fmt::Debug;
+use std::sync::{Condvar, Mutex, RwLock};
 use std::time::SystemTime;
 use std::{
     sync::{atomic::AtomicBool, atomic::Ordering, Arc},
@@ -11,11 +11,40 @@ use std::{
 };
 use threadpool::ThreadPool;
 
+const IDLE_SLEEP_MS: u128 = 500;
+
 #[derive(Clone, Debug)]
 pub struct Runtime {
     thread: Arc<JoinHandle<()>>,
     is_run: Arc<AtomicBool>,
     tasks: Arc<RwLock<Tasks>>,
+    wakeup: Arc<Wakeup>,
+}
+
+///
+/// Wakes the runtime thread when a task is created, so it doesn't sleep past the new task.
+///
+#[derive(Debug, Default)]
+struct Wakeup {
+    created: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Wakeup {
+    fn notify(&self) {
+        *self.created.lock().unwrap() = true;
+        self.cond.notify_one();
+    }
+
+    fn wait(&self, timeout: Duration) {
+        let created = self.created.lock().unwrap();
+        let mut created = if *created {
+            created
+        } else {
+            self.cond.wait_timeout(created, timeout).unwrap().0
+        };
+        *created = false;
+    }
 }
 
 #[derive(Debug)]
@@ -93,6 +122,7 @@ impl Tasks {
     }
 
     fn compute_next_task(&mut self) {
+        self.next_task = None;
         let mut next_time = u128::max_value();
         let mut descriptor = None;
         for (index, task) in self.tasks.iter() {
@@ -116,16 +146,20 @@ impl Runtime {
         let is_run = Arc::new(AtomicBool::new(true));
         let tasks = Arc::new(RwLock::new(Tasks::empty()));
 
+        let wakeup = Arc::new(Wakeup::default());
+
         let is_run_service = is_run.clone();
         let tasks_service = tasks.clone();
+        let wakeup_service = wakeup.clone();
         let thread = Arc::new(thread::spawn(move || {
-            Self::run(tasks_service, is_run_service, threads_count)
+            Self::run(tasks_service, is_run_service, wakeup_service, threads_count)
         }));
 
         Runtime {
             thread,
             is_run,
             tasks,
+            wakeup,
         }
     }
 
@@ -136,8 +170,12 @@ impl Runtime {
         is_async: bool,
         is_regular: bool,
     ) -> u128 {
-        let mut tasks = self.tasks.write().unwrap();
-        tasks.create_task(action, interval, is_async, is_regular)
+        let descriptor = {
+            let mut tasks = self.tasks.write().unwrap();
+            tasks.create_task(action, interval, is_async, is_regular)
+        };
+        self.wakeup.notify();
+        descriptor
     }
 
     pub fn remove_task(&self, descriptor: u128) {
@@ -155,19 +193,21 @@ impl Runtime {
         tasks.reset_task_time(descriptor)
     }
 
-    fn run(tasks: Arc<RwLock<Tasks>>, is_run: Arc<AtomicBool>, threads_count: usize) {
+    fn run(
+        tasks: Arc<RwLock<Tasks>>,
+        is_run: Arc<AtomicBool>,
+        wakeup: Arc<Wakeup>,
+        threads_count: usize,
+    ) {
         let pool = ThreadPool::new(threads_count);
         while is_run.load(Ordering::Relaxed) {
-            let task_index = {
+            let (task_index, wait) = {
                 let tasks = tasks.read().unwrap();
-                if let Some(task) = &tasks.next_task {
-                    if task.task_time <= time_ms() {
-                        Some(task.descriptor)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
+                let now = time_ms();
+                match &tasks.next_task {
+                    Some(task) if task.task_time <= now => (Some(task.descriptor), 0),
+                    Some(task) => (None, (task.task_time - now).min(IDLE_SLEEP_MS)),
+                    None => (None, IDLE_SLEEP_MS),
                 }
             };
 
@@ -175,7 +215,7 @@ impl Runtime {
                 let mut tasks = tasks.write().unwrap();
                 tasks.run_task(task_index, &pool);
             } else {
-                thread::sleep(Duration::from_millis(500));
+                wakeup.wait(Duration::from_millis(wait as u64));
             }
         }
     }
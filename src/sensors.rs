@@ -2,6 +2,7 @@ use crate::home::Home;
 use crate::io::IOMut;
 use crate::runtime::time_ms;
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use std::fmt::{Debug, Error, Formatter};
 use std::string::ToString;
 use std::sync::{Arc, RwLock};
@@ -113,6 +114,52 @@ impl StatefulSwitch {
     }
 }
 
+///
+/// Sensor with a numeric value, e.g. temperature.
+///
+#[derive(Clone, Debug)]
+pub struct AnalogSensor {
+    id: Arc<String>,
+    reading: Arc<RwLock<Option<Reading>>>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Reading {
+    pub value: f64,
+    pub time: DateTime<Utc>,
+}
+
+impl AnalogSensor {
+    /// Sensors with the same id share the reading.
+    pub fn new(io: &mut IOMut, id: &str) -> AnalogSensor {
+        if let Some(sensor) = io.analog_sensor(id) {
+            return sensor;
+        }
+
+        let sensor = AnalogSensor {
+            id: Arc::new(id.to_owned()),
+            reading: Default::default(),
+        };
+        io.add_analog_sensor(sensor.clone());
+        sensor
+    }
+
+    pub fn id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    pub fn set(&self, value: f64) {
+        *self.reading.write().unwrap() = Some(Reading {
+            value,
+            time: Utc::now(),
+        });
+    }
+
+    pub fn reading(&self) -> Option<Reading> {
+        *self.reading.read().unwrap()
+    }
+}
+
 pub enum ActionType {
     On,
     Off,
@@ -0,0 +1,92 @@
+use anyhow::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const DATA_DIR: &str = "ODIN_DATA";
+const DEFAULT_DATA_DIR: &str = "data";
+
+///
+/// Local json storage. Every record is a file in the data directory.
+///
+#[derive(Debug, Clone)]
+pub struct Storage {
+    dir: Arc<Dir>,
+}
+
+#[derive(Debug)]
+struct Dir {
+    path: PathBuf,
+    /// Removed with the last clone of the storage.
+    temp: bool,
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        if self.temp {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+impl Storage {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Storage {
+        Storage {
+            dir: Arc::new(Dir {
+                path: dir.into(),
+                temp: false,
+            }),
+        }
+    }
+
+    /// Storage in the `ODIN_DATA` directory (./data by default).
+    pub fn from_env() -> Storage {
+        Storage::new(env::var(DATA_DIR).unwrap_or_else(|_| DEFAULT_DATA_DIR.to_owned()))
+    }
+
+    pub fn load<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+        match fs::read(self.path(name)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::msg(format!("Failed to read {}: {}", name, err))),
+        }
+    }
+
+    pub fn save<T: Serialize>(&self, name: &str, value: &T) -> Result<()> {
+        fs::create_dir_all(&self.dir.path)?;
+        let tmp = self.dir.path.join(format!("{}.json.tmp", name));
+        fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+        fs::rename(tmp, self.path(name))?;
+        Ok(())
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path.join(format!("{}.json", name))
+    }
+}
+
+#[cfg(test)]
+impl Storage {
+    /// Storage in a fresh temporary directory, removed once the storage is dropped.
+    pub fn temp() -> Storage {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = env::temp_dir().join(format!(
+            "odin_{}_{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        Storage {
+            dir: Arc::new(Dir {
+                path: dir,
+                temp: true,
+            }),
+        }
+    }
+}
@@ -1,15 +1,21 @@
+use crate::devices::{
+    Animation, DeviceFilter, DeviceLock, DeviceType, LockError, SafetyError, SchemaError, Source,
+};
 use crate::home::scripts::Runner;
-use crate::io::Input;
+use crate::io::{DeviceRegistration, Input, RegistrationError, Transport};
 use crate::sensors::ActionType;
 use crate::web::backend::homebridge::{
-    dimmer_brightness, dimmer_brightness_status, dimmer_status, dimmer_switch,
+    cover_position, cover_position_status, cover_state_status, cover_target_status,
+    dimmer_brightness, dimmer_brightness_status, dimmer_status, dimmer_switch, thermostat_mode,
+    thermostat_status, thermostat_target,
 };
 use crate::web::AppState;
-use actix_web::web::{Data, Json, Path, get, post, scope};
+use actix_web::web::{Data, Json, Path, Query, get, post, scope};
+use actix_web::http::StatusCode;
 use actix_web::{web, App, HttpResponse, HttpServer};
 use chrono::Utc;
 use serde_json::Value;
-use crate::web::backend::configuration::{get_all, get_config};
+use crate::web::backend::configuration::{get_all, get_config, update_config};
 
 pub async fn run_web_service(state: AppState) -> std::io::Result<()> {
     HttpServer::new(move || {
@@ -19,12 +25,28 @@ pub async fn run_web_service(state: AppState) -> std::io::Result<()> {
                 scope("/odin/api")
                     .route("switch/{switch}/{state}", get().to(toggle_hndl))
                     .route("reg-device/{ids}/{base_url}", get().to(reg_device))
+                    .route("v1/reg-device", post().to(register_device))
+                    .route("v1/web-devices/list", get().to(web_devices_list))
+                    .route("v1/web-device/{id}/update", post().to(update_web_device))
+                    .route("v1/web-device/{id}/forget", post().to(forget_web_device))
                     .route("v1/devices/list", get().to(devices_list))
                     .route("v1/device/{device}/update", post().to(update_device))
                     .route("v1/device/{device}/info", get().to(get_device))
+                    .route("v1/device/{device}/lock", post().to(lock_device))
+                    .route("v1/device/{device}/unlock", post().to(unlock_device))
+                    .route("v1/device-types", get().to(device_types))
                     .route("v1/switch/{switch}/{state}", get().to(switch_hndl))
+                    .route("v1/sensor/{sensor}/value", post().to(update_sensor))
+                    .route("v1/energy/{period}", get().to(energy_report))
+                    .route("v1/led-presets", get().to(led_presets))
+                    .route("v1/animations", get().to(animations))
+                    .route("v1/animation/{name}/start", post().to(start_animation))
+                    .route("v1/animation/{name}/stop", post().to(stop_animation))
+                    .route("v1/led-preset/{name}", post().to(save_led_preset))
+                    .route("v1/led-preset/{name}/delete", post().to(delete_led_preset))
                     .route("v1/script/{name}", post().to(run_script))
-                    .route("v1/time", get().to(get_time)),
+                    .route("v1/time", get().to(get_time))
+                    .route("v1/simulator/state", get().to(simulator_state)),
             )
             .service(
                 scope("/homebridge/api")
@@ -40,12 +62,20 @@ pub async fn run_web_service(state: AppState) -> std::io::Result<()> {
                     .route(
                         "dimmer_brightness_status/{device}",
                         get().to(dimmer_brightness_status),
-                    ),
+                    )
+                    .route("cover_position/{device}/{position}", get().to(cover_position))
+                    .route("cover_position_status/{device}", get().to(cover_position_status))
+                    .route("cover_target_status/{device}", get().to(cover_target_status))
+                    .route("cover_state_status/{device}", get().to(cover_state_status))
+                    .route("thermostat_status/{device}", get().to(thermostat_status))
+                    .route("thermostat_target/{device}/{value}", get().to(thermostat_target))
+                    .route("thermostat_mode/{device}/{value}", get().to(thermostat_mode)),
             )
             .service(
                 scope("/configuration/api")
                     .route("get_all", get().to(get_all))
                     .route("get/{config}", get().to(get_config))
+                    .route("update/{config}", post().to(update_config))
             )
     })
         .bind("0.0.0.0:1884")
@@ -55,9 +85,12 @@ pub async fn run_web_service(state: AppState) -> std::io::Result<()> {
 }
 
 async fn toggle_hndl(params: Path<(String, String)>, state: Data<AppState>) -> HttpResponse {
-    if let Err(err) = state.io.act(&state.home, &params.0, ActionType::Toggle) {
+    if let Err(err) = state
+        .io
+        .act(&state.home, &params.0, ActionType::Toggle, Source::Switch)
+    {
         error!("toggle switch:{} err: {}", &params.0, err);
-        HttpResponse::InternalServerError().json(json!({"err": err.to_string()}))
+        device_error(err)
     } else {
         info!("toggle switch:{} ok", &params.0);
         HttpResponse::Ok().json(json!({"ok:": "ok"}))
@@ -71,9 +104,9 @@ async fn switch_hndl(params: Path<(String, String)>, state: Data<AppState>) -> H
         _ => return HttpResponse::InternalServerError().json(json!({"err":"Unknown action type"})),
     };
 
-    if let Err(err) = state.io.act(&state.home, &params.0, act_type) {
+    if let Err(err) = state.io.act(&state.home, &params.0, act_type, Source::Api) {
         error!("switch:{} err: {}", &params.0, err);
-        HttpResponse::InternalServerError().json(json!({"err":err.to_string()}))
+        device_error(err)
     } else {
         info!("switch:{} ok", &params.0);
         HttpResponse::Ok().json(json!({"ok:": "ok"}))
@@ -86,16 +119,58 @@ async fn update_device(
     value: Json<Value>,
 ) -> HttpResponse {
     info!("update device:{}, value: {:?}", &params, &value);
-    if let Err(err) = state.update_device(&params, value.0) {
+    if let Err(err) = state.update_device(&params, value.0, Source::Api) {
         error!("update device err: {}", err);
-        HttpResponse::InternalServerError().json(json!({"err": err.to_string()}))
+        device_error(err)
     } else {
         HttpResponse::Ok().json(json!({"ok:": "ok"}))
     }
 }
 
-async fn devices_list(state: Data<AppState>) -> HttpResponse {
-    HttpResponse::Ok().json(state.devices_list())
+/// Locked devices answer 423 and refused safety rules 409, so clients can tell them from failures.
+fn device_error(err: anyhow::Error) -> HttpResponse {
+    let body = json!({"err": err.to_string()});
+    if err.downcast_ref::<SchemaError>().is_some() {
+        HttpResponse::BadRequest().json(body)
+    } else if err.downcast_ref::<SafetyError>().is_some() {
+        HttpResponse::Conflict().json(body)
+    } else if err.downcast_ref::<LockError>().is_some() {
+        HttpResponse::build(StatusCode::LOCKED).json(body)
+    } else {
+        HttpResponse::InternalServerError().json(body)
+    }
+}
+
+/// Body: {"value": 21.5}
+async fn update_sensor(
+    params: Path<String>,
+    state: Data<AppState>,
+    value: Json<Value>,
+) -> HttpResponse {
+    let res = value.0["value"]
+        .as_f64()
+        .ok_or_else(|| anyhow::Error::msg("value is required"))
+        .and_then(|value| state.io.update_sensor(&params, value));
+    if let Err(err) = res {
+        error!("update sensor:{} err: {}", &params, err);
+        HttpResponse::BadRequest().json(json!({"err": err.to_string()}))
+    } else {
+        HttpResponse::Ok().json(json!({"ok:": "ok"}))
+    }
+}
+
+/// ?room=kitchen&type=SerialDimmer
+async fn devices_list(state: Data<AppState>, filter: Query<DeviceFilter>) -> HttpResponse {
+    HttpResponse::Ok().json(state.devices_list(&filter))
+}
+
+/// {device_type: JSON Schema of the update}
+async fn device_types() -> HttpResponse {
+    let types = DeviceType::all()
+        .into_iter()
+        .map(|dev_type| (format!("{:?}", dev_type), dev_type.schema().to_json()))
+        .collect::<serde_json::Map<_, _>>();
+    HttpResponse::Ok().json(types)
 }
 
 async fn get_device(params: Path<String>, state: Data<AppState>) -> HttpResponse {
@@ -108,8 +183,29 @@ async fn get_device(params: Path<String>, state: Data<AppState>) -> HttpResponse
     }
 }
 
+/// Body: {"sources": ["switch", "script"], "reason": "child lock"}, every source if empty.
+async fn lock_device(
+    params: Path<String>,
+    state: Data<AppState>,
+    lock: Json<DeviceLock>,
+) -> HttpResponse {
+    match state.lock_device(&params, lock.0) {
+        Ok(()) => HttpResponse::Ok().json(json!({"ok:": "ok"})),
+        Err(err) => HttpResponse::BadRequest().json(json!({"err": err.to_string()})),
+    }
+}
+
+async fn unlock_device(params: Path<String>, state: Data<AppState>) -> HttpResponse {
+    match state.unlock_device(&params) {
+        Ok(()) => HttpResponse::Ok().json(json!({"ok:": "ok"})),
+        Err(err) => HttpResponse::BadRequest().json(json!({"err": err.to_string()})),
+    }
+}
+
 /// 0 - ids (id_1:id_2:id_3)
 /// 1 - base_url (host:port)
+/// Unsigned, the devices use protocol v1. Protocol v2 requires a secret and v1/reg-device.
+/// Refused for devices with a secret or a signed registration, even a forgotten one.
 async fn reg_device(params: Path<(String, String)>, state: Data<AppState>) -> HttpResponse {
     info!("reg device id:{:?}, ip: {}", &params.0, &params.1);
     let ids = params
@@ -119,14 +215,141 @@ async fn reg_device(params: Path<(String, String)>, state: Data<AppState>) -> Ht
         .collect::<Vec<_>>();
     let host = params.1.to_owned();
 
-    state.io.reg_web_devices(ids, host);
-    HttpResponse::Ok().json(json!({"ok:": "ok"}))
+    match state.io.reg_web_devices(ids, host) {
+        Ok(_) => HttpResponse::Ok().json(json!({"ok:": "ok"})),
+        Err(err) => registration_error(err),
+    }
+}
+
+/// body - DeviceRegistration {"ids": [..], "host": "host:port", "firmware": "1.0.0",
+/// "protocol": 1, "capabilities": [..], "timestamp": unix_secs, "signature": "hex"}
+async fn register_device(state: Data<AppState>, value: Json<DeviceRegistration>) -> HttpResponse {
+    info!("register device id:{:?}, host: {:?}", &value.ids, &value.host);
+    match state.io.register_web_device(value.0) {
+        Ok(_) => HttpResponse::Ok().json(json!({"ok:": "ok"})),
+        Err(err) => registration_error(err),
+    }
+}
+
+fn registration_error(err: anyhow::Error) -> HttpResponse {
+    warn!("registration rejected: {}", err);
+    let body = json!({"err": err.to_string()});
+    match err.downcast_ref::<RegistrationError>() {
+        Some(RegistrationError::Invalid(_)) | Some(RegistrationError::UnsupportedProtocol(_)) => {
+            HttpResponse::BadRequest().json(body)
+        }
+        Some(RegistrationError::Conflict { .. }) => HttpResponse::Conflict().json(body),
+        Some(_) => HttpResponse::Forbidden().json(body),
+        None => HttpResponse::InternalServerError().json(body),
+    }
+}
+
+async fn web_devices_list(state: Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(state.io.web_devices())
+}
+
+/// body - {"host": "host:port", "transport": "Http" | "Mqtt"}
+/// Devices with a secret change their registration by signed registration only.
+async fn update_web_device(
+    params: Path<String>,
+    state: Data<AppState>,
+    value: Json<WebDeviceUpdate>,
+) -> HttpResponse {
+    info!("update web device:{}, value: {:?}", &params, &value);
+    let WebDeviceUpdate { host, transport } = value.0;
+    match state.io.update_web_device(&params, host, transport) {
+        Ok(_) => HttpResponse::Ok().json(json!({"ok:": "ok"})),
+        Err(err) => registration_error(err),
+    }
+}
+
+/// A forgotten device with a secret must register with a newer timestamp than the last one.
+async fn forget_web_device(params: Path<String>, state: Data<AppState>) -> HttpResponse {
+    info!("forget web device:{}", &params);
+    if let Err(err) = state.io.forget_web_device(&params) {
+        error!("forget web device err: {}", err);
+        HttpResponse::InternalServerError().json(json!({"err": err.to_string()}))
+    } else {
+        HttpResponse::Ok().json(json!({"ok:": "ok"}))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct WebDeviceUpdate {
+    host: Option<String>,
+    transport: Option<Transport>,
 }
 
 async fn get_time(_state: Data<AppState>) -> HttpResponse {
     HttpResponse::Ok().json(Utc::now())
 }
 
+/// period - day "2026-10-19" or month "2026-10"
+async fn energy_report(params: Path<String>, state: Data<AppState>) -> HttpResponse {
+    match state.energy_report(&params) {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(err) => HttpResponse::BadRequest().json(json!({"err": err.to_string()})),
+    }
+}
+
+async fn animations(state: Data<AppState>) -> HttpResponse {
+    match state.animations() {
+        Ok(animations) => HttpResponse::Ok().json(animations),
+        Err(err) => HttpResponse::NotFound().json(json!({"err": err.to_string()})),
+    }
+}
+
+/// body - Animation {"devices": [..], "keyframes": [..], "looped": true}
+async fn start_animation(
+    params: Path<String>,
+    state: Data<AppState>,
+    value: Json<Animation>,
+) -> HttpResponse {
+    info!("start animation:{}, value: {:?}", &params, &value);
+    match state.start_animation(&params, value.0) {
+        Ok(_) => HttpResponse::Ok().json(json!({"ok:": "ok"})),
+        Err(err) => HttpResponse::BadRequest().json(json!({"err": err.to_string()})),
+    }
+}
+
+async fn stop_animation(params: Path<String>, state: Data<AppState>) -> HttpResponse {
+    match state.stop_animation(&params) {
+        Ok(_) => HttpResponse::Ok().json(json!({"ok:": "ok"})),
+        Err(err) => HttpResponse::NotFound().json(json!({"err": err.to_string()})),
+    }
+}
+
+async fn led_presets(state: Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(state.led_presets())
+}
+
+/// body - LedMode, e.g. {"Gradient": [[255, 0, 0], [0, 0, 255]]}
+async fn save_led_preset(
+    params: Path<String>,
+    state: Data<AppState>,
+    value: Json<Value>,
+) -> HttpResponse {
+    info!("save led preset:{}, value: {:?}", &params, &value);
+    match state.save_led_preset(&params, value.0) {
+        Ok(_) => HttpResponse::Ok().json(json!({"ok:": "ok"})),
+        Err(err) => HttpResponse::BadRequest().json(json!({"err": err.to_string()})),
+    }
+}
+
+async fn delete_led_preset(params: Path<String>, state: Data<AppState>) -> HttpResponse {
+    match state.delete_led_preset(&params) {
+        Ok(_) => HttpResponse::Ok().json(json!({"ok:": "ok"})),
+        Err(err) => HttpResponse::NotFound().json(json!({"err": err.to_string()})),
+    }
+}
+
+async fn simulator_state(state: Data<AppState>) -> HttpResponse {
+    match state.simulator_state() {
+        Some(val) => HttpResponse::Ok().json(val),
+        None => HttpResponse::NotFound().json(json!({"err": "Serial simulator is not running"})),
+    }
+}
+
 async fn run_script(
     params: Path<String>,
     value: Json<Value>,
@@ -137,15 +360,18 @@ async fn run_script(
         Ok(_) => HttpResponse::Ok().json(json!({"ok:": "ok"})),
         Err(err) => {
             error!("Failed to run script: {:?}", err);
-            HttpResponse::InternalServerError().json(json!({ "err": err.to_string() }))
+            device_error(err)
         }
     }
 }
 
 mod homebridge {
+    use super::device_error;
+    use crate::devices::Source;
     use crate::web::AppState;
     use actix_web::web::{Data, Path};
     use actix_web::HttpResponse;
+    use serde_json::Value;
 
     pub async fn dimmer_switch(
         params: Path<(String, String)>,
@@ -160,9 +386,11 @@ mod homebridge {
             }
         };
 
-        if let Err(err) = state.update_device(&params.0, json!({ "is_on": is_on })) {
+        if let Err(err) =
+            state.update_device(&params.0, json!({ "is_on": is_on }), Source::Homebridge)
+        {
             error!("switch dimmer:{} err: {}", &params.0, err);
-            HttpResponse::InternalServerError().json(json!({"err": err.to_string()}))
+            device_error(err)
         } else {
             info!("switch dimmer:{} -> {} ok", params.0, params.1);
             HttpResponse::Ok().json(json!({"ok:": "ok"}))
@@ -191,19 +419,20 @@ mod homebridge {
         params: Path<(String, String)>,
         state: Data<AppState>,
     ) -> HttpResponse {
-        let res = params
-            .1
-            .parse::<u64>()
-            .map_err(|err| err.to_string())
-            .and_then(|brightness| {
-                state
-                    .update_device(&params.0, json!({ "brightness": brightness }))
-                    .map_err(|err| err.to_string())
-            });
+        let brightness = match params.1.parse::<u64>() {
+            Ok(brightness) => brightness,
+            Err(err) => {
+                error!("dimmer_brightness:{} err: {}", &params.0, err);
+                return HttpResponse::InternalServerError()
+                    .json(json!({"err": err.to_string()}));
+            }
+        };
 
-        if let Err(err) = res {
+        if let Err(err) =
+            state.update_device(&params.0, json!({ "brightness": brightness }), Source::Homebridge)
+        {
             error!("dimmer_brightness:{} err: {}", &params.0, err);
-            HttpResponse::InternalServerError().json(json!({"err": err.to_string()}))
+            device_error(err)
         } else {
             info!("dimmer_brightness:{} ok", &params.0);
             HttpResponse::Ok().json(json!({"ok:": "ok"}))
@@ -227,12 +456,153 @@ mod homebridge {
             }
         }
     }
+
+    pub async fn cover_position(
+        params: Path<(String, String)>,
+        state: Data<AppState>,
+    ) -> HttpResponse {
+        let target = match params.1.parse::<u64>() {
+            Ok(target) => target,
+            Err(err) => {
+                error!("cover_position:{} err: {}", &params.0, err);
+                return HttpResponse::InternalServerError()
+                    .json(json!({"err": err.to_string()}));
+            }
+        };
+
+        if let Err(err) =
+            state.update_device(&params.0, json!({ "target": target }), Source::Homebridge)
+        {
+            error!("cover_position:{} err: {}", &params.0, err);
+            device_error(err)
+        } else {
+            info!("cover_position:{} -> {} ok", &params.0, &params.1);
+            HttpResponse::Ok().json(json!({"ok:": "ok"}))
+        }
+    }
+
+    pub async fn cover_position_status(name: Path<String>, state: Data<AppState>) -> String {
+        cover_status(&name, &state, "position", |val| val.as_u64())
+    }
+
+    pub async fn cover_target_status(name: Path<String>, state: Data<AppState>) -> String {
+        cover_status(&name, &state, "target", |val| val.as_u64())
+    }
+
+    /// HomeKit position state: 0 - decreasing, 1 - increasing, 2 - stopped.
+    pub async fn cover_state_status(name: Path<String>, state: Data<AppState>) -> String {
+        cover_status(&name, &state, "motion", |val| match val.as_str()? {
+            "closing" => Some(0),
+            "opening" => Some(1),
+            "stopped" => Some(2),
+            _ => None,
+        })
+    }
+
+    fn cover_status<F>(name: &str, state: &AppState, field: &str, map: F) -> String
+    where
+        F: Fn(&Value) -> Option<u64>,
+    {
+        let res = state
+            .get_device(name)
+            .map_err(|err| err.to_string())
+            .and_then(|val| {
+                val.get(field)
+                    .and_then(&map)
+                    .ok_or_else(|| format!("Invalid status object:{}", val))
+            });
+        match res {
+            Ok(val) => format!("{}", val),
+            Err(err) => {
+                error!("cover_status:{} err: {}", field, err);
+                format!("cover_status err: {}", err)
+            }
+        }
+    }
+
+    /// HomeKit heating cooling state: 0 - off, 1 - heat, 3 - auto.
+    pub async fn thermostat_status(name: Path<String>, state: Data<AppState>) -> HttpResponse {
+        match state.get_device(&name) {
+            Ok(val) => {
+                let target_state = match val["mode"].as_str() {
+                    Some("manual") => 1,
+                    Some("schedule") => 3,
+                    _ => 0,
+                };
+                let current_state = if val["heating"].as_bool().unwrap_or(false) {
+                    1
+                } else {
+                    0
+                };
+                HttpResponse::Ok().json(json!({
+                    "targetHeatingCoolingState": target_state,
+                    "currentHeatingCoolingState": current_state,
+                    "targetTemperature": val["target"].as_f64().or_else(|| val["setpoint"].as_f64()),
+                    "currentTemperature": val["temperature"],
+                }))
+            }
+            Err(err) => {
+                error!("thermostat_status:{} err: {}", &name, err);
+                HttpResponse::InternalServerError().json(json!({"err": err.to_string()}))
+            }
+        }
+    }
+
+    pub async fn thermostat_target(
+        params: Path<(String, String)>,
+        state: Data<AppState>,
+    ) -> HttpResponse {
+        let setpoint = match params.1.parse::<f64>() {
+            Ok(setpoint) => setpoint,
+            Err(err) => {
+                error!("thermostat_target:{} err: {}", &params.0, err);
+                return HttpResponse::InternalServerError()
+                    .json(json!({"err": err.to_string()}));
+            }
+        };
+
+        if let Err(err) =
+            state.update_device(&params.0, json!({ "setpoint": setpoint }), Source::Homebridge)
+        {
+            error!("thermostat_target:{} err: {}", &params.0, err);
+            device_error(err)
+        } else {
+            info!("thermostat_target:{} -> {} ok", &params.0, &params.1);
+            HttpResponse::Ok().json(json!({"ok:": "ok"}))
+        }
+    }
+
+    pub async fn thermostat_mode(
+        params: Path<(String, String)>,
+        state: Data<AppState>,
+    ) -> HttpResponse {
+        let mode = match params.1.as_str() {
+            "0" => "off",
+            "1" => "manual",
+            "3" => "schedule",
+            _ => {
+                return HttpResponse::InternalServerError()
+                    .json(json!({"err": "Unsupported heating cooling state"}));
+            }
+        };
+
+        if let Err(err) =
+            state.update_device(&params.0, json!({ "mode": mode }), Source::Homebridge)
+        {
+            error!("thermostat_mode:{} err: {}", &params.0, err);
+            device_error(err)
+        } else {
+            info!("thermostat_mode:{} -> {} ok", &params.0, mode);
+            HttpResponse::Ok().json(json!({"ok:": "ok"}))
+        }
+    }
 }
 
 mod configuration {
-    use actix_web::web::{Data, Path};
+    use actix_web::web::{Data, Json, Path};
     use crate::web::AppState;
     use actix_web::HttpResponse;
+    use serde_json::Value;
 
     pub async fn get_all(state: Data<AppState>) -> HttpResponse {
         HttpResponse::Ok().json(state.get_configuration().get_state())
@@ -245,4 +615,18 @@ mod configuration {
             None => HttpResponse::NotFound().body("Config not found"),
         }
     }
+
+    pub async fn update_config(
+        name: Path<String>,
+        state: Data<AppState>,
+        value: Json<Value>,
+    ) -> HttpResponse {
+        info!("update config:{}, value: {:?}", &name, &value);
+        if let Err(err) = state.get_configuration().update(&name, value.0) {
+            error!("update config err: {}", err);
+            HttpResponse::InternalServerError().json(json!({"err": err.to_string()}))
+        } else {
+            HttpResponse::Ok().json(json!({"ok:": "ok"}))
+        }
+    }
 }
\ No newline at end of file
@@ -1,7 +1,12 @@
+use crate::devices::{
+    Animation, Animator, DeviceFilter, DeviceInfo, DeviceLock, EnergyMeter, EnergyReport, Source,
+    LED_PRESETS,
+};
 use crate::home::{BackgroundProcess, Home};
-use crate::io::{Input, IO};
+use crate::io::{Input, SerialSimulator, IO};
 use anyhow::Result;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::sync::Arc;
 use crate::home::configuration::Configuration;
 
@@ -13,6 +18,9 @@ pub struct AppState {
     pub io: IO,
     bg: BackgroundProcess,
     config: Configuration,
+    simulator: Option<Arc<SerialSimulator>>,
+    energy: Option<EnergyMeter>,
+    animator: Option<Animator>,
 }
 
 impl AppState {
@@ -21,16 +29,43 @@ impl AppState {
             home: Arc::new(home),
             io,
             bg,
-            config
+            config,
+            simulator: None,
+            energy: None,
+            animator: None,
         }
     }
 
-    pub fn update_device(&self, name: &str, state: Value) -> Result<()> {
-        self.io.update_device(name, state)
+    pub fn with_simulator(mut self, simulator: Option<SerialSimulator>) -> AppState {
+        self.simulator = simulator.map(Arc::new);
+        self
     }
 
-    pub fn devices_list(&self) -> Vec<String> {
-        self.io.devices_list()
+    pub fn with_energy(mut self, energy: EnergyMeter) -> AppState {
+        self.energy = Some(energy);
+        self
+    }
+
+    pub fn with_animator(mut self, animator: Animator) -> AppState {
+        self.animator = Some(animator);
+        self
+    }
+
+    /// Update on behalf of the source, locked devices reject it with LockError.
+    pub fn update_device(&self, name: &str, state: Value, source: Source) -> Result<()> {
+        source.run(|| self.io.update_device(name, state))
+    }
+
+    pub fn lock_device(&self, name: &str, lock: DeviceLock) -> Result<()> {
+        self.io.lock_device(name, lock)
+    }
+
+    pub fn unlock_device(&self, name: &str) -> Result<()> {
+        self.io.unlock_device(name)
+    }
+
+    pub fn devices_list(&self, filter: &DeviceFilter) -> Vec<DeviceInfo> {
+        self.io.devices_list(filter)
     }
 
     pub fn get_device(&self, name: &str) -> Result<Value> {
@@ -40,6 +75,56 @@ impl AppState {
     pub fn get_configuration(&self) -> &Configuration {
         &self.config
     }
+
+    pub fn energy_report(&self, period: &str) -> Result<EnergyReport> {
+        self.energy
+            .as_ref()
+            .ok_or_else(|| anyhow::Error::msg("Energy accounting is disabled"))
+            .and_then(|energy| energy.report(period))
+    }
+
+    fn animator(&self) -> Result<&Animator> {
+        self.animator
+            .as_ref()
+            .ok_or_else(|| anyhow::Error::msg("Animations are disabled"))
+    }
+
+    pub fn start_animation(&self, name: &str, animation: Animation) -> Result<()> {
+        self.animator()?.start(name, animation)
+    }
+
+    pub fn stop_animation(&self, name: &str) -> Result<()> {
+        self.animator()?.stop(name)
+    }
+
+    /// {name: [device ids]}
+    pub fn animations(&self) -> Result<HashMap<String, Vec<String>>> {
+        Ok(self.animator()?.playing())
+    }
+
+    /// {name: LedMode}
+    pub fn led_presets(&self) -> Value {
+        self.config.get_value(LED_PRESETS).unwrap_or_else(|| json!({}))
+    }
+
+    pub fn save_led_preset(&self, name: &str, mode: Value) -> Result<()> {
+        let mut presets = self.led_presets();
+        presets[name] = mode;
+        self.config.update(LED_PRESETS, presets)
+    }
+
+    pub fn delete_led_preset(&self, name: &str) -> Result<()> {
+        let mut presets = self.led_presets();
+        presets
+            .as_object_mut()
+            .and_then(|presets| presets.remove(name))
+            .ok_or_else(|| anyhow::Error::msg(format!("Unknown led preset: {}", name)))?;
+        self.config.update(LED_PRESETS, presets)
+    }
+
+    pub fn simulator_state(&self) -> Option<Value> {
+        self.simulator.as_ref().map(|simulator| simulator.state())
+    }
 }
 
 pub async fn start_io(app_state: AppState) -> std::io::Result<()> {